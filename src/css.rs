@@ -1,39 +1,153 @@
+use std::collections::{HashMap, HashSet};
 use std::default::Default;
 use std::fmt;
 
+#[derive(Default)]
 pub struct Stylesheet {
+    // From a leading `@charset "UTF-8";`, as written.
+    pub charset: Option<String>,
     pub rules: Vec<Rule>,
+    pub keyframes: Vec<Keyframes>,
+    pub supports: Vec<SupportsRule>,
 }
 
+// https://developer.mozilla.org/en-US/docs/Web/CSS/@keyframes
+pub struct Keyframes {
+    pub name: String,
+    pub frames: Vec<Keyframe>,
+}
+
+// `offsets` are percentages: `from` is 0.0, `to` is 100.0, and a block
+// like `0%, 50% { ... }` has several.
+pub struct Keyframe {
+    pub offsets: Vec<f32>,
+    pub declarations: Vec<Declaration>,
+}
+
+// https://developer.mozilla.org/en-US/docs/Web/CSS/@supports
+// The rules are kept apart from `Stylesheet::rules`: whether they apply is
+// up to the caller, e.g. via `SupportsCondition::evaluate`.
+pub struct SupportsRule {
+    pub condition: SupportsCondition,
+    pub rules: Vec<Rule>,
+}
+
+#[derive(Clone, PartialEq)]
+pub enum SupportsCondition {
+    // (property: value)
+    Declaration(String, String),
+    Not(Box<SupportsCondition>),
+    And(Vec<SupportsCondition>),
+    Or(Vec<SupportsCondition>),
+}
+
+#[derive(Default)]
 pub struct Rule {
     pub selectors: Vec<Selector>,
     pub declarations: Vec<Declaration>,
+    pub origin: Origin,
 }
 
-#[derive(PartialEq, Eq)]
+// Who supplied a rule. Normal author declarations beat user-agent ones
+// whatever their specificity; for `!important` declarations the order flips.
+// https://developer.mozilla.org/en-US/docs/Web/CSS/Cascade#origin_types
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default)]
+pub enum Origin {
+    UserAgent,
+    #[default]
+    Author,
+}
+
+// (ids, classes, tags), compared lexicographically.
+// https://developer.mozilla.org/en-US/docs/Web/CSS/Specificity
+pub type Specificity = (usize, usize, usize);
+
+// Compound selectors in source order; `combinators[i]` joins `simple[i]`
+// and `simple[i + 1]`.
+#[derive(PartialEq, Eq, Default)]
 pub struct Selector {
     pub simple: Vec<SimpleSelector>,
     pub combinators: Vec<char>,
 }
 
-#[derive(PartialEq, Eq)]
+#[derive(PartialEq, Eq, Default)]
 pub struct SimpleSelector {
+    // `Some("*")` for the universal selector, which matches any tag but,
+    // unlike a type selector, adds nothing to specificity.
     pub tag_name: Option<String>,
     pub id: Option<String>,
     pub classes: Vec<String>,
+    pub attributes: Vec<AttrSelector>,
+    pub pseudo_classes: Vec<PseudoClass>,
+}
+
+// https://developer.mozilla.org/en-US/docs/Web/CSS/Attribute_selectors
+#[derive(PartialEq, Eq)]
+pub struct AttrSelector {
+    pub name: String,
+    pub op: AttrOp,
+}
+
+#[derive(PartialEq, Eq)]
+pub enum AttrOp {
+    // [attr]
+    Exists,
+    // [attr=value]
+    Equals(String),
+    // [attr^=value]
+    Prefix(String),
+    // [attr|=value]: exactly `value` or starting with `value-`
+    DashMatch(String),
+    // [attr*=value]
+    Substring(String),
 }
 
+// https://developer.mozilla.org/en-US/docs/Web/CSS/Pseudo-classes#tree-structural_pseudo-classes
+// `NthChild(a, b)` matches the 1-based positions `a*n + b` for n >= 0, so
+// `odd` is (2, 1) and `:nth-child(3)` is (0, 3).
+#[derive(PartialEq, Eq, Clone, Copy)]
+pub enum PseudoClass {
+    FirstChild,
+    LastChild,
+    NthChild(i32, i32),
+}
+
+#[derive(Clone)]
 pub struct Declaration {
     pub property: String,
     pub value: Value,
+    pub important: bool,
 }
 
+#[derive(Clone, PartialEq)]
 pub enum Value {
     Color(Color),
+    // Angles such as `rotate: 45deg` are stored here too, with an angle unit.
     Length(f32, Unit),
+    // A unitless number, like `line-height: 1.5` or `opacity: 0.5`.
+    Number(f32),
+    // Properties taking only whole numbers, like `z-index: 5`.
+    Integer(i32),
+    // `aspect-ratio: 16 / 9`, as width then height.
+    Ratio(f32, f32),
+    Calc(CalcNode),
+    // Space-separated function calls, like
+    // `transform: translate(10px, 20px) rotate(45deg)`.
+    FunctionList(Vec<CssFunction>),
+    // `inherit`, `initial` or `unset`, resolved during the cascade.
+    Global(CssWide),
+    // Space-separated values, like the track sizes of
+    // `grid-template-columns: 1fr 100px auto`.
+    List(Vec<Value>),
+    // `font-family: "Helvetica Neue", Arial, sans-serif`, in order.
+    FontFamilyList(Vec<FontFamily>),
+    // The comma-separated layers of `box-shadow`, topmost first.
+    ShadowList(Vec<Shadow>),
+    Keyword(String),
     Other(String),
 }
 
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
 pub enum Unit {
     // https://developer.mozilla.org/en-US/docs/Learn/CSS/Building_blocks/Values_and_units#lengths
     Px,
@@ -43,8 +157,80 @@ pub enum Unit {
     Vw,
     Vmin,
     Vmax,
+    Percent,
+    // https://developer.mozilla.org/en-US/docs/Web/CSS/angle
+    Deg,
+    Rad,
+    Grad,
+    Turn,
+    // A share of the free space in a grid container.
+    // https://developer.mozilla.org/en-US/docs/Web/CSS/flex_value
+    Fr,
+}
+
+// https://developer.mozilla.org/en-US/docs/Web/CSS/calc
+#[derive(Clone, PartialEq)]
+pub enum CalcNode {
+    Number(f32),
+    Length(f32, Unit),
+    Add(Box<CalcNode>, Box<CalcNode>),
+    Sub(Box<CalcNode>, Box<CalcNode>),
+    Mul(Box<CalcNode>, Box<CalcNode>),
+    Div(Box<CalcNode>, Box<CalcNode>),
+}
+
+// https://developer.mozilla.org/en-US/docs/Web/CSS/CSS_Values_and_Units#css-wide_values
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum CssWide {
+    Inherit,
+    Initial,
+    // `inherit` for inherited properties such as `color`, `initial` for the
+    // rest.
+    Unset,
+}
+
+#[derive(Clone, PartialEq, Eq)]
+pub enum FontFamily {
+    // A specific family, quotes removed.
+    Name(String),
+    // A generic family such as `sans-serif`, lowercased.
+    Generic(String),
+}
+
+// One call in a `Value::FunctionList`. Arguments are kept as written, so
+// angles (`45deg`) and other units the engine doesn't model survive.
+#[derive(Clone, PartialEq, Eq)]
+pub struct CssFunction {
+    pub name: String,
+    pub args: Vec<String>,
+}
+
+// One layer of a `Value::ShadowList`, such as `inset 0 1px 2px red`.
+// Offsets, blur and spread are as `translate_length` gives them; an omitted
+// blur or spread is `0px`. `color` is `None` when omitted, which means
+// `currentcolor`.
+#[derive(Clone, PartialEq)]
+pub struct Shadow {
+    pub inset: bool,
+    pub offset_x: Value,
+    pub offset_y: Value,
+    pub blur: Value,
+    pub spread: Value,
+    pub color: Option<Color>,
 }
 
+// Everything relative units need to become pixels.
+// Percentages resolve against `containing_width`.
+#[derive(Clone, Copy, PartialEq)]
+pub struct LengthContext {
+    pub font_size: f32,
+    pub root_font_size: f32,
+    pub viewport_width: f32,
+    pub viewport_height: f32,
+    pub containing_width: f32,
+}
+
+#[derive(Clone, Copy, PartialEq)]
 pub struct Color {
     pub r: f32,
     pub g: f32,
@@ -54,14 +240,127 @@ pub struct Color {
 
 impl Stylesheet {
     pub fn new(rules: Vec<Rule>) -> Stylesheet {
-        Stylesheet { rules }
+        Stylesheet {
+            charset: None,
+            rules,
+            keyframes: Vec::new(),
+            supports: Vec::new(),
+        }
     }
-}
 
-impl Default for Stylesheet {
-    // https://doc.rust-lang.org/std/default/trait.Default.html
-    fn default() -> Self {
-        Stylesheet { rules: Vec::new() }
+    // Marks every rule as coming from `origin`, e.g. to load a default
+    // stylesheet as `Origin::UserAgent` before appending author rules.
+    pub fn with_origin(mut self, origin: Origin) -> Stylesheet {
+        for rule in &mut self.rules {
+            rule.origin = origin;
+        }
+        for supports in &mut self.supports {
+            for rule in &mut supports.rules {
+                rule.origin = origin;
+            }
+        }
+        self
+    }
+
+    // Merges each rule into an earlier one with exactly the same selector
+    // list (and origin), keeping one declaration per property: the last
+    // important one if any, else the last one. A rule is left where it is
+    // when a rule in between sets one of its properties, since moving it up
+    // would change which of the two wins.
+    pub fn dedupe(&mut self) {
+        let mut rules: Vec<Rule> = Vec::with_capacity(self.rules.len());
+
+        for rule in std::mem::take(&mut self.rules) {
+            let target = rules
+                .iter()
+                .rposition(|r| r.selectors == rule.selectors && r.origin == rule.origin);
+            let target = target.filter(|&at| {
+                rules[at + 1..].iter().all(|between| {
+                    !between.declarations.iter().any(|d| {
+                        rule.declarations
+                            .iter()
+                            .any(|own| own.property == d.property)
+                    })
+                })
+            });
+
+            match target {
+                Some(at) => {
+                    rules[at].declarations.extend(rule.declarations);
+                    dedupe_declarations(&mut rules[at].declarations);
+                }
+                None => rules.push(rule),
+            }
+        }
+
+        self.rules = rules;
+    }
+
+    // Rewrites `h1, h2 { ... }` as `h1 { ... }` and `h2 { ... }`, each with
+    // its own copy of the declarations. Order is preserved.
+    pub fn split_selector_groups(self) -> Stylesheet {
+        let mut rules = Vec::new();
+
+        for rule in self.rules {
+            if rule.selectors.len() <= 1 {
+                rules.push(rule);
+                continue;
+            }
+
+            for selector in rule.selectors {
+                let mut split = Rule::new(vec![selector], rule.declarations.clone());
+                split.origin = rule.origin;
+                rules.push(split);
+            }
+        }
+
+        Stylesheet {
+            charset: self.charset,
+            ..Stylesheet::new(rules)
+        }
+    }
+
+    // Same rules as `Display`, without optional whitespace and without the
+    // last semicolon of each block: `h1,h2{color:#ff0000;margin:4px}`.
+    pub fn to_minified(&self) -> String {
+        let mut result = String::new();
+
+        if let Some(ref charset) = self.charset {
+            result.push_str(&format!("@charset \"{}\";", charset));
+        }
+
+        for rule in &self.rules {
+            result.push_str(&rule.to_minified());
+        }
+
+        for keyframes in &self.keyframes {
+            result.push_str(&format!("@keyframes {}{{", keyframes.name));
+            for frame in &keyframes.frames {
+                let offsets: Vec<String> =
+                    frame.offsets.iter().map(|o| format!("{}%", o)).collect();
+                let declarations: Vec<String> = frame
+                    .declarations
+                    .iter()
+                    .map(Declaration::to_minified)
+                    .collect();
+
+                result.push_str(&offsets.join(","));
+                result.push('{');
+                result.push_str(&declarations.join(";"));
+                result.push('}');
+            }
+            result.push('}');
+        }
+
+        for supports in &self.supports {
+            result.push_str(&format!("@supports {}{{", supports.condition));
+            for rule in &supports.rules {
+                result.push_str(&rule.to_minified());
+            }
+            result.push('}');
+        }
+
+        result
     }
 }
 
@@ -69,7 +368,7 @@ impl fmt::Debug for Stylesheet {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         let mut rule_res = String::new();
         for rule in &self.rules {
-            if rule_res.len() > 0 {
+            if !rule_res.is_empty() {
                 rule_res.push_str("\n\n");
             }
 
@@ -80,20 +379,238 @@ impl fmt::Debug for Stylesheet {
     }
 }
 
+impl fmt::Display for Stylesheet {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(ref charset) = self.charset {
+            writeln!(f, "@charset \"{}\";", charset)?;
+        }
+
+        for (i, rule) in self.rules.iter().enumerate() {
+            if i > 0 || self.charset.is_some() {
+                writeln!(f)?;
+            }
+            writeln!(f, "{}", rule)?;
+        }
+
+        for (i, keyframes) in self.keyframes.iter().enumerate() {
+            if i > 0 || !self.rules.is_empty() {
+                writeln!(f)?;
+            }
+            writeln!(f, "{}", keyframes)?;
+        }
+
+        for (i, supports) in self.supports.iter().enumerate() {
+            if i > 0 || !self.rules.is_empty() || !self.keyframes.is_empty() {
+                writeln!(f)?;
+            }
+            writeln!(f, "{}", supports)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl fmt::Display for Keyframes {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "@keyframes {} {{", self.name)?;
+
+        for frame in &self.frames {
+            let offsets: Vec<String> = frame.offsets.iter().map(|o| format!("{}%", o)).collect();
+            writeln!(f, "    {} {{", offsets.join(", "))?;
+            for declaration in &frame.declarations {
+                writeln!(f, "        {};", declaration)?;
+            }
+            writeln!(f, "    }}")?;
+        }
+
+        write!(f, "}}")
+    }
+}
+
+impl fmt::Display for SupportsRule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "@supports {} {{", self.condition)?;
+
+        for rule in &self.rules {
+            for line in rule.to_string().lines() {
+                writeln!(f, "    {}", line)?;
+            }
+        }
+
+        write!(f, "}}")
+    }
+}
+
+impl SupportsCondition {
+    // `supports(property, value)` answers each declaration test; the
+    // boolean structure is applied on top.
+    pub fn evaluate<F: Fn(&str, &str) -> bool>(&self, supports: &F) -> bool {
+        match *self {
+            SupportsCondition::Declaration(ref property, ref value) => supports(property, value),
+            SupportsCondition::Not(ref c) => !c.evaluate(supports),
+            SupportsCondition::And(ref cs) => cs.iter().all(|c| c.evaluate(supports)),
+            SupportsCondition::Or(ref cs) => cs.iter().any(|c| c.evaluate(supports)),
+        }
+    }
+
+    // Parenthesised unless it already is, as operands of `not`/`and`/`or`
+    // must be.
+    fn to_operand(&self) -> String {
+        match *self {
+            SupportsCondition::Declaration(..) => self.to_string(),
+            _ => format!("({})", self),
+        }
+    }
+}
+
+impl fmt::Display for SupportsCondition {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            SupportsCondition::Declaration(ref property, ref value) => {
+                write!(f, "({}: {})", property, value)
+            }
+            SupportsCondition::Not(ref c) => write!(f, "not {}", c.to_operand()),
+            SupportsCondition::And(ref cs) | SupportsCondition::Or(ref cs) => {
+                let op = if matches!(*self, SupportsCondition::And(_)) {
+                    " and "
+                } else {
+                    " or "
+                };
+                let operands: Vec<String> = cs.iter().map(|c| c.to_operand()).collect();
+                write!(f, "{}", operands.join(op))
+            }
+        }
+    }
+}
+
+// Drops every declaration that a later one for the same property overrides
+// within one rule, keeping the survivors in order.
+fn dedupe_declarations(declarations: &mut Vec<Declaration>) {
+    let mut winners: HashMap<&str, usize> = HashMap::new();
+    for (i, dclr) in declarations.iter().enumerate() {
+        match winners.get(dclr.property.as_str()) {
+            Some(&w) if declarations[w].important && !dclr.important => {}
+            _ => {
+                winners.insert(&dclr.property, i);
+            }
+        }
+    }
+
+    let keep: HashSet<usize> = winners.into_values().collect();
+    let mut i = 0;
+    declarations.retain(|_| {
+        i += 1;
+        keep.contains(&(i - 1))
+    });
+}
+
 impl Rule {
     pub fn new(selectors: Vec<Selector>, declarations: Vec<Declaration>) -> Rule {
         Rule {
             selectors,
             declarations,
+            origin: Origin::Author,
         }
     }
+
+    fn to_minified(&self) -> String {
+        let selectors: Vec<String> = self.selectors.iter().map(|s| s.to_css("")).collect();
+        let declarations: Vec<String> = self
+            .declarations
+            .iter()
+            .map(Declaration::to_minified)
+            .collect();
+
+        format!("{}{{{}}}", selectors.join(","), declarations.join(";"))
+    }
 }
 
-impl Default for Rule {
-    fn default() -> Self {
-        Rule {
-            selectors: Vec::new(),
-            declarations: Vec::new(),
+// Starts a `RuleBuilder`:
+// `rule().select_tag("div").select_class("active").declare("color", v).build()`.
+pub fn rule() -> RuleBuilder {
+    RuleBuilder::default()
+}
+
+// Builds a `Rule` one selector part at a time. The `select_*` methods add to
+// the current compound selector; `child`/`descendant` start the next
+// compound in the chain and `or` starts another selector in the list.
+#[derive(Default)]
+pub struct RuleBuilder {
+    selectors: Vec<Selector>,
+    chain: Selector,
+    compound: SimpleSelector,
+    declarations: Vec<Declaration>,
+}
+
+impl RuleBuilder {
+    pub fn select_tag(mut self, tag_name: &str) -> Self {
+        self.compound.tag_name = Some(tag_name.to_string());
+        self
+    }
+
+    pub fn select_id(mut self, id: &str) -> Self {
+        self.compound.id = Some(id.to_string());
+        self
+    }
+
+    pub fn select_class(mut self, class: &str) -> Self {
+        self.compound.classes.push(class.to_string());
+        self
+    }
+
+    pub fn select_attr(mut self, name: &str, op: AttrOp) -> Self {
+        self.compound
+            .attributes
+            .push(AttrSelector::new(name.to_string(), op));
+        self
+    }
+
+    pub fn select_pseudo(mut self, pseudo: PseudoClass) -> Self {
+        self.compound.pseudo_classes.push(pseudo);
+        self
+    }
+
+    pub fn child(self) -> Self {
+        self.combine('>')
+    }
+
+    pub fn descendant(self) -> Self {
+        self.combine(' ')
+    }
+
+    pub fn or(mut self) -> Self {
+        self.finish_selector();
+        self
+    }
+
+    pub fn declare(mut self, property: &str, value: Value) -> Self {
+        self.declarations
+            .push(Declaration::new(property.to_string(), value));
+        self
+    }
+
+    pub fn build(mut self) -> Rule {
+        self.finish_selector();
+        Rule::new(self.selectors, self.declarations)
+    }
+
+    fn combine(mut self, combinator: char) -> Self {
+        let compound = std::mem::take(&mut self.compound);
+        self.chain.simple.push(compound);
+        self.chain.combinators.push(combinator);
+        self
+    }
+
+    // An empty selector is dropped rather than left to match everything.
+    fn finish_selector(&mut self) {
+        let compound = std::mem::take(&mut self.compound);
+        let mut chain = std::mem::take(&mut self.chain);
+
+        if compound != SimpleSelector::default() {
+            chain.simple.push(compound);
+        }
+        if !chain.simple.is_empty() && chain.combinators.len() + 1 == chain.simple.len() {
+            self.selectors.push(chain);
         }
     }
 }
@@ -106,7 +623,7 @@ impl fmt::Debug for Rule {
         let tab = "    ";
 
         for selector in &self.selectors {
-            if sel_res.len() > 0 {
+            if !sel_res.is_empty() {
                 sel_res.push_str(", ");
             }
 
@@ -123,6 +640,19 @@ impl fmt::Debug for Rule {
     }
 }
 
+impl fmt::Display for Rule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let selectors: Vec<String> = self.selectors.iter().map(|s| s.to_string()).collect();
+        writeln!(f, "{} {{", selectors.join(", "))?;
+
+        for declaration in &self.declarations {
+            writeln!(f, "    {};", declaration)?;
+        }
+
+        write!(f, "}}")
+    }
+}
+
 impl Selector {
     pub fn new(simple: Vec<SimpleSelector>, combinators: Vec<char>) -> Selector {
         Selector {
@@ -130,29 +660,51 @@ impl Selector {
             combinators,
         }
     }
-}
 
-impl Default for Selector {
-    fn default() -> Self {
-        Selector {
-            simple: Vec::new(),
-            combinators: Vec::new(),
+    pub fn specificity(&self) -> Specificity {
+        let mut spec = (0, 0, 0);
+
+        for simple in &self.simple {
+            if simple.id.is_some() {
+                spec.0 += 1;
+            }
+            spec.1 += simple.classes.len() + simple.attributes.len() + simple.pseudo_classes.len();
+            if simple.tag_name.as_ref().is_some_and(|t| t != "*") {
+                spec.2 += 1;
+            }
         }
+
+        spec
     }
-}
 
-impl fmt::Debug for Selector {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    // `child_sep` is what goes around `>`: " " for readable output, "" for
+    // minified output.
+    fn to_css(&self, child_sep: &str) -> String {
         let mut result = String::new();
 
-        for sel in &self.simple {
-            if result.len() > 0 {
-                result.push_str(", ");
+        for (i, sel) in self.simple.iter().enumerate() {
+            if i > 0 {
+                match self.combinators.get(i - 1) {
+                    Some(' ') | None => result.push(' '),
+                    Some(c) => result.push_str(&format!("{}{}{}", child_sep, c, child_sep)),
+                }
             }
             result.push_str(&format!("{:?}", sel));
         }
 
-        write!(f, "{}", result)
+        result
+    }
+}
+
+impl fmt::Debug for Selector {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.to_css(" "))
+    }
+}
+
+impl fmt::Display for Selector {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.to_css(" "))
     }
 }
 
@@ -166,16 +718,8 @@ impl SimpleSelector {
             tag_name,
             id,
             classes,
-        }
-    }
-}
-
-impl Default for SimpleSelector {
-    fn default() -> Self {
-        SimpleSelector {
-            tag_name: None,
-            id: None,
-            classes: Vec::new(),
+            attributes: Vec::new(),
+            pseudo_classes: Vec::new(),
         }
     }
 }
@@ -184,31 +728,157 @@ impl fmt::Debug for SimpleSelector {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         let mut result = String::new();
 
-        match self.tag_name {
-            Some(ref t) => result.push_str(t),
+        match self.tag_name.as_deref() {
+            Some("*") => result.push('*'),
+            Some(t) => result.push_str(&escape_ident(t)),
             None => {}
         }
 
-        match self.id {
-            Some(ref id) => {
-                result.push('#');
-                result.push_str(id);
-            }
-            None => {}
+        if let Some(ref id) = self.id {
+            result.push('#');
+            result.push_str(&escape_ident(id));
         }
 
         for class in &self.classes {
             result.push('.');
-            result.push_str(class);
+            result.push_str(&escape_ident(class));
+        }
+
+        for attr in &self.attributes {
+            result.push_str(&format!("{}", attr));
+        }
+
+        for pseudo in &self.pseudo_classes {
+            result.push_str(&format!("{}", pseudo));
         }
 
         write!(f, "{}", result)
     }
 }
 
+// Backslash-escapes what can't appear literally in an identifier, so
+// `.a\:b` prints back as it was written rather than as `.a:b`. A leading
+// digit becomes a hex escape, as `\31 23` for `123`.
+fn escape_ident(ident: &str) -> String {
+    let mut escaped = String::with_capacity(ident.len());
+
+    for (i, c) in ident.chars().enumerate() {
+        let starts = i == 0 || (i == 1 && ident.starts_with('-'));
+        if starts && c.is_ascii_digit() {
+            escaped.push_str(&format!("\\{:x} ", c as u32));
+        } else if c.is_alphanumeric() || c == '-' || c == '_' || !c.is_ascii() {
+            escaped.push(c);
+        } else {
+            escaped.push('\\');
+            escaped.push(c);
+        }
+    }
+
+    escaped
+}
+
+impl fmt::Display for SimpleSelector {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+impl AttrSelector {
+    pub fn new(name: String, op: AttrOp) -> AttrSelector {
+        AttrSelector { name, op }
+    }
+
+    pub fn matches(&self, value: Option<&str>) -> bool {
+        let value = match value {
+            Some(v) => v,
+            None => return false,
+        };
+
+        match self.op {
+            AttrOp::Exists => true,
+            AttrOp::Equals(ref expected) => value == expected,
+            AttrOp::Prefix(ref prefix) => !prefix.is_empty() && value.starts_with(prefix.as_str()),
+            AttrOp::DashMatch(ref lang) => {
+                value == lang
+                    || value
+                        .strip_prefix(lang.as_str())
+                        .is_some_and(|rest| rest.starts_with('-'))
+            }
+            AttrOp::Substring(ref part) => !part.is_empty() && value.contains(part.as_str()),
+        }
+    }
+}
+
+impl fmt::Display for AttrSelector {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.op {
+            AttrOp::Exists => write!(f, "[{}]", self.name),
+            AttrOp::Equals(ref v) => write!(f, "[{}={:?}]", self.name, v),
+            AttrOp::Prefix(ref v) => write!(f, "[{}^={:?}]", self.name, v),
+            AttrOp::DashMatch(ref v) => write!(f, "[{}|={:?}]", self.name, v),
+            AttrOp::Substring(ref v) => write!(f, "[{}*={:?}]", self.name, v),
+        }
+    }
+}
+
+impl fmt::Debug for AttrSelector {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self)
+    }
+}
+
+impl PseudoClass {
+    // `position` is 1-based, `count` is the number of element siblings
+    // including the element itself.
+    pub fn matches(&self, position: usize, count: usize) -> bool {
+        match *self {
+            PseudoClass::FirstChild => position == 1,
+            PseudoClass::LastChild => position == count,
+            // In i64, so extreme `an+b` values like `-2147483648` or
+            // `-2147483648n` can't overflow.
+            PseudoClass::NthChild(a, b) => {
+                let (a, offset) = (i64::from(a), position as i64 - i64::from(b));
+                match a {
+                    0 => offset == 0,
+                    _ => offset % a == 0 && offset / a >= 0,
+                }
+            }
+        }
+    }
+}
+
+impl fmt::Display for PseudoClass {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            PseudoClass::FirstChild => write!(f, ":first-child"),
+            PseudoClass::LastChild => write!(f, ":last-child"),
+            PseudoClass::NthChild(0, b) => write!(f, ":nth-child({})", b),
+            PseudoClass::NthChild(a, 0) => write!(f, ":nth-child({}n)", a),
+            PseudoClass::NthChild(a, b) => write!(f, ":nth-child({}n{:+})", a, b),
+        }
+    }
+}
+
+impl fmt::Debug for PseudoClass {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self)
+    }
+}
+
 impl Declaration {
     pub fn new(property: String, value: Value) -> Declaration {
-        Declaration { property, value }
+        Declaration {
+            property,
+            value,
+            important: false,
+        }
+    }
+}
+
+impl Declaration {
+    fn to_minified(&self) -> String {
+        let important = if self.important { "!important" } else { "" };
+        format!("{}:{}{}", self.property, self.value, important)
     }
 }
 
@@ -217,13 +887,75 @@ impl Default for Declaration {
         Declaration {
             property: String::from(""),
             value: Value::Other(String::from("")),
+            important: false,
         }
     }
 }
 
 impl fmt::Debug for Declaration {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "{}: {:?}", self.property, self.value)
+        write!(f, "{}: {:?}", self.property, self.value)?;
+        if self.important {
+            write!(f, " !important")?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Declaration {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.property, self.value)?;
+        if self.important {
+            write!(f, " !important")?;
+        }
+        Ok(())
+    }
+}
+
+impl Value {
+    pub fn as_color(&self) -> Option<&Color> {
+        match *self {
+            Value::Color(ref c) => Some(c),
+            _ => None,
+        }
+    }
+
+    // Any dimension, angles and `fr` included, with its unit.
+    pub fn as_length(&self) -> Option<(f32, &Unit)> {
+        match *self {
+            Value::Length(n, ref unit) => Some((n, unit)),
+            _ => None,
+        }
+    }
+
+    // Lengths given in px. Other units need a `LengthContext` to become
+    // pixels; see `Unit::to_px`.
+    pub fn as_px(&self) -> Option<f32> {
+        match *self {
+            Value::Length(n, Unit::Px) => Some(n),
+            _ => None,
+        }
+    }
+
+    pub fn as_number(&self) -> Option<f32> {
+        match *self {
+            Value::Number(n) => Some(n),
+            _ => None,
+        }
+    }
+
+    pub fn as_integer(&self) -> Option<i32> {
+        match *self {
+            Value::Integer(n) => Some(n),
+            _ => None,
+        }
+    }
+
+    pub fn as_keyword(&self) -> Option<&str> {
+        match *self {
+            Value::Keyword(ref k) => Some(k),
+            _ => None,
+        }
     }
 }
 
@@ -232,15 +964,277 @@ impl fmt::Debug for Value {
         match *self {
             Value::Color(ref c) => write!(f, "{:?}", c),
             Value::Length(l, _) => write!(f, "{:?}", l),
+            Value::Number(n) => write!(f, "{:?}", n),
+            Value::Integer(n) => write!(f, "{}", n),
+            Value::Calc(ref c) => write!(f, "calc({:?})", c),
+            Value::Ratio(..)
+            | Value::FunctionList(_)
+            | Value::Global(_)
+            | Value::FontFamilyList(_)
+            | Value::ShadowList(_) => {
+                write!(f, "{}", self)
+            }
+            Value::List(ref values) => write!(f, "{:?}", values),
+            Value::Keyword(ref k) => write!(f, "{}", k),
             Value::Other(ref o) => write!(f, "{:?}", o),
         }
     }
 }
 
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            Value::Color(ref c) => write!(f, "{}", c),
+            Value::Length(l, ref unit) => write!(f, "{}{}", l, unit),
+            Value::Number(n) => write!(f, "{}", n),
+            Value::Integer(n) => write!(f, "{}", n),
+            Value::Ratio(w, h) => write!(f, "{} / {}", w, h),
+            Value::Calc(ref c) => write!(f, "calc({})", c),
+            Value::FunctionList(ref functions) => {
+                let calls: Vec<String> = functions.iter().map(|c| c.to_string()).collect();
+                write!(f, "{}", calls.join(" "))
+            }
+            Value::Global(keyword) => write!(f, "{}", keyword),
+            Value::List(ref values) => {
+                let values: Vec<String> = values.iter().map(|v| v.to_string()).collect();
+                write!(f, "{}", values.join(" "))
+            }
+            Value::FontFamilyList(ref families) => {
+                let families: Vec<String> = families.iter().map(|f| f.to_string()).collect();
+                write!(f, "{}", families.join(", "))
+            }
+            Value::ShadowList(ref shadows) => {
+                let shadows: Vec<String> = shadows.iter().map(|s| s.to_string()).collect();
+                write!(f, "{}", shadows.join(", "))
+            }
+            Value::Keyword(ref k) => write!(f, "{}", k),
+            Value::Other(ref o) => write!(f, "{}", o),
+        }
+    }
+}
+
+impl Unit {
+    pub fn to_px(&self, value: f32, ctx: &LengthContext) -> f32 {
+        match *self {
+            Unit::Px => value,
+            Unit::Em => value * ctx.font_size,
+            Unit::Rem => value * ctx.root_font_size,
+            Unit::Vh => value * ctx.viewport_height / 100.0,
+            Unit::Vw => value * ctx.viewport_width / 100.0,
+            Unit::Vmin => value * ctx.viewport_width.min(ctx.viewport_height) / 100.0,
+            Unit::Vmax => value * ctx.viewport_width.max(ctx.viewport_height) / 100.0,
+            Unit::Percent => value * ctx.containing_width / 100.0,
+            // Angles and grid fractions aren't lengths; they come back
+            // unchanged.
+            Unit::Deg | Unit::Rad | Unit::Grad | Unit::Turn | Unit::Fr => value,
+        }
+    }
+
+    // `value` of this angle unit in degrees, or `None` for length units.
+    pub fn to_degrees(&self, value: f32) -> Option<f32> {
+        match *self {
+            Unit::Deg => Some(value),
+            Unit::Rad => Some(value.to_degrees()),
+            Unit::Grad => Some(value * 0.9),
+            Unit::Turn => Some(value * 360.0),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Unit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let unit = match *self {
+            Unit::Px => "px",
+            Unit::Em => "em",
+            Unit::Rem => "rem",
+            Unit::Vh => "vh",
+            Unit::Vw => "vw",
+            Unit::Vmin => "vmin",
+            Unit::Vmax => "vmax",
+            Unit::Percent => "%",
+            Unit::Deg => "deg",
+            Unit::Rad => "rad",
+            Unit::Grad => "grad",
+            Unit::Turn => "turn",
+            Unit::Fr => "fr",
+        };
+
+        write!(f, "{}", unit)
+    }
+}
+
+impl fmt::Debug for Unit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self)
+    }
+}
+
+impl CalcNode {
+    pub fn eval(&self, ctx: &LengthContext) -> f32 {
+        match *self {
+            CalcNode::Number(n) => n,
+            CalcNode::Length(n, ref unit) => unit.to_px(n, ctx),
+            CalcNode::Add(ref l, ref r) => l.eval(ctx) + r.eval(ctx),
+            CalcNode::Sub(ref l, ref r) => l.eval(ctx) - r.eval(ctx),
+            CalcNode::Mul(ref l, ref r) => l.eval(ctx) * r.eval(ctx),
+            CalcNode::Div(ref l, ref r) => l.eval(ctx) / r.eval(ctx),
+        }
+    }
+}
+
+impl fmt::Debug for CalcNode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            CalcNode::Number(n) => write!(f, "{}", n),
+            CalcNode::Length(n, ref unit) => write!(f, "{}{:?}", n, unit),
+            CalcNode::Add(ref l, ref r) => write!(f, "({:?} + {:?})", l, r),
+            CalcNode::Sub(ref l, ref r) => write!(f, "({:?} - {:?})", l, r),
+            CalcNode::Mul(ref l, ref r) => write!(f, "{:?} * {:?}", l, r),
+            CalcNode::Div(ref l, ref r) => write!(f, "{:?} / {:?}", l, r),
+        }
+    }
+}
+
+impl fmt::Display for CssWide {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let keyword = match *self {
+            CssWide::Inherit => "inherit",
+            CssWide::Initial => "initial",
+            CssWide::Unset => "unset",
+        };
+
+        write!(f, "{}", keyword)
+    }
+}
+
+// Names are quoted so they re-parse as names even when they contain
+// spaces or match a generic family.
+impl fmt::Display for FontFamily {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            FontFamily::Name(ref name) => write!(f, "\"{}\"", name.replace('"', "\\\"")),
+            FontFamily::Generic(ref generic) => write!(f, "{}", generic),
+        }
+    }
+}
+
+impl fmt::Display for CssFunction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}({})", self.name, self.args.join(", "))
+    }
+}
+
+impl fmt::Display for Shadow {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.inset {
+            write!(f, "inset ")?;
+        }
+        write!(
+            f,
+            "{} {} {} {}",
+            self.offset_x, self.offset_y, self.blur, self.spread
+        )?;
+        match self.color {
+            Some(color) => write!(f, " {}", color),
+            None => Ok(()),
+        }
+    }
+}
+
+// Operands of `*` and `/` get parentheses when they are sums. Operators
+// group to the left, so a right operand also gets them when it has the same
+// precedence: `100px / (2 * 5)` isn't `100px / 2 * 5`. The output re-parses
+// to the same tree.
+impl fmt::Display for CalcNode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let operand = |node: &CalcNode| match *node {
+            CalcNode::Add(..) | CalcNode::Sub(..) => format!("({})", node),
+            _ => node.to_string(),
+        };
+        let right_operand = |node: &CalcNode| match *node {
+            CalcNode::Mul(..) | CalcNode::Div(..) => format!("({})", node),
+            _ => operand(node),
+        };
+
+        match *self {
+            CalcNode::Number(n) => write!(f, "{}", n),
+            CalcNode::Length(n, ref unit) => write!(f, "{}{}", n, unit),
+            CalcNode::Add(ref l, ref r) => write!(f, "{} + {}", l, operand(r)),
+            CalcNode::Sub(ref l, ref r) => write!(f, "{} - {}", l, operand(r)),
+            CalcNode::Mul(ref l, ref r) => write!(f, "{} * {}", operand(l), right_operand(r)),
+            CalcNode::Div(ref l, ref r) => write!(f, "{} / {}", operand(l), right_operand(r)),
+        }
+    }
+}
+
+impl LengthContext {
+    pub fn new(font_size: f32, viewport_width: f32, viewport_height: f32) -> LengthContext {
+        LengthContext {
+            font_size,
+            root_font_size: font_size,
+            viewport_width,
+            viewport_height,
+            containing_width: viewport_width,
+        }
+    }
+}
+
+impl Default for LengthContext {
+    fn default() -> Self {
+        LengthContext::new(16.0, 0.0, 0.0)
+    }
+}
+
 impl Color {
     pub fn new(r: f32, g: f32, b: f32, a: f32) -> Color {
         Color { r, g, b, a }
     }
+
+    pub fn from_rgba8([r, g, b, a]: [u8; 4]) -> Color {
+        let channel = |c: u8| c as f32 / 255.0;
+        Color::new(channel(r), channel(g), channel(b), channel(a))
+    }
+
+    // Out-of-range channels are clamped first.
+    pub fn to_rgba8(&self) -> [u8; 4] {
+        [
+            to_u8_channel(self.r),
+            to_u8_channel(self.g),
+            to_u8_channel(self.b),
+            to_u8_channel(self.a),
+        ]
+    }
+
+    // Moves each color channel `amount` of the way to white (1.0 is white).
+    // Alpha is kept.
+    pub fn lighten(&self, amount: f32) -> Color {
+        self.mix(&Color::new(1.0, 1.0, 1.0, self.a), amount)
+    }
+
+    // Moves each color channel `amount` of the way to black.
+    pub fn darken(&self, amount: f32) -> Color {
+        self.mix(&Color::new(0.0, 0.0, 0.0, self.a), amount)
+    }
+
+    // Linear blend of every channel, alpha included: `weight` 0.0 gives
+    // `self`, 1.0 gives `other` and 0.5 their average. Both the weight and
+    // the resulting channels are clamped to 0.0-1.0.
+    pub fn mix(&self, other: &Color, weight: f32) -> Color {
+        let w = weight.clamp(0.0, 1.0);
+        let blend = |a: f32, b: f32| (a + (b - a) * w).clamp(0.0, 1.0);
+
+        Color::new(
+            blend(self.r, other.r),
+            blend(self.g, other.g),
+            blend(self.b, other.b),
+            blend(self.a, other.a),
+        )
+    }
+}
+
+fn to_u8_channel(c: f32) -> u8 {
+    (c.clamp(0.0, 1.0) * 255.0).round() as u8
 }
 
 impl Default for Color {
@@ -254,3 +1248,363 @@ impl fmt::Debug for Color {
         write!(f, "r: {} g: {} b: {} a: {}", self.r, self.g, self.b, self.a)
     }
 }
+
+// Opaque colors as #rrggbb, translucent ones as #rrggbbaa.
+impl fmt::Display for Color {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let [r, g, b, a] = self.to_rgba8();
+
+        // Hex keeps parsed hex colors lossless; the alpha digits are only
+        // written when they aren't `ff`.
+        if a == 255 {
+            write!(f, "#{:02x}{:02x}{:02x}", r, g, b)
+        } else {
+            write!(f, "#{:02x}{:02x}{:02x}{:02x}", r, g, b, a)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::css_parser::CssParser;
+    use crate::html_parser::HtmlParser;
+    use crate::styles::StyledNode;
+
+    fn selector(text: &str) -> Selector {
+        CssParser::new(text)
+            .parse_selector_list()
+            .unwrap()
+            .remove(0)
+    }
+
+    #[test]
+    fn simple_selector_debug_is_canonical() {
+        let simple = SimpleSelector {
+            tag_name: Some(String::from("a")),
+            id: Some(String::from("main")),
+            classes: vec![String::from("btn"), String::from("big")],
+            attributes: vec![
+                AttrSelector::new(String::from("href"), AttrOp::Prefix(String::from("http"))),
+                AttrSelector::new(String::from("title"), AttrOp::Exists),
+            ],
+            pseudo_classes: vec![PseudoClass::FirstChild, PseudoClass::NthChild(2, 1)],
+        };
+        let canonical = "a#main.btn.big[href^=\"http\"][title]:first-child:nth-child(2n+1)";
+        assert_eq!(format!("{:?}", simple), canonical);
+
+        let reparsed = selector(canonical);
+        assert_eq!(reparsed.simple, [simple]);
+        assert_eq!(
+            format!("{:?}", selector("a:first-child[title].btn#main").simple[0]),
+            "a#main.btn[title]:first-child"
+        );
+        assert_eq!(
+            format!("{:?}", selector("li:nth-child(odd)").simple[0]),
+            "li:nth-child(2n+1)"
+        );
+    }
+
+    #[test]
+    fn split_selector_groups_gives_one_rule_per_selector() {
+        let stylesheet =
+            CssParser::new("h1, h2.x, h3 { color: red; width: 1px } p { height: 2px }")
+                .parse_stylesheet()
+                .split_selector_groups();
+
+        let rules: Vec<String> = stylesheet.rules.iter().map(Rule::to_minified).collect();
+        assert_eq!(
+            rules,
+            [
+                "h1{color:#ff0000;width:1px}",
+                "h2.x{color:#ff0000;width:1px}",
+                "h3{color:#ff0000;width:1px}",
+                "p{height:2px}",
+            ]
+        );
+    }
+
+    #[test]
+    fn dash_match_and_substring_attribute_operators() {
+        let lang = &selector("[lang|=en]").simple[0].attributes[0];
+        assert!(lang.op == AttrOp::DashMatch(String::from("en")));
+        assert!(lang.matches(Some("en")));
+        assert!(lang.matches(Some("en-US")));
+        assert!(!lang.matches(Some("fen")));
+        assert!(!lang.matches(Some("english")));
+        assert!(!lang.matches(None));
+
+        let class = &selector("[class*=btn]").simple[0].attributes[0];
+        assert!(class.op == AttrOp::Substring(String::from("btn")));
+        assert!(class.matches(Some("my-btn-lg")));
+        assert!(class.matches(Some("btn")));
+        assert!(!class.matches(Some("button")));
+        assert!(!selector("[class*='']").simple[0].attributes[0].matches(Some("x")));
+    }
+
+    #[test]
+    fn rgba8_round_trips_and_clamps() {
+        let orange = CssParser::new("a { color: #ff8800 }").parse_stylesheet();
+        let orange = orange.rules[0].declarations[0]
+            .value
+            .as_color()
+            .copied()
+            .unwrap();
+        assert_eq!(orange.to_rgba8(), [255, 136, 0, 255]);
+        assert_eq!(Color::from_rgba8(orange.to_rgba8()), orange);
+        assert_eq!(
+            Color::from_rgba8([255, 136, 0, 128]).to_rgba8(),
+            [255, 136, 0, 128]
+        );
+
+        assert_eq!(
+            Color::new(1.5, -0.2, 0.5, 2.0).to_rgba8(),
+            [255, 0, 128, 255]
+        );
+    }
+
+    fn parse_color(css: &str) -> Color {
+        let stylesheet = CssParser::new(&format!("a {{ color: {} }}", css)).parse_stylesheet();
+        *stylesheet.rules[0].declarations[0]
+            .value
+            .as_color()
+            .unwrap()
+    }
+
+    #[test]
+    fn hex_colors_round_trip_through_display() {
+        for (hex, canonical) in [
+            ("#abc", "#aabbcc"),
+            ("#aabbcc", "#aabbcc"),
+            ("#AABBCC", "#aabbcc"),
+            ("#abcd", "#aabbccdd"),
+            ("#aabbccdd", "#aabbccdd"),
+            ("#11223344", "#11223344"),
+            ("#aabbccff", "#aabbcc"),
+        ] {
+            let color = parse_color(hex);
+            assert_eq!(color.to_string(), canonical, "{}", hex);
+            assert_eq!(parse_color(canonical), color, "{}", hex);
+        }
+    }
+
+    #[test]
+    fn rule_builder_matches_like_parsed_css() {
+        let built = rule()
+            .select_tag("div")
+            .select_class("active")
+            .child()
+            .select_tag("p")
+            .select_attr("lang", AttrOp::DashMatch(String::from("en")))
+            .or()
+            .select_id("main")
+            .declare("color", Value::Color(Color::new(1.0, 0.0, 0.0, 1.0)))
+            .declare("width", Value::Length(5.0, Unit::Px))
+            .build();
+        assert_eq!(
+            built.to_minified(),
+            "div.active>p[lang|=\"en\"],#main{color:#ff0000;width:5px}"
+        );
+
+        let stylesheet = Stylesheet::new(vec![built]);
+        let nodes = HtmlParser::new(
+            "<div class='active x'><p id=a lang=en-GB></p><p id=b></p><span><p id=c lang=en></p></span></div>",
+        )
+        .parse_with_diagnostics()
+        .0;
+        let styled = StyledNode::new(&nodes[0], &stylesheet);
+
+        let widths: Vec<Option<f32>> = styled
+            .children
+            .iter()
+            .map(|child| child.value("width").and_then(|v| v.as_px()))
+            .collect();
+        assert_eq!(widths, [Some(5.0), None, None]);
+        assert!(styled.children[2].children[0].value("width").is_none());
+    }
+
+    #[test]
+    fn lighten_darken_and_mix() {
+        let base = Color::new(0.25, 0.5, 0.75, 0.5);
+
+        let light = base.lighten(0.5);
+        assert_eq!(light, Color::new(0.625, 0.75, 0.875, 0.5));
+        assert!(light.r > base.r && light.g > base.g && light.b > base.b);
+
+        let dark = base.darken(0.5);
+        assert_eq!(dark, Color::new(0.125, 0.25, 0.375, 0.5));
+        assert_eq!(base.darken(2.0), Color::new(0.0, 0.0, 0.0, 0.5));
+        assert_eq!(base.lighten(0.0), base);
+
+        let red = Color::new(1.0, 0.0, 0.0, 1.0);
+        let blue = Color::new(0.0, 0.0, 1.0, 0.0);
+        assert_eq!(red.mix(&blue, 0.5), Color::new(0.5, 0.0, 0.5, 0.5));
+        assert_eq!(red.mix(&blue, 0.0), red);
+        assert_eq!(red.mix(&blue, 1.0), blue);
+        assert_eq!(red.mix(&blue, -1.0), red);
+    }
+
+    #[test]
+    fn value_accessors_match_only_their_variant() {
+        let red = Color::new(1.0, 0.0, 0.0, 1.0);
+        let values = [
+            Value::Color(red),
+            Value::Length(2.0, Unit::Em),
+            Value::Length(3.0, Unit::Px),
+            Value::Number(1.5),
+            Value::Integer(4),
+            Value::Keyword(String::from("auto")),
+        ];
+
+        let colors: Vec<_> = values.iter().map(Value::as_color).collect();
+        assert_eq!(colors, [Some(&red), None, None, None, None, None]);
+        let lengths: Vec<_> = values.iter().map(Value::as_length).collect();
+        assert_eq!(
+            lengths,
+            [
+                None,
+                Some((2.0, &Unit::Em)),
+                Some((3.0, &Unit::Px)),
+                None,
+                None,
+                None
+            ]
+        );
+        let px: Vec<_> = values.iter().map(Value::as_px).collect();
+        assert_eq!(px, [None, None, Some(3.0), None, None, None]);
+        let numbers: Vec<_> = values.iter().map(Value::as_number).collect();
+        assert_eq!(numbers, [None, None, None, Some(1.5), None, None]);
+        let integers: Vec<_> = values.iter().map(Value::as_integer).collect();
+        assert_eq!(integers, [None, None, None, None, Some(4), None]);
+        let keywords: Vec<_> = values.iter().map(Value::as_keyword).collect();
+        assert_eq!(keywords, [None, None, None, None, None, Some("auto")]);
+    }
+
+    #[test]
+    fn dedupe_merges_rules_with_equal_selectors() {
+        let mut stylesheet = CssParser::new(
+            ".btn { color: red; width: 1px !important } a { height: 1px } \
+             .btn { color: blue; width: 2px; margin-top: 3px }",
+        )
+        .parse_stylesheet();
+        stylesheet.dedupe();
+
+        let rules: Vec<String> = stylesheet.rules.iter().map(Rule::to_string).collect();
+        assert_eq!(
+            rules,
+            [
+                ".btn {\n    width: 1px !important;\n    color: #0000ff;\n    margin-top: 3px;\n}",
+                "a {\n    height: 1px;\n}",
+            ]
+        );
+
+        // `.x` can't move past the `p` rule that also sets `width`.
+        let mut stylesheet = CssParser::new(".x { width: 1px } p { width: 2px } .x { width: 3px }")
+            .parse_stylesheet();
+        stylesheet.dedupe();
+        assert_eq!(stylesheet.rules.len(), 3);
+    }
+
+    #[test]
+    fn values_and_units_are_cloneable_and_hashable() {
+        let length = Value::Length(1.5, Unit::Em);
+        let color = Value::Color(Color::new(0.2, 0.4, 0.6, 0.5));
+        let list = Value::List(vec![length.clone(), color.clone()]);
+        assert_eq!(length.clone(), length);
+        assert_eq!(color.clone(), color);
+        assert_eq!(list.clone(), list);
+
+        let units: HashSet<Unit> = [Unit::Px, Unit::Em, Unit::Px].into_iter().collect();
+        assert_eq!(units.len(), 2);
+        assert!(units.contains(&Unit::Em));
+    }
+
+    #[test]
+    fn nth_child_with_extreme_values_does_not_overflow() {
+        let (min, max) = (i32::MIN, i32::MAX);
+        let cases = [
+            (PseudoClass::NthChild(0, min), 1, false),
+            (PseudoClass::NthChild(0, max), 1, false),
+            (PseudoClass::NthChild(-1, min), 1, false),
+            (PseudoClass::NthChild(-1, 3), 2, true),
+            (PseudoClass::NthChild(-1, 3), 4, false),
+            (PseudoClass::NthChild(min, 1), 1, true),
+            (PseudoClass::NthChild(min, max), 1, false),
+            (PseudoClass::NthChild(max, min), 1, false),
+            (PseudoClass::NthChild(max, 1), 1, true),
+        ];
+        for (pseudo, position, expected) in cases {
+            assert_eq!(
+                pseudo.matches(position, 5),
+                expected,
+                "{} at {}",
+                pseudo,
+                position
+            );
+        }
+
+        let html = HtmlParser::new("<ul><li></li><li></li></ul>").parse_nodes();
+        for selector in [
+            "li:nth-child(-2147483648)",
+            "li:nth-child(-1n-2147483648)",
+            "li:nth-child(-2147483648n+2147483647)",
+        ] {
+            let stylesheet =
+                CssParser::new(&format!("{} {{ color: red }}", selector)).parse_stylesheet();
+            assert_eq!(stylesheet.rules[0].selectors.len(), 1, "{}", selector);
+            let styled = StyledNode::new(&html[0], &stylesheet);
+            assert!(
+                styled.children.iter().all(|li| li.value("color").is_none()),
+                "{}",
+                selector
+            );
+        }
+    }
+
+    #[test]
+    fn calc_values_reparse_to_the_same_tree() {
+        let width = |css: &str| {
+            CssParser::new(css).parse_stylesheet().rules[0].declarations[0]
+                .value
+                .clone()
+        };
+        let cases = [
+            ("calc(100px / (2 * 5))", "calc(100px / (2 * 5))"),
+            ("calc(100px / (2 / 5))", "calc(100px / (2 / 5))"),
+            ("calc(2 * (3 * 4px))", "calc(2 * (3 * 4px))"),
+            ("calc(100px / 2 * 5)", "calc(100px / 2 * 5)"),
+            (
+                "calc((1px + 2px) * 3 - (4px - 5px))",
+                "calc((1px + 2px) * 3 - (4px - 5px))",
+            ),
+            ("calc(1px + (2px + 3px))", "calc(1px + (2px + 3px))"),
+            ("calc(1px + 2px + 3px)", "calc(1px + 2px + 3px)"),
+        ];
+
+        for (calc, printed) in cases {
+            let sheet = format!("a {{ width: {} }}", calc);
+            let value = width(&sheet);
+            assert_eq!(value.to_string(), printed);
+
+            let stylesheet = CssParser::new(&sheet).parse_stylesheet();
+            for output in [stylesheet.to_string(), stylesheet.to_minified()] {
+                assert_eq!(width(&output), value, "{}", output);
+            }
+        }
+    }
+
+    #[test]
+    fn minified_output_is_compact_and_reparses() {
+        let css = "h1,  h2 > a.b {\n  color: red;\n  margin: 0 auto;\n}\n\n#main   p {\n  width: 10px !important;\n}\n";
+        let minified = CssParser::new(css).parse_stylesheet().to_minified();
+        assert_eq!(
+            minified,
+            "h1,h2>a.b{color:#ff0000;margin-top:0px;margin-right:auto;margin-bottom:0px;margin-left:auto}\
+             #main p{width:10px!important}"
+        );
+
+        let reparsed = CssParser::new(&minified).parse_stylesheet();
+        assert_eq!(reparsed.rules.len(), 2);
+        assert_eq!(reparsed.to_minified(), minified);
+    }
+}
@@ -1,10 +1,19 @@
+use crate::css::{Selector, Stylesheet};
+use crate::css_parser::{CssParseError, CssParser};
+use crate::html_parser::{sniff_meta_charset, Diagnostic, HtmlParser, ParseError};
+use crate::styles::{element_children, element_count, is_selector_matches, ElementContext};
 use core::fmt;
+use std::borrow::Cow;
 use std::collections::{HashMap, HashSet};
+use std::rc::Rc;
 
-#[derive(PartialEq, Eq)]
 pub struct Node {
     pub children: Vec<Node>,
     pub node_type: NodeType,
+    // Byte range of the node in the parsed source, from its first `<` to
+    // the end of its end tag. Only set with `ParseOptions::record_spans`,
+    // and ignored when comparing nodes.
+    pub span: Option<(usize, usize)>,
 }
 
 #[derive(PartialEq, Eq, Clone)]
@@ -14,16 +23,18 @@ pub enum NodeType {
     Comment(String),
 }
 
+// Tag names and attribute names are `Rc<str>` so the parser can share one
+// allocation between every element spelling the same name.
 #[derive(PartialEq, Eq, Clone)]
 pub struct ElementData {
-    pub tag_name: String,
+    pub tag_name: Rc<str>,
     attributes: AttrMap,
 }
 
 impl ElementData {
-    pub fn new(tag_name: String, attributes: AttrMap) -> ElementData {
+    pub fn new(tag_name: impl Into<Rc<str>>, attributes: AttrMap) -> ElementData {
         ElementData {
-            tag_name,
+            tag_name: tag_name.into(),
             attributes,
         }
     }
@@ -32,25 +43,989 @@ impl ElementData {
         self.attributes.get("id")
     }
 
+    // The attribute value with character references such as `&amp;` decoded.
+    // Values without any come back borrowed.
+    pub fn get_attribute(&self, name: &str) -> Option<Cow<'_, str>> {
+        self.get_attribute_raw(name).map(decode_entities)
+    }
+
+    // The attribute value exactly as written in the source.
+    pub fn get_attribute_raw(&self, name: &str) -> Option<&str> {
+        self.attributes.get(name).map(|v| v.as_str())
+    }
+
+    // A URL attribute such as `href` or `src` made absolute against `base`,
+    // the document's own URL. `get_attribute` still gives the value as
+    // written. `None` if the attribute is missing or `base` isn't absolute.
+    pub fn get_url(&self, name: &str, base: &str) -> Option<String> {
+        resolve_against(&self.get_attribute(name)?, base)
+    }
+
+    // Every attribute as `(name, raw value)`, sorted by name, for output
+    // that doesn't depend on `AttrMap`'s iteration order.
+    pub fn sorted_attributes(&self) -> Vec<(&str, &str)> {
+        let mut attrs: Vec<(&str, &str)> = self
+            .attributes
+            .iter()
+            .map(|(name, value)| (&**name, value.as_str()))
+            .collect();
+        attrs.sort();
+        attrs
+    }
+
+    pub(crate) fn into_attributes(self) -> AttrMap {
+        self.attributes
+    }
+
+    // Classes are separated by any run of whitespace, so `class=""` and
+    // `class="a  b"` don't produce empty class names.
     pub fn get_classes(&self) -> HashSet<&str> {
         match self.attributes.get("class") {
-            Some(s) => s.split(' ').collect(),
+            Some(s) => s.split_ascii_whitespace().collect(),
             None => HashSet::new(),
         }
     }
+
+    // The `data-*` attributes keyed by the rest of their name, so
+    // `data-role="btn"` gives `role` => `btn`. Unlike the DOM's `dataset`,
+    // names aren't camel-cased. Values are raw, as in `get_attribute_raw`.
+    pub fn dataset(&self) -> HashMap<&str, &str> {
+        self.attributes
+            .iter()
+            .filter_map(|(name, value)| {
+                let key = name.strip_prefix("data-").filter(|key| !key.is_empty())?;
+                Some((key, value.as_str()))
+            })
+            .collect()
+    }
+}
+
+pub type AttrMap = HashMap<Rc<str>, String>;
+
+// https://html.spec.whatwg.org/multipage/syntax.html#void-elements
+pub fn is_void_element(tag_name: &str) -> bool {
+    matches!(
+        tag_name,
+        "area"
+            | "base"
+            | "br"
+            | "col"
+            | "embed"
+            | "hr"
+            | "img"
+            | "input"
+            | "link"
+            | "meta"
+            | "source"
+            | "track"
+            | "wbr"
+    )
+}
+
+// Controls `Node::to_html_with`. The default is compact output with
+// `<br>`-style void elements.
+pub struct HtmlOptions {
+    // Put every child on its own line, indented by `indent_width` spaces
+    // per level.
+    pub pretty: bool,
+    pub indent_width: usize,
+    // Write void elements as `<br/>` instead of `<br>`.
+    pub self_close_void: bool,
+}
+
+impl Default for HtmlOptions {
+    fn default() -> Self {
+        HtmlOptions {
+            pretty: false,
+            indent_width: 2,
+            self_close_void: false,
+        }
+    }
 }
 
-pub type AttrMap = HashMap<String, String>;
+// One difference found by `diff`. Paths are child indices from the root,
+// so `[]` is the root itself and `[1, 0]` its second child's first child.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum Change {
+    NodeAdded(Vec<usize>),
+    NodeRemoved(Vec<usize>),
+    // The content of a text (or comment) node.
+    TextChanged {
+        path: Vec<usize>,
+        old: String,
+        new: String,
+    },
+    // `None` on one side means the attribute was added or removed.
+    AttributeChanged {
+        path: Vec<usize>,
+        name: String,
+        old: Option<String>,
+        new: Option<String>,
+    },
+}
+
+// Callbacks for `Node::accept`. Every method does nothing by default, so a
+// visitor only implements the node kinds it cares about.
+pub trait Visitor {
+    fn visit_element(&mut self, _element: &ElementData) {}
+    fn visit_text(&mut self, _text: &str) {}
+    fn visit_comment(&mut self, _comment: &str) {}
+}
+
+// Callbacks for `Node::emit_events`, in the style of a SAX parser. Unlike
+// `Visitor`, every element also reports where it ends, void ones included.
+pub trait SaxHandler {
+    fn start_element(&mut self, _element: &ElementData) {}
+    fn end_element(&mut self, _element: &ElementData) {}
+    fn characters(&mut self, _text: &str) {}
+    fn comment(&mut self, _comment: &str) {}
+}
+
+// Markup found by `Node::validate_nesting` that HTML's content models
+// don't allow. Paths are as in `Change`, relative to the validated node.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum NestingViolation {
+    // `tag_name` sits under `parent` (`None` at the top), which isn't one of
+    // the parents it requires, like a `<td>` outside a `<tr>`.
+    WrongParent {
+        path: Vec<usize>,
+        tag_name: String,
+        parent: Option<String>,
+    },
+    // `tag_name` is somewhere inside an `ancestor` that may not contain it,
+    // like a `<div>` in a `<p>` or an `<a>` in another `<a>`.
+    ForbiddenDescendant {
+        path: Vec<usize>,
+        tag_name: String,
+        ancestor: String,
+    },
+}
+
+// A parsed document: the top-level nodes plus what was learned about the
+// source along the way. `HtmlParser` remains the lower-level entry point.
+pub struct Document {
+    pub nodes: Vec<Node>,
+    // What followed `<!DOCTYPE`, e.g. `html`.
+    pub doctype: Option<String>,
+    // The charset a `<meta>` near the start declares, lowercased.
+    pub charset: Option<String>,
+    // As returned by `HtmlParser::parse_all_errors`.
+    pub diagnostics: Vec<(usize, Diagnostic)>,
+}
+
+impl Document {
+    pub fn parse(source: &str) -> Document {
+        let mut parser = HtmlParser::new(source);
+        let nodes = parser.parse_nodes();
+
+        Document {
+            doctype: parser.doctype().map(String::from),
+            charset: sniff_meta_charset(source.as_bytes()),
+            diagnostics: parser.take_located_diagnostics(),
+            nodes,
+        }
+    }
+
+    // The first top-level element, normally `<html>`. Comments and text
+    // around it are only in `nodes`.
+    pub fn root(&self) -> Option<&Node> {
+        self.nodes.iter().find(|node| node.is_element())
+    }
+}
 
 impl Node {
     pub fn new(node_type: NodeType, children: Vec<Node>) -> Node {
         Node {
             node_type,
             children,
+            span: None,
+        }
+    }
+
+    pub fn as_element(&self) -> Option<&ElementData> {
+        match self.node_type {
+            NodeType::Element(ref e) => Some(e),
+            _ => None,
+        }
+    }
+
+    // Text nodes only; comments are `None` too.
+    pub fn as_text(&self) -> Option<&str> {
+        match self.node_type {
+            NodeType::Text(ref t) => Some(t),
+            _ => None,
         }
     }
+
+    pub fn is_element(&self) -> bool {
+        self.as_element().is_some()
+    }
+
+    pub fn tag_name(&self) -> Option<&str> {
+        self.as_element().map(|e| &*e.tag_name)
+    }
+
+    // Whether `other` is a strict descendant of `self`. Nodes are compared
+    // by address, so `other` must be a reference into this very tree; an
+    // equal copy elsewhere doesn't count.
+    pub fn contains(&self, other: &Node) -> bool {
+        self.children
+            .iter()
+            .any(|child| std::ptr::eq(child, other) || child.contains(other))
+    }
+
+    // The document's `<head>`, wherever it sits: `self`, a child of `<html>`
+    // or deeper. The parser doesn't insert missing sections, so a fragment
+    // without an explicit `<head>` gets `None`.
+    pub fn head(&self) -> Option<&Node> {
+        self.find_element("head")
+    }
+
+    // Same as `head`, for `<body>`.
+    pub fn body(&self) -> Option<&Node> {
+        self.find_element("body")
+    }
+
+    // The first element named `tag_name` in document order, `self` included.
+    fn find_element(&self, tag_name: &str) -> Option<&Node> {
+        if self.tag_name() == Some(tag_name) {
+            return Some(self);
+        }
+
+        self.children
+            .iter()
+            .find_map(|child| child.find_element(tag_name))
+    }
+
+    // Walks the tree depth-first in document order, calling `visitor` on
+    // each node before its children.
+    pub fn accept(&self, visitor: &mut impl Visitor) {
+        match self.node_type {
+            NodeType::Element(ref e) => visitor.visit_element(e),
+            NodeType::Text(ref t) => visitor.visit_text(t),
+            NodeType::Comment(ref c) => visitor.visit_comment(c),
+        }
+
+        for child in &self.children {
+            child.accept(visitor);
+        }
+    }
+
+    // Replays the tree as a stream of events in document order: an
+    // element's `start_element`, the events of its children, then its
+    // `end_element`.
+    pub fn emit_events(&self, handler: &mut impl SaxHandler) {
+        match self.node_type {
+            NodeType::Element(ref e) => {
+                handler.start_element(e);
+                for child in &self.children {
+                    child.emit_events(handler);
+                }
+                handler.end_element(e);
+            }
+            NodeType::Text(ref t) => handler.characters(t),
+            NodeType::Comment(ref c) => handler.comment(c),
+        }
+    }
+
+    pub fn to_html(&self) -> String {
+        self.to_html_with(&HtmlOptions::default())
+    }
+
+    pub fn to_html_with(&self, options: &HtmlOptions) -> String {
+        let mut out = String::new();
+        write_html(self, options, 0, false, &mut out);
+        out
+    }
+
+    // Every text node in the tree, in document order, whose content satisfies
+    // `pred`. Comments are not searched.
+    pub fn find_text<F: Fn(&str) -> bool>(&self, pred: F) -> Vec<&str> {
+        let mut found = Vec::new();
+        collect_text(self, &pred, &mut found);
+        found
+    }
+
+    // All text in the tree concatenated as stored, script and style bodies
+    // included, like the DOM's `textContent`.
+    pub fn text_content(&self) -> String {
+        self.find_text(|_| true).concat()
+    }
+
+    // The text a reader would see, approximating the DOM's `innerText`:
+    // whitespace collapsed, a line break around block elements and at each
+    // `<br>`, and script, style and `<head>` content left out. Text inside
+    // `<pre>` keeps its whitespace.
+    pub fn inner_text(&self) -> String {
+        let mut out = String::new();
+        collect_inner_text(self, false, &mut out);
+        out.trim_end().to_string()
+    }
+
+    // Replaces the content of a text node; returns false (and changes
+    // nothing) for any other node. The text is stored as-is and escaped by
+    // `to_html`, so it can't inject markup.
+    pub fn set_text(&mut self, text: &str) -> bool {
+        match self.node_type {
+            NodeType::Text(ref mut t) => {
+                *t = text.to_string();
+                true
+            }
+            _ => false,
+        }
+    }
+
+    // Replaces the children of this element with `new_html` parsed on its
+    // own, leaving the rest of the tree untouched. Markup the parser has to
+    // repair (unclosed elements, stray end tags) is rejected and the old
+    // children are kept. Script, style and textarea take `new_html` as raw text.
+    pub fn reparse_subtree(&mut self, new_html: &str) -> Result<(), ParseError> {
+        let tag_name = match self.node_type {
+            NodeType::Element(ref e) => e.tag_name.clone(),
+            _ => return Err(ParseError::NotAnElement),
+        };
+
+        if matches!(&*tag_name, "script" | "style" | "textarea") {
+            self.children = if new_html.is_empty() {
+                Vec::new()
+            } else {
+                vec![Node::new(NodeType::Text(new_html.to_string()), Vec::new())]
+            };
+            return Ok(());
+        }
+
+        let (children, diagnostics) = HtmlParser::new(new_html).parse_with_diagnostics();
+        if !diagnostics.is_empty() {
+            return Err(ParseError::Malformed(diagnostics));
+        }
+
+        self.children = children;
+        Ok(())
+    }
+
+    // Swaps every node for which `pred` holds, `self` included, with what
+    // `make` returns for it. `make` receives the old node by value, so it can
+    // move the children over or drop them. Replacements aren't searched
+    // again. Returns how many nodes were replaced.
+    pub fn replace_matching<F, G>(&mut self, pred: F, mut make: G) -> usize
+    where
+        F: Fn(&Node) -> bool,
+        G: FnMut(Node) -> Node,
+    {
+        replace_nodes(self, &pred, &mut make)
+    }
+
+    // Checks the tree against a small table of nesting rules and reports
+    // every element breaking one, in document order. The tree is left as is;
+    // the parser doesn't fix up invalid nesting either.
+    pub fn validate_nesting(&self) -> Vec<NestingViolation> {
+        let mut violations = Vec::new();
+        check_nesting(self, &mut Vec::new(), &mut Vec::new(), &mut violations);
+        violations
+    }
+
+    // Parses the contents of every `<style>` element in the tree and merges
+    // the rules, in document order, into one stylesheet.
+    pub fn extract_stylesheets(&self) -> Stylesheet {
+        let mut stylesheet = Stylesheet::default();
+        collect_styles(self, &mut stylesheet);
+        stylesheet
+    }
+
+    // All descendant elements matching `selector`, in document order. `self`
+    // only takes part as an ancestor, like `querySelectorAll`.
+    pub fn query<'a>(&'a self, selector: &Selector) -> Vec<&'a Node> {
+        self.query_any(std::slice::from_ref(selector), false)
+    }
+
+    // The first descendant element matching `selector` in document order,
+    // like `querySelector`. Stops walking the tree once it's found.
+    pub fn query_first<'a>(&'a self, selector: &Selector) -> Option<&'a Node> {
+        self.query_any(std::slice::from_ref(selector), true).pop()
+    }
+
+    // Parses `selector_text` (a selector list such as `ul > li.item, .x`)
+    // and returns elements matching any of its selectors.
+    pub fn query_str<'a>(&'a self, selector_text: &str) -> Result<Vec<&'a Node>, CssParseError> {
+        let selectors = CssParser::new(selector_text).parse_selector_list()?;
+        Ok(self.query_any(&selectors, false))
+    }
+
+    // `query_first` for a selector list; see `query_str`.
+    pub fn query_first_str<'a>(
+        &'a self,
+        selector_text: &str,
+    ) -> Result<Option<&'a Node>, CssParseError> {
+        let selectors = CssParser::new(selector_text).parse_selector_list()?;
+        Ok(self.query_any(&selectors, true).pop())
+    }
+
+    // With `first_only`, returns at most one element and skips the rest of
+    // the tree as soon as it has it.
+    fn query_any<'a>(&'a self, selectors: &[Selector], first_only: bool) -> Vec<&'a Node> {
+        let mut found = Vec::new();
+        let mut ancestors = Vec::new();
+
+        if let NodeType::Element(ref e) = self.node_type {
+            ancestors.push(ElementContext {
+                data: e,
+                position: None,
+            });
+        }
+
+        let count = element_count(self);
+        for (i, child) in element_children(self).enumerate() {
+            if collect_matches(
+                child,
+                (i + 1, count),
+                selectors,
+                first_only,
+                &mut ancestors,
+                &mut found,
+            ) {
+                break;
+            }
+        }
+
+        found
+    }
+}
+
+// Structural changes turning `old` into `new`. Children are paired up by
+// position; a pair whose kind or tag differs is reported as a removal plus
+// an addition rather than diffed further.
+pub fn diff(old: &Node, new: &Node) -> Vec<Change> {
+    let mut changes = Vec::new();
+    diff_nodes(old, new, &mut Vec::new(), &mut changes);
+    changes
 }
 
+fn diff_nodes(old: &Node, new: &Node, path: &mut Vec<usize>, changes: &mut Vec<Change>) {
+    match (&old.node_type, &new.node_type) {
+        (NodeType::Text(a), NodeType::Text(b)) | (NodeType::Comment(a), NodeType::Comment(b)) => {
+            if a != b {
+                changes.push(Change::TextChanged {
+                    path: path.clone(),
+                    old: a.clone(),
+                    new: b.clone(),
+                });
+            }
+        }
+        (NodeType::Element(a), NodeType::Element(b)) if a.tag_name == b.tag_name => {
+            let mut names: Vec<&Rc<str>> = a.attributes.keys().chain(b.attributes.keys()).collect();
+            names.sort();
+            names.dedup();
+
+            for name in names {
+                let (old_value, new_value) = (a.attributes.get(name), b.attributes.get(name));
+                if old_value != new_value {
+                    changes.push(Change::AttributeChanged {
+                        path: path.clone(),
+                        name: name.to_string(),
+                        old: old_value.cloned(),
+                        new: new_value.cloned(),
+                    });
+                }
+            }
+        }
+        _ => {
+            changes.push(Change::NodeRemoved(path.clone()));
+            changes.push(Change::NodeAdded(path.clone()));
+            return;
+        }
+    }
+
+    let common = old.children.len().min(new.children.len());
+    for i in 0..old.children.len().max(new.children.len()) {
+        path.push(i);
+        if i < common {
+            diff_nodes(&old.children[i], &new.children[i], path, changes);
+        } else if i < old.children.len() {
+            changes.push(Change::NodeRemoved(path.clone()));
+        } else {
+            changes.push(Change::NodeAdded(path.clone()));
+        }
+        path.pop();
+    }
+}
+
+fn replace_nodes<F, G>(node: &mut Node, pred: &F, make: &mut G) -> usize
+where
+    F: Fn(&Node) -> bool,
+    G: FnMut(Node) -> Node,
+{
+    if pred(node) {
+        let old = std::mem::replace(node, Node::new(NodeType::Text(String::new()), Vec::new()));
+        *node = make(old);
+        return 1;
+    }
+
+    node.children
+        .iter_mut()
+        .map(|child| replace_nodes(child, pred, make))
+        .sum()
+}
+
+fn collect_text<'a, F: Fn(&str) -> bool>(node: &'a Node, pred: &F, found: &mut Vec<&'a str>) {
+    if let NodeType::Text(ref t) = node.node_type {
+        if pred(t) {
+            found.push(t);
+        }
+    }
+
+    for child in &node.children {
+        collect_text(child, pred, found);
+    }
+}
+
+// Elements that must be a direct child of one of the listed elements.
+const REQUIRED_PARENTS: &[(&str, &[&str])] = &[
+    ("td", &["tr"]),
+    ("th", &["tr"]),
+    ("tr", &["table", "thead", "tbody", "tfoot"]),
+    ("thead", &["table"]),
+    ("tbody", &["table"]),
+    ("tfoot", &["table"]),
+    ("caption", &["table"]),
+    ("li", &["ul", "ol", "menu"]),
+];
+
+type TagCheck = fn(&str) -> bool;
+
+// Elements that may not contain, at any depth, an element the check accepts.
+const FORBIDDEN_DESCENDANTS: &[(&str, TagCheck)] = &[
+    ("p", is_block_element),
+    ("a", is_interactive_element),
+    ("button", is_interactive_element),
+];
+
+// `ancestors` holds the tag names from the validated node down to the
+// parent of `node`.
+fn check_nesting<'a>(
+    node: &'a Node,
+    ancestors: &mut Vec<&'a str>,
+    path: &mut Vec<usize>,
+    violations: &mut Vec<NestingViolation>,
+) {
+    let tag_name = match node.tag_name() {
+        Some(tag_name) => tag_name,
+        None => return,
+    };
+
+    let parent = ancestors.last().copied();
+    for (child, parents) in REQUIRED_PARENTS {
+        if *child == tag_name && !parent.is_some_and(|p| parents.contains(&p)) {
+            violations.push(NestingViolation::WrongParent {
+                path: path.clone(),
+                tag_name: tag_name.to_string(),
+                parent: parent.map(String::from),
+            });
+        }
+    }
+
+    for (ancestor, forbidden) in FORBIDDEN_DESCENDANTS {
+        if forbidden(tag_name) && ancestors.contains(ancestor) {
+            violations.push(NestingViolation::ForbiddenDescendant {
+                path: path.clone(),
+                tag_name: tag_name.to_string(),
+                ancestor: ancestor.to_string(),
+            });
+        }
+    }
+
+    ancestors.push(tag_name);
+    for (i, child) in node.children.iter().enumerate() {
+        path.push(i);
+        check_nesting(child, ancestors, path, violations);
+        path.pop();
+    }
+    ancestors.pop();
+}
+
+// https://html.spec.whatwg.org/multipage/dom.html#interactive-content
+fn is_interactive_element(tag_name: &str) -> bool {
+    matches!(
+        tag_name,
+        "a" | "button" | "details" | "embed" | "iframe" | "input" | "label" | "select" | "textarea"
+    )
+}
+
+fn collect_inner_text(node: &Node, preformatted: bool, out: &mut String) {
+    let tag_name = match node.node_type {
+        NodeType::Text(ref t) if preformatted => {
+            out.push_str(t);
+            return;
+        }
+        NodeType::Text(ref t) => {
+            for c in t.chars() {
+                if !c.is_whitespace() {
+                    out.push(c);
+                } else if !out.is_empty() && !out.ends_with([' ', '\n']) {
+                    out.push(' ');
+                }
+            }
+            return;
+        }
+        NodeType::Comment(_) => return,
+        NodeType::Element(ref e) => &*e.tag_name,
+    };
+
+    match tag_name {
+        "script" | "style" | "head" | "template" => return,
+        "br" => {
+            trim_trailing_spaces(out);
+            out.push('\n');
+            return;
+        }
+        _ => {}
+    }
+
+    let block = is_block_element(tag_name);
+    if block {
+        break_line(out);
+    }
+
+    let preformatted = preformatted || tag_name == "pre";
+    for child in &node.children {
+        collect_inner_text(child, preformatted, out);
+    }
+
+    if block {
+        break_line(out);
+    }
+}
+
+// Ends the current line unless it is already empty.
+fn break_line(out: &mut String) {
+    trim_trailing_spaces(out);
+    if !out.is_empty() && !out.ends_with('\n') {
+        out.push('\n');
+    }
+}
+
+fn trim_trailing_spaces(out: &mut String) {
+    out.truncate(out.trim_end_matches(' ').len());
+}
+
+// Elements the default stylesheet of a browser lays out as blocks, which
+// is what decides line breaks for `inner_text`.
+fn is_block_element(tag_name: &str) -> bool {
+    matches!(
+        tag_name,
+        "html"
+            | "body"
+            | "address"
+            | "article"
+            | "aside"
+            | "blockquote"
+            | "dd"
+            | "div"
+            | "dl"
+            | "dt"
+            | "fieldset"
+            | "figcaption"
+            | "figure"
+            | "footer"
+            | "form"
+            | "h1"
+            | "h2"
+            | "h3"
+            | "h4"
+            | "h5"
+            | "h6"
+            | "header"
+            | "hr"
+            | "li"
+            | "main"
+            | "nav"
+            | "ol"
+            | "p"
+            | "pre"
+            | "section"
+            | "table"
+            | "tr"
+            | "ul"
+    )
+}
+
+fn collect_styles(node: &Node, stylesheet: &mut Stylesheet) {
+    match node.node_type {
+        NodeType::Element(ref e) if &*e.tag_name == "style" => {
+            let css: String = node
+                .children
+                .iter()
+                .filter_map(|child| match child.node_type {
+                    NodeType::Text(ref t) => Some(t.as_str()),
+                    _ => None,
+                })
+                .collect();
+
+            let mut parsed = CssParser::new(&css).parse_stylesheet();
+            stylesheet.rules.append(&mut parsed.rules);
+        }
+        _ => {
+            for child in &node.children {
+                collect_styles(child, stylesheet);
+            }
+        }
+    }
+}
+
+fn collect_matches<'a>(
+    node: &'a Node,
+    position: (usize, usize),
+    selectors: &[Selector],
+    first_only: bool,
+    ancestors: &mut Vec<ElementContext<'a>>,
+    found: &mut Vec<&'a Node>,
+) -> bool {
+    // Returns true when `first_only` is set and a match has been found.
+    if let NodeType::Element(ref e) = node.node_type {
+        let el = ElementContext {
+            data: e,
+            position: Some(position),
+        };
+
+        if selectors
+            .iter()
+            .any(|selector| is_selector_matches(el, ancestors, selector))
+        {
+            found.push(node);
+            if first_only {
+                return true;
+            }
+        }
+
+        ancestors.push(el);
+        let count = element_count(node);
+        for (i, child) in element_children(node).enumerate() {
+            if collect_matches(
+                child,
+                (i + 1, count),
+                selectors,
+                first_only,
+                ancestors,
+                found,
+            ) {
+                return true;
+            }
+        }
+        ancestors.pop();
+    }
+    false
+}
+
+fn write_html(node: &Node, options: &HtmlOptions, depth: usize, raw_text: bool, out: &mut String) {
+    if options.pretty {
+        if !out.is_empty() {
+            out.push('\n');
+        }
+        out.push_str(&" ".repeat(depth * options.indent_width));
+    }
+
+    match node.node_type {
+        NodeType::Text(ref t) if raw_text => out.push_str(t),
+        NodeType::Text(ref t) => out.push_str(&escape_html(t, false)),
+        NodeType::Comment(ref c) => {
+            out.push_str("<!--");
+            out.push_str(c);
+            out.push_str("-->");
+        }
+        NodeType::Element(ref e) => {
+            out.push('<');
+            out.push_str(&e.tag_name);
+
+            for (name, value) in e.sorted_attributes() {
+                out.push(' ');
+                out.push_str(name);
+                if !value.is_empty() {
+                    out.push_str("=\"");
+                    out.push_str(&escape_html(&decode_entities(value), true));
+                    out.push('"');
+                }
+            }
+
+            if is_void_element(&e.tag_name) {
+                out.push_str(if options.self_close_void { "/>" } else { ">" });
+                for child in &node.children {
+                    write_html(child, options, depth, false, out);
+                }
+                return;
+            }
+            out.push('>');
+
+            let raw_text = matches!(&*e.tag_name, "script" | "style" | "textarea");
+            for child in &node.children {
+                write_html(child, options, depth + 1, raw_text, out);
+            }
+
+            if options.pretty && !node.children.is_empty() {
+                out.push('\n');
+                out.push_str(&" ".repeat(depth * options.indent_width));
+            }
+            out.push_str("</");
+            out.push_str(&e.tag_name);
+            out.push('>');
+        }
+    }
+}
+
+fn escape_html(text: &str, in_attribute: bool) -> String {
+    let mut escaped = String::with_capacity(text.len());
+
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' if in_attribute => escaped.push_str("&quot;"),
+            _ => escaped.push(c),
+        }
+    }
+
+    escaped
+}
+
+// Replaces character references with the characters they stand for: the
+// common named ones plus decimal and hex numeric references. Anything else,
+// including a reference missing its `;`, is left as written.
+pub fn decode_entities(text: &str) -> Cow<'_, str> {
+    if !text.contains('&') {
+        return Cow::Borrowed(text);
+    }
+
+    let mut decoded = String::with_capacity(text.len());
+    let mut rest = text;
+
+    while let Some(amp) = rest.find('&') {
+        decoded.push_str(&rest[..amp]);
+        rest = &rest[amp..];
+
+        let reference = rest[1..]
+            .find(';')
+            .and_then(|semi| Some((semi, decode_reference(&rest[1..semi + 1])?)));
+        match reference {
+            Some((semi, c)) => {
+                decoded.push(c);
+                rest = &rest[semi + 2..];
+            }
+            None => {
+                decoded.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+    decoded.push_str(rest);
+
+    Cow::Owned(decoded)
+}
+
+// https://www.rfc-editor.org/rfc/rfc3986#section-5.2
+// Resolves `href` against the absolute URL `base`: root-relative (`/a.png`),
+// path-relative (`../b.css`), protocol-relative (`//cdn/x.js`) and query or
+// fragment-only references all work, and an `href` with its own scheme is
+// returned as is. `None` when `base` has no `scheme://` prefix.
+pub fn resolve_against(href: &str, base: &str) -> Option<String> {
+    let href = href.trim();
+    let (scheme, rest) = split_scheme(base)?;
+    let rest = rest.strip_prefix("//")?;
+
+    if split_scheme(href).is_some() {
+        return Some(href.to_string());
+    }
+    if href.starts_with("//") {
+        return Some(format!("{}:{}", scheme, href));
+    }
+
+    let authority_len = rest.find(['/', '?', '#']).unwrap_or(rest.len());
+    let (authority, base_rest) = rest.split_at(authority_len);
+    let base_path = &base_rest[..base_rest.find(['?', '#']).unwrap_or(base_rest.len())];
+    let base_query = &base_rest[..base_rest.find('#').unwrap_or(base_rest.len())];
+
+    let (path, suffix) = href.split_at(href.find(['?', '#']).unwrap_or(href.len()));
+    let resolved = if path.is_empty() {
+        if suffix.starts_with('?') {
+            format!("{}{}", base_path, suffix)
+        } else {
+            format!("{}{}", base_query, suffix)
+        }
+    } else if path.starts_with('/') {
+        format!("{}{}", remove_dot_segments(path), suffix)
+    } else {
+        let dir = &base_path[..base_path.rfind('/').map_or(0, |slash| slash + 1)];
+        format!(
+            "{}{}",
+            remove_dot_segments(&format!("/{}{}", dir.trim_start_matches('/'), path)),
+            suffix
+        )
+    };
+
+    Some(format!("{}://{}{}", scheme, authority, resolved))
+}
+
+// `http` and the rest of a URL starting `http:`. A scheme is a letter
+// followed by letters, digits, `+`, `-` or `.`.
+fn split_scheme(url: &str) -> Option<(&str, &str)> {
+    let (scheme, rest) = url.split_once(':')?;
+    let mut chars = scheme.chars();
+    let valid = chars.next().is_some_and(|c| c.is_ascii_alphabetic())
+        && chars.all(|c| c.is_ascii_alphanumeric() || matches!(c, '+' | '-' | '.'));
+
+    valid.then_some((scheme, rest))
+}
+
+// Applies `.` and `..` segments of an absolute path; `..` stops at the root.
+fn remove_dot_segments(path: &str) -> String {
+    let mut segments: Vec<&str> = Vec::new();
+    let mut parts = path.split('/').skip(1).peekable();
+
+    while let Some(part) = parts.next() {
+        match part {
+            "." | ".." => {
+                if part == ".." {
+                    segments.pop();
+                }
+                // A trailing `.` or `..` still names a directory.
+                if parts.peek().is_none() {
+                    segments.push("");
+                }
+            }
+            _ => segments.push(part),
+        }
+    }
+
+    format!("/{}", segments.join("/"))
+}
+
+// The character named by a reference body, the part between `&` and `;`.
+fn decode_reference(body: &str) -> Option<char> {
+    if let Some(num) = body.strip_prefix('#') {
+        let code = match num.strip_prefix(['x', 'X']) {
+            Some(hex) => u32::from_str_radix(hex, 16).ok()?,
+            None => num.parse().ok()?,
+        };
+        return char::from_u32(code);
+    }
+
+    match body {
+        "amp" => Some('&'),
+        "lt" => Some('<'),
+        "gt" => Some('>'),
+        "quot" => Some('"'),
+        "apos" => Some('\''),
+        "nbsp" => Some('\u{a0}'),
+        _ => None,
+    }
+}
+
+impl PartialEq for Node {
+    fn eq(&self, other: &Node) -> bool {
+        self.node_type == other.node_type && self.children == other.children
+    }
+}
+
+impl Eq for Node {}
+
 impl fmt::Debug for Node {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(f, "{:?}", self.node_type)
@@ -70,7 +1045,7 @@ impl fmt::Debug for ElementData {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         let mut attr_str = String::new();
 
-        for (attr, val) in self.attributes.iter() {
+        for (attr, val) in self.sorted_attributes() {
             attr_str.push_str(&format!(" {}=\"{}\" ", attr, val));
         }
 
@@ -78,6 +1053,8 @@ impl fmt::Debug for ElementData {
     }
 }
 
+// Debugging aid for dumping a tree while working on the parser.
+#[allow(dead_code)]
 fn pretty_print(n: &Node, indent_size: usize) {
     let indent = (0..indent_size).map(|_| " ").collect::<String>();
 
@@ -88,11 +1065,581 @@ fn pretty_print(n: &Node, indent_size: usize) {
     }
 
     for child in n.children.iter() {
-        pretty_print(&child, indent_size + 2);
+        pretty_print(child, indent_size + 2);
     }
 
-    match n.node_type {
-        NodeType::Element(ref e) => println!("{}</{}>", indent, e.tag_name),
-        _ => {}
+    if let NodeType::Element(ref e) = n.node_type {
+        println!("{}</{}>", indent, e.tag_name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parse_options::ParseOptions;
+
+    fn parse(html: &str) -> Vec<Node> {
+        HtmlParser::new(html).parse_with_diagnostics().0
+    }
+
+    fn ids<'a>(nodes: &[&'a Node]) -> Vec<&'a str> {
+        nodes
+            .iter()
+            .filter_map(|node| node.as_element()?.get_id())
+            .map(String::as_str)
+            .collect()
+    }
+
+    fn selector(text: &str) -> Selector {
+        CssParser::new(text)
+            .parse_selector_list()
+            .unwrap()
+            .remove(0)
+    }
+
+    #[test]
+    fn query_matches_parsed_selectors_with_ancestors() {
+        let root = parse(
+            "<main id=m><div id=a class=active><p id=b></p></div><div id=c></div>\
+             <section><div id=d class='x active'><span><p id=e></p></span></div></section></main>",
+        )
+        .remove(0);
+
+        assert_eq!(ids(&root.query(&selector("div.active"))), ["a", "d"]);
+        assert_eq!(ids(&root.query(&selector("section p"))), ["e"]);
+        assert_eq!(ids(&root.query(&selector("main p"))), ["b", "e"]);
+        assert_eq!(ids(&root.query(&selector("main"))), Vec::<&str>::new());
+    }
+
+    #[test]
+    fn query_str_parses_the_selector() {
+        let root = parse(
+            "<div><ul><li id=a class=item></li><li id=b></li><li><ol><li id=c class=item></li></ol></li></ul>\
+             <ul><li id=d class='item new'></li></ul></div>",
+        )
+        .remove(0);
+
+        assert_eq!(ids(&root.query_str("ul > li.item").unwrap()), ["a", "d"]);
+        assert_eq!(ids(&root.query_str("ol li, #b").unwrap()), ["b", "c"]);
+        assert!(root.query_str("ul > > li").is_err());
+        assert!(root.query_str("").is_err());
+    }
+
+    #[test]
+    fn to_html_with_pretty_and_void_styles() {
+        let root = parse("<div id=a><p>x</p><br><img src=a.png></div>").remove(0);
+
+        assert_eq!(
+            root.to_html(),
+            "<div id=\"a\"><p>x</p><br><img src=\"a.png\"></div>"
+        );
+        assert_eq!(root.to_html_with(&HtmlOptions::default()), root.to_html());
+        assert_eq!(
+            root.to_html_with(&HtmlOptions {
+                self_close_void: true,
+                ..HtmlOptions::default()
+            }),
+            "<div id=\"a\"><p>x</p><br/><img src=\"a.png\"/></div>"
+        );
+        assert_eq!(
+            root.to_html_with(&HtmlOptions {
+                pretty: true,
+                indent_width: 4,
+                ..HtmlOptions::default()
+            }),
+            "<div id=\"a\">\n    <p>\n        x\n    </p>\n    <br>\n    <img src=\"a.png\">\n</div>"
+        );
+    }
+
+    #[test]
+    fn extract_stylesheets_merges_style_elements_in_order() {
+        let root = parse(
+            "<html><head><style>p > a { color: red } h1 { width: 1px }</style></head>\
+             <body><p>x</p><div><style>.note { height: 2px }</style></div></body></html>",
+        )
+        .remove(0);
+
+        let stylesheet = root.extract_stylesheets();
+        let selectors: Vec<String> = stylesheet
+            .rules
+            .iter()
+            .map(|rule| rule.selectors[0].to_string())
+            .collect();
+        assert_eq!(selectors, ["p > a", "h1", ".note"]);
+
+        assert!(parse("<p>a { color: red }</p>")[0]
+            .extract_stylesheets()
+            .rules
+            .is_empty());
+    }
+
+    #[test]
+    fn set_text_is_escaped_on_output() {
+        let mut root = parse("<p>old</p>").remove(0);
+
+        assert!(root.children[0].set_text("a < b && <script>x</script>"));
+        assert_eq!(
+            root.children[0].as_text(),
+            Some("a < b && <script>x</script>")
+        );
+        assert_eq!(
+            root.to_html(),
+            "<p>a &lt; b &amp;&amp; &lt;script&gt;x&lt;/script&gt;</p>"
+        );
+
+        assert!(!root.set_text("ignored"));
+        assert_eq!(root.children.len(), 1);
+        assert_eq!(root.tag_name(), Some("p"));
+    }
+
+    #[test]
+    fn diff_reports_changed_text_and_added_attribute() {
+        let old = parse("<div><p>hello</p><a href=x>link</a></div>").remove(0);
+        let new = parse("<div><p>goodbye</p><a href=x title=t>link</a></div>").remove(0);
+
+        assert_eq!(
+            diff(&old, &new),
+            [
+                Change::TextChanged {
+                    path: vec![0, 0],
+                    old: String::from("hello"),
+                    new: String::from("goodbye"),
+                },
+                Change::AttributeChanged {
+                    path: vec![1],
+                    name: String::from("title"),
+                    old: None,
+                    new: Some(String::from("t")),
+                },
+            ]
+        );
+        assert!(diff(&old, &old).is_empty());
+
+        let longer = parse("<div><p>hello</p><a href=x>link</a><br></div>").remove(0);
+        assert_eq!(diff(&old, &longer), [Change::NodeAdded(vec![2])]);
+        assert_eq!(diff(&longer, &old), [Change::NodeRemoved(vec![2])]);
+
+        let retagged = parse("<div><span>hello</span><a href=x>link</a></div>").remove(0);
+        assert_eq!(
+            diff(&old, &retagged),
+            [Change::NodeRemoved(vec![0]), Change::NodeAdded(vec![0])]
+        );
+    }
+
+    #[test]
+    fn find_text_walks_the_whole_tree() {
+        let options = ParseOptions::default().keep_comments(true);
+        let root = HtmlParser::parse_with_options(
+            "<div>rust intro<!-- rust comment --><ul><li>learn <b>rust</b> now</li><li>go</li></ul><p>Rust</p></div>",
+            &options,
+        )
+        .unwrap()
+        .remove(0);
+
+        assert_eq!(
+            root.find_text(|text| text.contains("rust")),
+            ["rust intro", "rust"]
+        );
+        assert_eq!(
+            root.find_text(|text| text.to_lowercase().contains("rust"))
+                .len(),
+            3
+        );
+        assert!(root.find_text(|text| text.contains("comment")).is_empty());
+    }
+
+    #[test]
+    fn node_type_accessors() {
+        let options = ParseOptions::default().keep_comments(true);
+        let root = HtmlParser::parse_with_options("<p id=x>text<!--note--></p>", &options)
+            .unwrap()
+            .remove(0);
+        let (text, comment) = (&root.children[0], &root.children[1]);
+
+        assert!(root.is_element());
+        assert_eq!(root.tag_name(), Some("p"));
+        assert_eq!(
+            root.as_element()
+                .and_then(ElementData::get_id)
+                .map(String::as_str),
+            Some("x")
+        );
+        assert_eq!(root.as_text(), None);
+
+        assert!(!text.is_element());
+        assert_eq!(text.tag_name(), None);
+        assert!(text.as_element().is_none());
+        assert_eq!(text.as_text(), Some("text"));
+
+        assert!(matches!(comment.node_type, NodeType::Comment(ref c) if c == "note"));
+        assert!(!comment.is_element());
+        assert_eq!(comment.tag_name(), None);
+        assert!(comment.as_element().is_none());
+        assert_eq!(comment.as_text(), None);
+    }
+
+    #[test]
+    fn head_and_body_of_documents_and_fragments() {
+        let document =
+            parse("<html><head><title>t</title></head><body><p>x</p></body></html>").remove(0);
+        assert_eq!(document.head().and_then(Node::tag_name), Some("head"));
+        assert_eq!(document.head().unwrap().text_content(), "t");
+        assert_eq!(document.body().unwrap().text_content(), "x");
+
+        let fragment = parse("<div><p>x</p></div>").remove(0);
+        assert!(fragment.head().is_none());
+        assert!(fragment.body().is_none());
+
+        let options = ParseOptions::default().insert_skeleton(true);
+        let wrapped = HtmlParser::parse_with_options("<title>t</title><p>x</p>", &options)
+            .unwrap()
+            .remove(0);
+        assert_eq!(wrapped.head().unwrap().text_content(), "t");
+        assert_eq!(wrapped.body().unwrap().text_content(), "x");
+    }
+
+    #[test]
+    fn reparse_subtree_replaces_only_the_children() {
+        let mut root =
+            parse("<main><h1>t</h1><div id=d class=x><p>old</p></div><footer>f</footer></main>")
+                .remove(0);
+
+        root.children[1]
+            .reparse_subtree("<ul><li>a</li><li>b</li></ul>text")
+            .unwrap();
+        assert_eq!(
+            root.to_html(),
+            "<main><h1>t</h1><div class=\"x\" id=\"d\"><ul><li>a</li><li>b</li></ul>text</div><footer>f</footer></main>"
+        );
+
+        let before = root.to_html();
+        assert!(matches!(
+            root.children[1].reparse_subtree("<p>unclosed"),
+            Err(ParseError::Malformed(_))
+        ));
+        assert!(matches!(
+            root.children[1].reparse_subtree("</b>"),
+            Err(ParseError::Malformed(_))
+        ));
+        assert!(matches!(
+            root.children[0].children[0].reparse_subtree("<b>x</b>"),
+            Err(ParseError::NotAnElement)
+        ));
+        assert_eq!(root.to_html(), before);
+    }
+
+    #[test]
+    fn contains_finds_descendants_by_identity() {
+        let root = parse("<div><section><p>x</p></section><p>x</p></div>").remove(0);
+        let grandchild = &root.children[0].children[0];
+        let copy = parse("<p>x</p>").remove(0);
+
+        assert!(root.contains(grandchild));
+        assert!(root.contains(&grandchild.children[0]));
+        assert!(root.children[0].contains(grandchild));
+        assert!(!root.children[1].contains(grandchild));
+        assert!(!grandchild.contains(&root));
+        assert!(!root.contains(&root));
+        assert_eq!(*grandchild, copy);
+        assert!(!root.contains(&copy));
+    }
+
+    #[test]
+    fn attributes_decode_on_access_and_stay_raw() {
+        let root = parse("<a href=\"a&amp;b&lt;&#x41;\" title=plain>x</a>").remove(0);
+        let a = root.as_element().unwrap();
+
+        assert_eq!(a.get_attribute("href").as_deref(), Some("a&b<A"));
+        assert_eq!(a.get_attribute_raw("href"), Some("a&amp;b&lt;&#x41;"));
+        assert!(matches!(
+            a.get_attribute("title"),
+            Some(Cow::Borrowed("plain"))
+        ));
+        assert_eq!(a.get_attribute("missing"), None);
+        assert_eq!(a.get_attribute_raw("missing"), None);
+        assert_eq!(
+            root.to_html(),
+            "<a href=\"a&amp;b&lt;A\" title=\"plain\">x</a>"
+        );
+    }
+
+    #[derive(Default)]
+    struct TagCollector {
+        tags: Vec<String>,
+        texts: usize,
+    }
+
+    impl Visitor for TagCollector {
+        fn visit_element(&mut self, element: &ElementData) {
+            self.tags.push(element.tag_name.to_string());
+        }
+
+        fn visit_text(&mut self, _text: &str) {
+            self.texts += 1;
+        }
+    }
+
+    #[test]
+    fn visitor_walks_depth_first() {
+        let options = ParseOptions::default().keep_comments(true);
+        let root = HtmlParser::parse_with_options(
+            "<div><ul><li>a</li><li><b>b</b></li></ul><!-- c --><p>d</p></div>",
+            &options,
+        )
+        .unwrap()
+        .remove(0);
+
+        let mut collector = TagCollector::default();
+        root.accept(&mut collector);
+        assert_eq!(collector.tags, ["div", "ul", "li", "li", "b", "p"]);
+        assert_eq!(collector.texts, 3);
+    }
+
+    #[test]
+    fn inner_text_breaks_at_blocks_and_skips_scripts() {
+        let root = parse(
+            "<div><h1>Title</h1><p>One  <b>bold</b>\n text</p><script>var x = 1;</script>\
+             <style>p { }</style>line<br>next<div><p>nested</p></div>tail</div>",
+        )
+        .remove(0);
+
+        assert_eq!(
+            root.inner_text(),
+            "Title\nOne bold text\nline\nnext\nnested\ntail"
+        );
+        assert_eq!(
+            root.text_content(),
+            "TitleOne bold textvar x = 1;p { }linenextnestedtail"
+        );
+    }
+
+    #[test]
+    fn sorted_attributes_ignore_insertion_order() {
+        for html in ["<a zeta=1 alpha=2 mid=3>", "<a mid=3 zeta=1 alpha=2>"] {
+            let root = parse(html).remove(0);
+            assert_eq!(
+                root.as_element().unwrap().sorted_attributes(),
+                [("alpha", "2"), ("mid", "3"), ("zeta", "1")]
+            );
+        }
+    }
+
+    #[test]
+    fn validate_nesting_reports_forbidden_descendants() {
+        let root =
+            parse("<section><p><div></div></p><a><span><a></a></span></a></section>").remove(0);
+        assert_eq!(
+            root.validate_nesting(),
+            [
+                NestingViolation::ForbiddenDescendant {
+                    path: vec![0, 0],
+                    tag_name: "div".to_string(),
+                    ancestor: "p".to_string(),
+                },
+                NestingViolation::ForbiddenDescendant {
+                    path: vec![1, 0, 0],
+                    tag_name: "a".to_string(),
+                    ancestor: "a".to_string(),
+                },
+            ]
+        );
+
+        let clean = parse("<div><p><a>x</a></p><ul><li>y</li></ul></div>").remove(0);
+        assert!(clean.validate_nesting().is_empty());
+    }
+
+    #[test]
+    fn document_parse_exposes_doctype_and_charset() {
+        let document = Document::parse(
+            "<!DOCTYPE html><html><head><meta charset=UTF-8></head><body></body></html>",
+        );
+        assert_eq!(document.doctype.as_deref(), Some("html"));
+        assert_eq!(document.charset.as_deref(), Some("utf-8"));
+        assert!(document.diagnostics.is_empty());
+        assert_eq!(document.root().and_then(Node::tag_name), Some("html"));
+
+        let fragment = Document::parse("<p>no doctype</p>");
+        assert_eq!(fragment.doctype, None);
+        assert_eq!(fragment.root().and_then(Node::tag_name), Some("p"));
+    }
+
+    #[test]
+    fn empty_class_attribute_has_no_classes() {
+        let root = parse("<div><p class=\"\"></p><p class=\"  a\tb  \"></p></div>").remove(0);
+        let classes: Vec<HashSet<&str>> = root
+            .children
+            .iter()
+            .map(|child| child.as_element().unwrap().get_classes())
+            .collect();
+        assert!(classes[0].is_empty());
+        assert_eq!(classes[1], HashSet::from(["a", "b"]));
+
+        let matched = root.query_str("p.a, p.b").unwrap();
+        assert_eq!(matched.len(), 1);
+        assert!(std::ptr::eq(matched[0], &root.children[1]));
+    }
+
+    #[test]
+    fn query_first_returns_the_first_match_in_document_order() {
+        let root =
+            parse("<div><p><span id=a class=item></span></p><span id=b class=item></span></div>")
+                .remove(0);
+        let id = |node: Option<&Node>| node.and_then(|n| n.as_element()?.get_id().cloned());
+
+        assert_eq!(
+            id(root.query_first(&selector(".item"))),
+            Some("a".to_string())
+        );
+        assert_eq!(
+            id(root.query_first_str("div > .item, #missing").unwrap()),
+            Some("b".to_string())
+        );
+        assert!(root.query_first(&selector(".missing")).is_none());
+        assert!(root.query_first_str("p > p").unwrap().is_none());
+        assert!(root.query_first_str("> p").is_err());
+    }
+
+    #[test]
+    fn leading_comment_stays_beside_the_root() {
+        let html = "<!-- license -->\n<!DOCTYPE html><html><body><p>x</p></body></html>";
+
+        let document = Document::parse(html);
+        assert_eq!(document.nodes.len(), 2);
+        assert_eq!(
+            document.nodes[0].node_type,
+            NodeType::Comment(String::from(" license "))
+        );
+        assert_eq!(document.doctype.as_deref(), Some("html"));
+        let root = document.root().unwrap();
+        assert_eq!(root.tag_name(), Some("html"));
+        assert!(std::ptr::eq(root, &document.nodes[1]));
+
+        let options = ParseOptions::default().insert_skeleton(true);
+        let nodes = HtmlParser::parse_with_options(html, &options).unwrap();
+        let kinds: Vec<Option<&str>> = nodes.iter().map(Node::tag_name).collect();
+        assert_eq!(kinds, [None, Some("html")]);
+        assert_eq!(nodes[1].children.len(), 2);
+    }
+
+    #[derive(Default)]
+    struct HtmlWriter(String);
+
+    impl SaxHandler for HtmlWriter {
+        fn start_element(&mut self, element: &ElementData) {
+            self.0.push('<');
+            self.0.push_str(&element.tag_name);
+            for (name, value) in element.sorted_attributes() {
+                self.0
+                    .push_str(&format!(" {}=\"{}\"", name, escape_html(value, true)));
+            }
+            self.0.push('>');
+        }
+
+        fn end_element(&mut self, element: &ElementData) {
+            if !is_void_element(&element.tag_name) {
+                self.0.push_str(&format!("</{}>", element.tag_name));
+            }
+        }
+
+        fn characters(&mut self, text: &str) {
+            self.0.push_str(&escape_html(text, false));
+        }
+
+        fn comment(&mut self, comment: &str) {
+            self.0.push_str(&format!("<!--{}-->", comment));
+        }
+    }
+
+    #[test]
+    fn emit_events_rebuild_the_html() {
+        let options = ParseOptions::default().keep_comments(true);
+        let root = HtmlParser::parse_with_options(
+            "<div id=a class=\"x y\"><!-- c --><p>one &lt; two<br>three</p><img src=i.png></div>",
+            &options,
+        )
+        .unwrap()
+        .remove(0);
+
+        let mut writer = HtmlWriter::default();
+        root.emit_events(&mut writer);
+        assert_eq!(writer.0, root.to_html());
+    }
+
+    #[test]
+    fn relative_urls_resolve_against_the_base() {
+        let base = "https://example.com/docs/guide/page.html?q=1#top";
+        let cases = [
+            ("/a.png", "https://example.com/a.png"),
+            ("../b.css", "https://example.com/docs/b.css"),
+            ("./c/./d/../e.js", "https://example.com/docs/guide/c/e.js"),
+            ("//cdn.example.net/x.js", "https://cdn.example.net/x.js"),
+            ("?page=2", "https://example.com/docs/guide/page.html?page=2"),
+            (
+                "#intro",
+                "https://example.com/docs/guide/page.html?q=1#intro",
+            ),
+            ("../../../../up", "https://example.com/up"),
+            ("mailto:a@b.c", "mailto:a@b.c"),
+        ];
+        for (href, expected) in cases {
+            assert_eq!(
+                resolve_against(href, base).as_deref(),
+                Some(expected),
+                "{}",
+                href
+            );
+        }
+        assert_eq!(resolve_against("/a.png", "example.com/page"), None);
+
+        let root = parse("<a href=\"../img/x&amp;y.png\"></a>").remove(0);
+        let a = root.as_element().unwrap();
+        assert_eq!(a.get_attribute_raw("href"), Some("../img/x&amp;y.png"));
+        assert_eq!(
+            a.get_url("href", base).as_deref(),
+            Some("https://example.com/docs/img/x&y.png")
+        );
+        assert_eq!(a.get_url("src", base), None);
+    }
+
+    #[test]
+    fn dataset_strips_the_data_prefix() {
+        let root =
+            parse("<button data-id=\"5\" data-role=btn data-user-name=a&amp;b datax=1 id=b>")
+                .remove(0);
+        let dataset = root.as_element().unwrap().dataset();
+        assert_eq!(
+            dataset,
+            HashMap::from([("id", "5"), ("role", "btn"), ("user-name", "a&amp;b")])
+        );
+
+        assert!(parse("<p id=x>")
+            .remove(0)
+            .as_element()
+            .unwrap()
+            .dataset()
+            .is_empty());
+    }
+
+    #[test]
+    fn replace_matching_swaps_b_for_strong() {
+        let mut root = parse("<div><b>a<i>i</i></b><p class=x>c<b>d</b></p></div>").remove(0);
+
+        let replaced = root.replace_matching(
+            |node| node.tag_name() == Some("b"),
+            |old| {
+                let strong = ElementData::new("strong", AttrMap::new());
+                Node::new(NodeType::Element(strong), old.children)
+            },
+        );
+
+        assert_eq!(replaced, 2);
+        assert_eq!(
+            root.to_html(),
+            "<div><strong>a<i>i</i></strong><p class=\"x\">c<strong>d</strong></p></div>"
+        );
+        assert_eq!(
+            root.replace_matching(|node| node.tag_name() == Some("b"), |old| old),
+            0
+        );
     }
 }
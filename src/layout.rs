@@ -0,0 +1,507 @@
+use crate::css::Value;
+use crate::dom::{Node, NodeType};
+use crate::styles::{Display, StyledNode};
+
+// Text isn't shaped yet: every character advances by the same amount.
+pub const CHAR_WIDTH: f32 = 8.0;
+// Used when an inline element has no `line-height` of its own.
+pub const LINE_HEIGHT: f32 = 16.0;
+// Used when an element has no `font-size` of its own.
+pub const FONT_SIZE: f32 = 16.0;
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Dimensions {
+    // Position of the content area relative to the document origin.
+    pub content: Rect,
+    pub padding: EdgeSizes,
+    pub border: EdgeSizes,
+    pub margin: EdgeSizes,
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Rect {
+    pub x: f32,
+    pub y: f32,
+    pub width: f32,
+    pub height: f32,
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct EdgeSizes {
+    pub left: f32,
+    pub right: f32,
+    pub top: f32,
+    pub bottom: f32,
+}
+
+// The edges around a box's content, without its position or size. See
+// `StyledNode::box_edges`.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct BoxEdges {
+    pub margin: EdgeSizes,
+    pub border: EdgeSizes,
+    pub padding: EdgeSizes,
+}
+
+pub struct LayoutBox<'a> {
+    pub dimensions: Dimensions,
+    pub box_type: BoxType<'a>,
+    pub children: Vec<LayoutBox<'a>>,
+}
+
+pub enum BoxType<'a> {
+    BlockNode(&'a StyledNode<'a>),
+    // Laid out as one unbreakable box sized by its text; its descendants get
+    // no boxes of their own.
+    InlineNode(&'a StyledNode<'a>),
+    // Flows like an inline box but lays out its children like a block.
+    InlineBlockNode(&'a StyledNode<'a>),
+    // Wraps consecutive inline children of a block into line boxes.
+    AnonymousBlock,
+}
+
+// Builds and lays out the box tree for `node`. The height of
+// `containing_block` is ignored; boxes grow to fit their content. `None` if
+// the root has `display: none`.
+pub fn layout_tree<'a>(
+    node: &'a StyledNode<'a>,
+    mut containing_block: Dimensions,
+) -> Option<LayoutBox<'a>> {
+    containing_block.content.height = 0.0;
+
+    let mut root_box = build_layout_tree(node)?;
+    root_box.layout(containing_block);
+    Some(root_box)
+}
+
+fn build_layout_tree<'a>(style_node: &'a StyledNode<'a>) -> Option<LayoutBox<'a>> {
+    let mut root = LayoutBox::new(match style_node.get_display() {
+        Display::Block => BoxType::BlockNode(style_node),
+        Display::Inline => BoxType::InlineNode(style_node),
+        Display::InlineBlock => BoxType::InlineBlockNode(style_node),
+        Display::None => return None,
+    });
+
+    if let BoxType::InlineNode(_) = root.box_type {
+        return Some(root);
+    }
+
+    for child in &style_node.children {
+        match child.get_display() {
+            Display::Block => root.children.extend(build_layout_tree(child)),
+            Display::Inline | Display::InlineBlock => root
+                .get_inline_container()
+                .children
+                .extend(build_layout_tree(child)),
+            Display::None => {}
+        }
+    }
+
+    Some(root)
+}
+
+impl<'a> LayoutBox<'a> {
+    fn new(box_type: BoxType<'a>) -> LayoutBox<'a> {
+        LayoutBox {
+            box_type,
+            dimensions: Dimensions::default(),
+            children: Vec::new(),
+        }
+    }
+
+    fn get_style_node(&self) -> Option<&'a StyledNode<'a>> {
+        match self.box_type {
+            BoxType::BlockNode(node)
+            | BoxType::InlineNode(node)
+            | BoxType::InlineBlockNode(node) => Some(node),
+            BoxType::AnonymousBlock => None,
+        }
+    }
+
+    // Where an inline child goes: the trailing anonymous block, created on
+    // demand so block and inline children never share a formatting context.
+    fn get_inline_container(&mut self) -> &mut LayoutBox<'a> {
+        if !matches!(
+            self.children.last(),
+            Some(LayoutBox {
+                box_type: BoxType::AnonymousBlock,
+                ..
+            })
+        ) {
+            self.children.push(LayoutBox::new(BoxType::AnonymousBlock));
+        }
+
+        self.children.last_mut().unwrap()
+    }
+
+    fn layout(&mut self, containing_block: Dimensions) {
+        match self.box_type {
+            BoxType::BlockNode(_) => self.layout_block(containing_block),
+            BoxType::AnonymousBlock => {
+                let d = &mut self.dimensions;
+                d.content.x = containing_block.content.x;
+                d.content.y = containing_block.content.y + containing_block.content.height;
+                d.content.width = containing_block.content.width;
+                self.layout_lines();
+            }
+            BoxType::InlineNode(_) | BoxType::InlineBlockNode(_) => {
+                let cb = containing_block.content;
+                self.calculate_inline_width(cb.width);
+                self.place_inline(cb.x, cb.y + cb.height);
+                self.layout_inline_contents();
+            }
+        }
+    }
+
+    fn layout_block(&mut self, containing_block: Dimensions) {
+        self.calculate_block_width(containing_block);
+        self.calculate_block_position(containing_block);
+        self.layout_block_children();
+        self.calculate_block_height();
+    }
+
+    // https://www.w3.org/TR/CSS2/visudet.html#blockwidth
+    fn calculate_block_width(&mut self, containing_block: Dimensions) {
+        let style = self.get_style_node().unwrap();
+
+        let mut width = style.num_or("width", 0.0);
+        let width_auto = is_auto(style, "width");
+        let mut margin_left = style.num_or("margin-left", 0.0);
+        let mut margin_right = style.num_or("margin-right", 0.0);
+        let mut margin_left_auto = is_keyword(style, "margin-left", "auto");
+        let mut margin_right_auto = is_keyword(style, "margin-right", "auto");
+
+        let border_left = style.num_or("border-left-width", 0.0);
+        let border_right = style.num_or("border-right-width", 0.0);
+        let padding_left = style.num_or("padding-left", 0.0);
+        let padding_right = style.num_or("padding-right", 0.0);
+
+        let total = margin_left
+            + margin_right
+            + border_left
+            + border_right
+            + padding_left
+            + padding_right
+            + width;
+
+        // Too wide already: `auto` margins can't be negative.
+        if !width_auto && total > containing_block.content.width {
+            margin_left_auto = false;
+            margin_right_auto = false;
+        }
+
+        let underflow = containing_block.content.width - total;
+
+        match (width_auto, margin_left_auto, margin_right_auto) {
+            (false, false, false) => margin_right += underflow,
+            (false, false, true) => margin_right = underflow,
+            (false, true, false) => margin_left = underflow,
+            (false, true, true) => {
+                margin_left = underflow / 2.0;
+                margin_right = underflow / 2.0;
+            }
+            (true, _, _) => {
+                if underflow >= 0.0 {
+                    width = underflow;
+                } else {
+                    width = 0.0;
+                    margin_right += underflow;
+                }
+            }
+        }
+
+        let d = &mut self.dimensions;
+        d.content.width = width;
+        d.padding.left = padding_left;
+        d.padding.right = padding_right;
+        d.border.left = border_left;
+        d.border.right = border_right;
+        d.margin.left = margin_left;
+        d.margin.right = margin_right;
+    }
+
+    fn calculate_block_position(&mut self, containing_block: Dimensions) {
+        let style = self.get_style_node().unwrap();
+        self.set_vertical_edges(style);
+
+        let d = &mut self.dimensions;
+        d.content.x = containing_block.content.x + d.margin.left + d.border.left + d.padding.left;
+        // Below everything already in the containing block.
+        d.content.y = containing_block.content.height
+            + containing_block.content.y
+            + d.margin.top
+            + d.border.top
+            + d.padding.top;
+    }
+
+    fn layout_block_children(&mut self) {
+        let d = &mut self.dimensions;
+        for child in &mut self.children {
+            child.layout(*d);
+            d.content.height += child.dimensions.margin_box().height;
+        }
+    }
+
+    fn calculate_block_height(&mut self) {
+        let style = self.get_style_node().unwrap();
+        if let Some(Value::Length(h, _)) = style.value("height") {
+            self.dimensions.content.height = *h;
+        }
+    }
+
+    // Flows the inline children left to right, starting a new line whenever
+    // the next box would overflow the content width. A box wider than the
+    // whole line gets a line to itself. Each line is as tall as its tallest
+    // box.
+    fn layout_lines(&mut self) {
+        let content = self.dimensions.content;
+        let mut cursor_x = 0.0;
+        let mut line_y = 0.0;
+        let mut line_height: f32 = 0.0;
+
+        for child in &mut self.children {
+            child.calculate_inline_width(content.width);
+
+            let width = child.dimensions.margin_box().width;
+            if cursor_x > 0.0 && cursor_x + width > content.width {
+                line_y += line_height;
+                cursor_x = 0.0;
+                line_height = 0.0;
+            }
+
+            child.place_inline(content.x + cursor_x, content.y + line_y);
+            child.layout_inline_contents();
+
+            cursor_x += width;
+            line_height = line_height.max(child.dimensions.margin_box().height);
+        }
+
+        self.dimensions.content.height = line_y + line_height;
+    }
+
+    // Horizontal edges and content width of an inline or inline-block box.
+    // Inline boxes are as wide as their text; inline-blocks use `width`, or
+    // their text width when it's `auto`, never more than `max_width`.
+    fn calculate_inline_width(&mut self, max_width: f32) {
+        let style = self.get_style_node().unwrap();
+
+        let d = &mut self.dimensions;
+        d.margin.left = style.num_or("margin-left", 0.0);
+        d.margin.right = style.num_or("margin-right", 0.0);
+        d.border.left = style.num_or("border-left-width", 0.0);
+        d.border.right = style.num_or("border-right-width", 0.0);
+        d.padding.left = style.num_or("padding-left", 0.0);
+        d.padding.right = style.num_or("padding-right", 0.0);
+
+        let text_width = text_len(style.node()) as f32 * CHAR_WIDTH;
+        d.content.width = match self.box_type {
+            BoxType::InlineBlockNode(_) if !is_auto(style, "width") => style.num_or("width", 0.0),
+            _ => {
+                let edges = d.margin.left
+                    + d.margin.right
+                    + d.border.left
+                    + d.border.right
+                    + d.padding.left
+                    + d.padding.right;
+                text_width.min((max_width - edges).max(0.0))
+            }
+        };
+    }
+
+    // Puts the margin box's top-left corner at (`x`, `y`).
+    fn place_inline(&mut self, x: f32, y: f32) {
+        let style = self.get_style_node().unwrap();
+        self.set_vertical_edges(style);
+
+        let d = &mut self.dimensions;
+        d.content.x = x + d.margin.left + d.border.left + d.padding.left;
+        d.content.y = y + d.margin.top + d.border.top + d.padding.top;
+    }
+
+    // Content height of a placed inline box. An inline-block lays out its
+    // children as a block would, unless it has an explicit `height`.
+    fn layout_inline_contents(&mut self) {
+        let style = self.get_style_node().unwrap();
+
+        match self.box_type {
+            BoxType::InlineBlockNode(_) => {
+                self.dimensions.content.height = 0.0;
+                self.layout_block_children();
+                self.calculate_block_height();
+            }
+            _ => self.dimensions.content.height = line_height(style),
+        }
+    }
+
+    fn set_vertical_edges(&mut self, style: &StyledNode) {
+        let d = &mut self.dimensions;
+        d.margin.top = style.num_or("margin-top", 0.0);
+        d.margin.bottom = style.num_or("margin-bottom", 0.0);
+        d.border.top = style.num_or("border-top-width", 0.0);
+        d.border.bottom = style.num_or("border-bottom-width", 0.0);
+        d.padding.top = style.num_or("padding-top", 0.0);
+        d.padding.bottom = style.num_or("padding-bottom", 0.0);
+    }
+}
+
+impl Rect {
+    pub fn expanded_by(self, edge: EdgeSizes) -> Rect {
+        Rect {
+            x: self.x - edge.left,
+            y: self.y - edge.top,
+            width: self.width + edge.left + edge.right,
+            height: self.height + edge.top + edge.bottom,
+        }
+    }
+}
+
+impl Dimensions {
+    pub fn padding_box(self) -> Rect {
+        self.content.expanded_by(self.padding)
+    }
+
+    pub fn border_box(self) -> Rect {
+        self.padding_box().expanded_by(self.border)
+    }
+
+    pub fn margin_box(self) -> Rect {
+        self.border_box().expanded_by(self.margin)
+    }
+}
+
+// A unitless `line-height` multiplies the font size.
+fn line_height(style: &StyledNode) -> f32 {
+    match style.value("line-height") {
+        Some(Value::Number(n)) => n * style.num_or("font-size", FONT_SIZE),
+        _ => style.num_or("line-height", LINE_HEIGHT),
+    }
+}
+
+fn is_auto(style: &StyledNode, name: &str) -> bool {
+    style.value(name).is_none() || is_keyword(style, name, "auto")
+}
+
+fn is_keyword(style: &StyledNode, name: &str, keyword: &str) -> bool {
+    matches!(style.value(name), Some(Value::Keyword(k)) if k == keyword)
+}
+
+// Characters of text under `node`, comments excluded.
+fn text_len(node: &Node) -> usize {
+    let own = match node.node_type {
+        NodeType::Text(ref t) => t.chars().count(),
+        _ => 0,
+    };
+
+    own + node.children.iter().map(text_len).sum::<usize>()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::css_parser::CssParser;
+    use crate::html_parser::HtmlParser;
+
+    fn viewport(width: f32) -> Dimensions {
+        let mut viewport = Dimensions::default();
+        viewport.content.width = width;
+        viewport
+    }
+
+    #[test]
+    fn inline_blocks_wrap_onto_lines_of_their_height() {
+        let nodes =
+            HtmlParser::new("<div><i id=a></i><i id=b></i><i id=c></i><span>abcd</span></div>")
+                .parse_with_diagnostics()
+                .0;
+        let stylesheet = CssParser::new(
+            "div { display: block } i { display: inline-block; width: 100px; height: 20px } \
+             span { display: inline; line-height: 10px }",
+        )
+        .parse_stylesheet();
+        let styled = StyledNode::new(&nodes[0], &stylesheet);
+
+        let root = layout_tree(&styled, viewport(250.0)).unwrap();
+        assert_eq!(root.children.len(), 1);
+        let line_boxes = &root.children[0];
+        assert!(matches!(line_boxes.box_type, BoxType::AnonymousBlock));
+
+        let positions: Vec<(f32, f32)> = line_boxes
+            .children
+            .iter()
+            .map(|child| (child.dimensions.content.x, child.dimensions.content.y))
+            .collect();
+        assert_eq!(
+            positions,
+            [(0.0, 0.0), (100.0, 0.0), (0.0, 20.0), (100.0, 20.0)]
+        );
+        assert_eq!(
+            line_boxes.children[3].dimensions.content.width,
+            4.0 * CHAR_WIDTH
+        );
+        assert_eq!(line_boxes.dimensions.content.height, 40.0);
+        assert_eq!(root.dimensions.content.height, 40.0);
+
+        let wide = layout_tree(&styled, viewport(1000.0)).unwrap();
+        assert!(wide.children[0]
+            .children
+            .iter()
+            .all(|child| child.dimensions.content.y == 0.0));
+        assert_eq!(wide.dimensions.content.height, 20.0);
+    }
+
+    #[test]
+    fn unitless_line_height_multiplies_the_font_size() {
+        let nodes = HtmlParser::new("<div><a>x</a><b>y</b><i>z</i></div>")
+            .parse_with_diagnostics()
+            .0;
+        let stylesheet = CssParser::new(
+            "div { display: block } a, b, i { display: inline } \
+             a { line-height: 1.5; font-size: 20px } b { line-height: 1.5 } i { line-height: 12px }",
+        )
+        .parse_stylesheet();
+        let styled = StyledNode::new(&nodes[0], &stylesheet);
+
+        let root = layout_tree(&styled, viewport(800.0)).unwrap();
+        let heights: Vec<f32> = root.children[0]
+            .children
+            .iter()
+            .map(|child| child.dimensions.content.height)
+            .collect();
+        assert_eq!(heights, [30.0, 1.5 * FONT_SIZE, 12.0]);
+    }
+
+    #[test]
+    fn hidden_boxes_keep_their_space_but_display_none_goes() {
+        let nodes = HtmlParser::new("<div><p id=a></p><p id=b></p><p id=c></p></div>")
+            .parse_with_diagnostics()
+            .0;
+        let stylesheet = CssParser::new(
+            "div, p { display: block } p { height: 10px } #a { display: none } #b { visibility: hidden }",
+        )
+        .parse_stylesheet();
+        let styled = StyledNode::new(&nodes[0], &stylesheet);
+
+        let visible: Vec<bool> = styled.children.iter().map(StyledNode::is_visible).collect();
+        assert_eq!(visible, [false, false, true]);
+
+        let root = layout_tree(&styled, viewport(100.0)).unwrap();
+        let laid_out: Vec<(&str, f32)> = root
+            .children
+            .iter()
+            .map(|child| match child.box_type {
+                BoxType::BlockNode(style) => (
+                    style
+                        .node()
+                        .as_element()
+                        .unwrap()
+                        .get_id()
+                        .unwrap()
+                        .as_str(),
+                    child.dimensions.content.y,
+                ),
+                _ => panic!("expected block boxes"),
+            })
+            .collect();
+        assert_eq!(laid_out, [("b", 0.0), ("c", 10.0)]);
+    }
+}
@@ -1,5 +1,6 @@
 pub mod css;
 pub mod dom;
+pub mod layout;
 pub mod styles;
 
 #[path = "parsers/html-parser.rs"]
@@ -7,3 +8,6 @@ pub mod html_parser;
 
 #[path = "parsers/css-parser.rs"]
 pub mod css_parser;
+
+#[path = "parsers/parse-options.rs"]
+pub mod parse_options;
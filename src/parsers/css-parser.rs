@@ -1,37 +1,289 @@
+use std::fmt;
 use std::iter::Peekable;
 use std::str::Chars;
 
-use crate::css::{Color, Declaration, Rule, Selector, SimpleSelector, Stylesheet, Unit, Value};
+use crate::css::{
+    AttrOp, AttrSelector, CalcNode, Color, CssFunction, CssWide, Declaration, FontFamily, Keyframe,
+    Keyframes, PseudoClass, Rule, Selector, Shadow, SimpleSelector, Stylesheet, SupportsCondition,
+    SupportsRule, Unit, Value,
+};
+use crate::parse_options::ParseOptions;
 
 pub struct CssParser<'a> {
     chars: Peekable<Chars<'a>>,
+    // Keep lengths with an unknown unit (`10ptx`) as `Value::Other` instead
+    // of reading them as px.
+    strict_units: bool,
+    // Called with the name and raw value, as written, of every kept
+    // declaration whose property the parser doesn't recognise.
+    on_unknown_property: Option<PropertyCallback<'a>>,
+}
+
+type PropertyCallback<'a> = Box<dyn FnMut(&str, &str) + 'a>;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum CssParseError {
+    InvalidSelector,
+    // The input is longer, in bytes, than the limit given to `with_max_len`.
+    InputTooLarge { len: usize, limit: usize },
 }
 
 impl<'a> CssParser<'a> {
-    pub fn new(full_css: &str) -> CssParser {
+    pub fn new(full_css: &str) -> CssParser<'_> {
         CssParser {
             chars: full_css.chars().peekable(),
+            strict_units: false,
+            on_unknown_property: None,
+        }
+    }
+
+    // Like `new`, but refuses inputs over `limit` bytes up front instead of
+    // parsing them. `new` has no limit.
+    pub fn with_max_len(full_css: &str, limit: usize) -> Result<CssParser<'_>, CssParseError> {
+        if full_css.len() > limit {
+            return Err(CssParseError::InputTooLarge {
+                len: full_css.len(),
+                limit,
+            });
         }
+
+        Ok(CssParser::new(full_css))
+    }
+
+    // Applies the options that concern CSS: `max_len` and `strict_units`.
+    pub fn parse_with_options(
+        full_css: &str,
+        options: &ParseOptions,
+    ) -> Result<Stylesheet, CssParseError> {
+        let parser = match options.max_len {
+            Some(limit) => CssParser::with_max_len(full_css, limit)?,
+            None => CssParser::new(full_css),
+        };
+
+        Ok(parser.strict_units(options.strict_units).parse_stylesheet())
+    }
+
+    pub fn strict_units(mut self, strict: bool) -> Self {
+        self.strict_units = strict;
+        self
+    }
+
+    // Registers `callback` to hear about properties the parser has no
+    // translation for, which it keeps as `Value::Other`. Useful for
+    // property-coverage reports.
+    pub fn on_unknown_property(mut self, callback: impl FnMut(&str, &str) + 'a) -> Self {
+        self.on_unknown_property = Some(Box::new(callback));
+        self
     }
 
     pub fn parse_stylesheet(&mut self) -> Stylesheet {
-        let mut stylesheet = Stylesheet::default();
+        let mut stylesheet = Stylesheet {
+            charset: self.parse_charset(),
+            ..Stylesheet::default()
+        };
+        self.parse_rule_list(&mut stylesheet, false);
+        stylesheet
+    }
 
-        while self.chars.peek().is_some() {
-            let selectors = self.parse_selectors();
-            let styles = self.parse_declarations();
-            let rule = Rule::new(selectors, styles);
+    // https://www.w3.org/TR/css-syntax-3/#charset-rule
+    // Only recognised as the very first bytes of the sheet and spelled
+    // exactly `@charset "...";`. Anywhere else it's skipped like any
+    // unknown at-rule.
+    fn parse_charset(&mut self) -> Option<String> {
+        const PREFIX: &str = "@charset \"";
 
-            stylesheet.rules.push(rule);
+        if !self.chars.clone().take(PREFIX.len()).eq(PREFIX.chars()) {
+            return None;
+        }
+        for _ in 0..PREFIX.len() {
+            self.chars.next();
         }
 
-        stylesheet
+        let charset = self.consume_while(|c| c != '"' && c != ';');
+        if self.chars.peek() == Some(&'"') {
+            self.chars.next();
+            if self.chars.peek() == Some(&';') {
+                self.chars.next();
+                return Some(charset);
+            }
+        }
+
+        self.skip_at_rule();
+        None
+    }
+
+    // Rules and at-rules up to the end of input or, when `nested` (inside a
+    // group like `@supports`), up to and including the closing `}`.
+    fn parse_rule_list(&mut self, stylesheet: &mut Stylesheet, nested: bool) {
+        self.consume_while(char::is_whitespace);
+        while let Some(&c) = self.chars.peek() {
+            if c == '@' {
+                self.chars.next();
+                self.parse_at_rule(stylesheet);
+            } else if c == '}' && nested {
+                self.chars.next();
+                return;
+            } else {
+                let selectors = self.parse_selectors();
+                let styles = self.parse_declarations();
+                let rule = Rule::new(selectors, styles);
+
+                stylesheet.rules.push(rule);
+            }
+            self.consume_while(char::is_whitespace);
+        }
+    }
+
+    // Called after the `@`. At-rules we don't model are skipped whole, block
+    // included, so they can't leak into the following rules.
+    fn parse_at_rule(&mut self, stylesheet: &mut Stylesheet) {
+        let name = self
+            .consume_while(|c| is_valid_ident(c) || c == '-')
+            .to_lowercase();
+
+        match name.as_ref() {
+            "keyframes" | "-webkit-keyframes" | "-moz-keyframes" | "-o-keyframes" => {
+                self.consume_while(char::is_whitespace);
+                let keyframes_name = self.consume_while(|c| c != '{').trim().to_string();
+                self.chars.next();
+                let frames = self.parse_keyframe_blocks();
+
+                stylesheet.keyframes.push(Keyframes {
+                    name: keyframes_name,
+                    frames,
+                });
+            }
+            "supports" => {
+                let prelude = self.consume_while(|c| c != '{' && c != ';');
+                if self.chars.next() != Some('{') {
+                    return;
+                }
+
+                let mut group = Stylesheet::default();
+                self.parse_rule_list(&mut group, true);
+
+                // An unparsable condition never applies, so its rules go.
+                let Some(condition) = parse_supports_condition(&prelude.to_lowercase()) else {
+                    return;
+                };
+
+                // Keyframes are global even when declared in a group; nested
+                // groups apply only if both conditions hold.
+                stylesheet.keyframes.append(&mut group.keyframes);
+                let nested: Vec<SupportsRule> = group
+                    .supports
+                    .into_iter()
+                    .map(|inner| SupportsRule {
+                        condition: SupportsCondition::And(vec![condition.clone(), inner.condition]),
+                        rules: inner.rules,
+                    })
+                    .collect();
+
+                stylesheet.supports.push(SupportsRule {
+                    condition,
+                    rules: group.rules,
+                });
+                stylesheet.supports.extend(nested);
+            }
+            _ => self.skip_at_rule(),
+        }
+    }
+
+    // Keyframe blocks up to and including the closing `}` of `@keyframes`.
+    fn parse_keyframe_blocks(&mut self) -> Vec<Keyframe> {
+        let mut frames = Vec::new();
+
+        loop {
+            self.consume_while(char::is_whitespace);
+            match self.chars.peek() {
+                Some('}') => {
+                    self.chars.next();
+                    break;
+                }
+                None => break,
+                _ => {}
+            }
+
+            let selector = self.consume_while(|c| c != '{' && c != '}');
+            if self.chars.peek() != Some(&'{') {
+                continue;
+            }
+            self.chars.next();
+            let declarations = self.parse_declarations();
+
+            let offsets: Option<Vec<f32>> = selector
+                .split(',')
+                .map(|offset| match offset.trim().to_lowercase().as_ref() {
+                    "from" => Some(0.0),
+                    "to" => Some(100.0),
+                    o => o.strip_suffix('%').and_then(parse_finite),
+                })
+                .collect();
+
+            // An invalid keyframe selector drops just that block.
+            if let Some(offsets) = offsets {
+                frames.push(Keyframe {
+                    offsets,
+                    declarations,
+                });
+            }
+        }
+
+        frames
+    }
+
+    fn skip_at_rule(&mut self) {
+        self.consume_while(|c| c != ';' && c != '{');
+
+        if self.chars.next() == Some('{') {
+            let mut depth = 1;
+            for c in self.chars.by_ref() {
+                match c {
+                    '{' => depth += 1,
+                    '}' => {
+                        depth -= 1;
+                        if depth == 0 {
+                            break;
+                        }
+                    }
+                    _ => {}
+                }
+            }
+        }
+    }
+
+    // Parses the whole input as a comma-separated selector list. Unlike the
+    // stylesheet path, one bad selector fails the list instead of being
+    // skipped.
+    pub fn parse_selector_list(&mut self) -> Result<Vec<Selector>, CssParseError> {
+        let mut selectors = Vec::new();
+
+        loop {
+            let selector = self.parse_selector();
+            if selector == Selector::default() {
+                return Err(CssParseError::InvalidSelector);
+            }
+            selectors.push(selector);
+
+            self.consume_while(char::is_whitespace);
+            match self.chars.next() {
+                Some(',') => {}
+                None => return Ok(selectors),
+                Some(_) => return Err(CssParseError::InvalidSelector),
+            }
+        }
+    }
+
+    // Parses the whole input as the inside of a `{ ... }` block, such as the
+    // value of a `style` attribute: `color: red; margin: 4px`.
+    pub fn parse_declaration_block(&mut self) -> Vec<Declaration> {
+        self.parse_declarations()
     }
 
     fn parse_selectors(&mut self) -> Vec<Selector> {
         let mut selectors = Vec::new();
 
-        while self.chars.peek().map_or(false, |c| *c != '{') {
+        while self.chars.peek().is_some_and(|c| *c != '{') {
             let selector = self.parse_selector();
 
             if selector != Selector::default() {
@@ -39,7 +291,7 @@ impl<'a> CssParser<'a> {
             }
 
             self.consume_while(char::is_whitespace);
-            if self.chars.peek().map_or(false, |c| *c == ',') {
+            if self.chars.peek().is_some_and(|c| *c == ',') {
                 self.chars.next();
             }
         }
@@ -48,75 +300,217 @@ impl<'a> CssParser<'a> {
         selectors
     }
 
+    // A chain of compound selectors joined by descendant (` `) or child (`>`)
+    // combinators. Anything we can't understand drops the whole selector
+    // rather than leaving a looser one that would over-match.
     fn parse_selector(&mut self) -> Selector {
-        let mut simple_sel = SimpleSelector::default();
         let mut selector = Selector::default();
 
         self.consume_while(char::is_whitespace);
 
-        simple_sel.tag_name = match self.chars.peek() {
-            Some(&c) if is_valid_start_ident(c) => Some(self.parse_identifier()),
-            _ => None,
+        loop {
+            let simple_sel = match self.parse_simple_selector() {
+                Some(simple_sel) => simple_sel,
+                None => {
+                    self.consume_while(|c| c != ',' && c != '{');
+                    return Selector::default();
+                }
+            };
+            if simple_sel == SimpleSelector::default() {
+                break;
+            }
+            selector.simple.push(simple_sel);
+
+            let ws = self.consume_while(char::is_whitespace);
+            match self.chars.peek() {
+                Some('>') => {
+                    self.chars.next();
+                    self.consume_while(char::is_whitespace);
+                    selector.combinators.push('>');
+                }
+                Some(',') | Some('{') | None => break,
+                Some(_) if !ws.is_empty() => selector.combinators.push(' '),
+                Some(_) => break,
+            }
+        }
+
+        let complete = self.chars.peek().is_none_or(|c| *c == ',' || *c == '{')
+            && selector.combinators.len() + 1 == selector.simple.len();
+
+        if !complete {
+            self.consume_while(|c| c != ',' && c != '{');
+            return Selector::default();
+        }
+
+        selector
+    }
+
+    // `tag#id.class` with every part optional, so `#main` and `.btn.primary`
+    // need no leading tag; `*` may stand in for the tag. Returns `None` for a
+    // compound that can never match, like `#a#b`.
+    fn parse_simple_selector(&mut self) -> Option<SimpleSelector> {
+        // Tag names match case-insensitively, escaped or not.
+        let tag_name = if starts_identifier(self.chars.clone()) {
+            Some(self.parse_identifier().to_lowercase())
+        } else if self.chars.next_if_eq(&'*').is_some() {
+            Some("*".to_string())
+        } else {
+            None
         };
+        let mut simple_sel = SimpleSelector::new(tag_name, None, Vec::new());
 
-        let mut multiple_ids = false;
-        while self
-            .chars
-            .peek()
-            .map_or(false, |c| *c == ',' && *c != '{' && !(*c).is_whitespace())
-        {
+        loop {
             match self.chars.peek() {
-                Some(&c) if c == '#' => {
+                Some('#') => {
                     self.chars.next();
 
-                    if simple_sel.id.is_some() || multiple_ids {
-                        simple_sel.id = None;
-                        multiple_ids = true;
-                        self.parse_id();
-                    } else {
-                        simple_sel.id = self.parse_id();
+                    let id = self.parse_id()?;
+                    if simple_sel.id.as_ref().is_some_and(|prev| *prev != id) {
+                        return None;
                     }
+                    simple_sel.id = Some(id);
                 }
-                Some(&c) if c == '.' => {
+                Some('.') => {
                     self.chars.next();
                     let class_name = self.parse_identifier();
 
-                    if class_name != String::from("") {
-                        simple_sel.classes.push(class_name);
+                    if class_name.is_empty() {
+                        return None;
                     }
+                    simple_sel.classes.push(class_name);
                 }
-                _ => {
-                    self.consume_while(|c| c != ',' && c != '{');
+                Some('[') => {
+                    self.chars.next();
+                    simple_sel.attributes.push(self.parse_attr_selector()?);
+                }
+                Some(':') => {
+                    self.chars.next();
+                    simple_sel.pseudo_classes.push(self.parse_pseudo_class()?);
                 }
+                _ => break,
             }
         }
 
-        if simple_sel != SimpleSelector::default() {
-            selector.simple.push(simple_sel);
+        Some(simple_sel)
+    }
+
+    // The part after `[`, up to and including `]`. Values may be quoted or a
+    // bare identifier; quoted values keep their case.
+    fn parse_attr_selector(&mut self) -> Option<AttrSelector> {
+        self.consume_while(char::is_whitespace);
+        let name = self.parse_identifier().to_lowercase();
+        if name.is_empty() {
+            return None;
         }
+        self.consume_while(char::is_whitespace);
 
-        selector
+        let op = match self.chars.next()? {
+            ']' => return Some(AttrSelector::new(name, AttrOp::Exists)),
+            '=' => AttrOp::Equals,
+            '^' if self.chars.next() == Some('=') => AttrOp::Prefix,
+            '|' if self.chars.next() == Some('=') => AttrOp::DashMatch,
+            '*' if self.chars.next() == Some('=') => AttrOp::Substring,
+            _ => return None,
+        };
+
+        self.consume_while(char::is_whitespace);
+        let value = match self.chars.peek() {
+            Some(&q) if q == '"' || q == '\'' => {
+                self.chars.next();
+                let value = self.consume_while(|c| c != q);
+                self.chars.next()?;
+                value
+            }
+            _ => self.consume_while(is_valid_ident),
+        };
+        self.consume_while(char::is_whitespace);
+
+        match self.chars.next() {
+            Some(']') => Some(AttrSelector::new(name, op(value))),
+            _ => None,
+        }
+    }
+
+    // Only the structural pseudo-classes are understood; any other one makes
+    // the selector unusable.
+    fn parse_pseudo_class(&mut self) -> Option<PseudoClass> {
+        match self.parse_identifier().to_lowercase().as_ref() {
+            "first-child" => Some(PseudoClass::FirstChild),
+            "last-child" => Some(PseudoClass::LastChild),
+            "nth-child" if self.chars.peek() == Some(&'(') => {
+                self.chars.next();
+                let arg = self.consume_while(|c| c != ')' && c != '{');
+                if self.chars.next() != Some(')') {
+                    return None;
+                }
+                let (a, b) = parse_nth(&arg)?;
+                Some(PseudoClass::NthChild(a, b))
+            }
+            _ => None,
+        }
     }
 
+    // https://www.w3.org/TR/css-syntax-3/#ident-token-diagram
+    // Empty when the input doesn't start an identifier, as with `123` or a
+    // lone `-`, so callers can reject the selector. Escapes such as `\31 23`
+    // or `\:` stand for the character they name, which is kept literally:
+    // only the unescaped characters are lowercased.
     fn parse_identifier(&mut self) -> String {
         let mut ident = String::new();
 
-        match self.chars.peek() {
-            Some(&c) => {
-                if is_valid_start_ident(c) {
-                    ident.push_str(&self.consume_while(is_valid_ident))
-                }
+        if !starts_identifier(self.chars.clone()) {
+            return ident;
+        }
+
+        while let Some(&c) = self.chars.peek() {
+            if is_valid_ident(c) {
+                ident.extend(c.to_lowercase());
+                self.chars.next();
+            } else if c == '\\' && is_valid_escape(self.chars.clone()) {
+                self.chars.next();
+                ident.push(self.consume_escape());
+            } else {
+                break;
             }
-            None => {}
         }
 
-        ident.to_lowercase()
+        ident
+    }
+
+    // After the backslash: up to six hex digits plus one optional
+    // whitespace, or any other single character taken literally.
+    fn consume_escape(&mut self) -> char {
+        let hex = self.consume_while_max(|c| c.is_ascii_hexdigit(), 6);
+        if hex.is_empty() {
+            return self.chars.next().unwrap_or(char::REPLACEMENT_CHARACTER);
+        }
+        self.chars.next_if(|c| c.is_whitespace());
+
+        u32::from_str_radix(&hex, 16)
+            .ok()
+            .filter(|&n| n != 0)
+            .and_then(char::from_u32)
+            .unwrap_or(char::REPLACEMENT_CHARACTER)
+    }
+
+    fn consume_while_max<F>(&mut self, condition: F, max: usize) -> String
+    where
+        F: Fn(char) -> bool,
+    {
+        let mut result = String::new();
+        while result.len() < max {
+            match self.chars.next_if(|&c| condition(c)) {
+                Some(c) => result.push(c),
+                None => break,
+            }
+        }
+        result
     }
 
     fn parse_id(&mut self) -> Option<String> {
         match &self.parse_identifier()[..] {
             "" => None,
-            s @ _ => Some(s.to_string()),
+            s => Some(s.to_string()),
             // @
             // https://doc.rust-lang.org/reference/patterns.html#identifier-patterns
         }
@@ -125,50 +519,71 @@ impl<'a> CssParser<'a> {
     fn parse_declarations(&mut self) -> Vec<Declaration> {
         let mut decls = Vec::<Declaration>::new();
 
-        while self.chars.peek().map_or(false, |c| *c != '}') {
+        while self.chars.peek().is_some_and(|c| *c != '}') {
             self.consume_while(char::is_whitespace);
-            let property = self.consume_while(|x| x != ':').to_lowercase();
+            let property = self
+                .consume_while(|x| x != ':' && x != ';' && x != '}')
+                .trim()
+                .to_lowercase();
+
+            // No colon: not a declaration. Drop it without touching the
+            // declarations around it.
+            if self.chars.peek() != Some(&':') {
+                if self.chars.peek() == Some(&';') {
+                    self.chars.next();
+                }
+                self.consume_while(char::is_whitespace);
+                continue;
+            }
 
             self.chars.next();
             self.consume_while(char::is_whitespace);
 
-            let val = self
-                .consume_while(|x| x != ';' && x != '\n' && x != '{')
-                .to_lowercase();
+            // Values are case-insensitive, except for the family names in
+            // `font-family`.
+            let raw = self.consume_value().unwrap_or_default().trim().to_string();
+            let val = if property == "font-family" {
+                raw.clone()
+            } else {
+                raw.to_lowercase()
+            };
+            let (val, important) = strip_important(&val);
+            let unknown = !is_known_property(&property);
 
-            let value_enum = match property.as_ref() {
-                "background-color" | "border-color" | "color" => {
-                    Value::Color(translate_color(&val))
+            // `: red`, `color: ;` and values with a bad string are dropped
+            // like any other invalid declaration, but still consume their
+            // terminator below.
+            let declarations = if property.is_empty() || val.is_empty() {
+                Vec::new()
+            } else {
+                // The flag is applied after expansion, so every longhand of
+                // `margin: 0 !important` is important, not just the first.
+                let mut declarations =
+                    expand_declaration(property.clone(), val.to_string(), self.strict_units);
+                for declaration in &mut declarations {
+                    declaration.important = important;
                 }
-                "margin"
-                | "padding"
-                | "margin-top"
-                | "margin-left"
-                | "margin-right"
-                | "margin-bottom"
-                | "padding-top"
-                | "padding-left"
-                | "padding-right"
-                | "padding-bottom"
-                | "border-top-width"
-                | "border-left-width"
-                | "border-right-width"
-                | "border-bottom-width"
-                | "width"
-                | "height" => translate_length(&val),
-                _ => Value::Other(val),
+                declarations
             };
 
-            let declaration = Declaration::new(property, value_enum);
-
-            if self.chars.peek().map_or(false, |c| *c == ';') {
-                decls.push(declaration);
+            let terminated = if self.chars.peek().is_some_and(|c| *c == ';') {
                 self.chars.next();
+                true
             } else {
+                // The end of input closes an unterminated block too.
                 self.consume_while(char::is_whitespace);
-                if self.chars.peek().map_or(false, |c| *c == '}') {
-                    decls.push(declaration);
+                self.chars.peek().is_none_or(|c| *c == '}')
+            };
+
+            if terminated && !declarations.is_empty() {
+                // Reported with the value as written, `!important` and all,
+                // and only for declarations that are kept.
+                if let Some(callback) = self.on_unknown_property.as_mut() {
+                    if unknown {
+                        callback(&property, &raw);
+                    }
                 }
+                decls.extend(declarations);
             }
             self.consume_while(char::is_whitespace);
         }
@@ -177,6 +592,76 @@ impl<'a> CssParser<'a> {
         decls
     }
 
+    // Reads a declaration value up to a top-level `;` or `}`. Semicolons and
+    // braces inside quotes or parens (`url(a;b)`, `"a;b"`) belong to the
+    // value. Values may span lines; newlines outside quotes (CSS counts CR
+    // and form feed as newlines too) are read as spaces. `None` when the
+    // value has a quote left open at the end of its line, a bad string whose
+    // declaration is dropped. Such a quote doesn't start a quoted section,
+    // so a `;` or `}` after it on the same line still ends the value and the
+    // rest of the sheet parses as usual.
+    fn consume_value(&mut self) -> Option<String> {
+        let mut result = String::new();
+        let mut quote: Option<char> = None;
+        let mut depth = 0usize;
+        let mut bad_string = false;
+
+        while let Some(&c) = self.chars.peek() {
+            match quote {
+                Some(q) => {
+                    if c == '\\' {
+                        result.push(c);
+                        self.chars.next();
+                        if let Some(escaped) = self.chars.next() {
+                            result.push(escaped);
+                        }
+                        continue;
+                    }
+                    if c == q {
+                        quote = None;
+                    }
+                }
+                None => match c {
+                    '"' | '\'' if self.quote_closes_on_its_line() => quote = Some(c),
+                    '"' | '\'' => bad_string = true,
+                    '(' => depth += 1,
+                    ')' => depth = depth.saturating_sub(1),
+                    ';' | '}' if depth == 0 => break,
+                    '\n' | '\r' | '\x0c' => {
+                        result.push(' ');
+                        self.chars.next();
+                        continue;
+                    }
+                    _ => {}
+                },
+            }
+
+            result.push(c);
+            self.chars.next();
+        }
+
+        (!bad_string).then_some(result)
+    }
+
+    // Whether the quote at the current position is closed before a newline.
+    // The end of input closes a string too.
+    fn quote_closes_on_its_line(&self) -> bool {
+        let mut chars = self.chars.clone();
+        let quote = chars.next();
+
+        while let Some(c) = chars.next() {
+            match c {
+                '\\' => {
+                    chars.next();
+                }
+                '\n' | '\r' | '\x0c' => return false,
+                _ if Some(c) == quote => return true,
+                _ => {}
+            }
+        }
+        true
+    }
+
     //
 
     fn consume_while<F>(&mut self, condition: F) -> String
@@ -184,7 +669,7 @@ impl<'a> CssParser<'a> {
         F: Fn(char) -> bool,
     {
         let mut result = String::new();
-        while self.chars.peek().map_or(false, |c| condition(*c)) {
+        while self.chars.peek().is_some_and(|c| condition(*c)) {
             result.push(self.chars.next().unwrap());
         }
 
@@ -192,72 +677,2108 @@ impl<'a> CssParser<'a> {
     }
 }
 
-fn translate_color(color: &str) -> Color {
-    // Все цвета: https://colorscheme.ru/html-colors.html
-    // TODO: Дописать все цвета. Сюда напишу основные.
-    // TODO: Дописать все форматы. Пока будут только текстовые идентификаторы.
-
-    // занятие блять на недельку другую
+// Shorthands turn into their longhands here; everything else maps to a
+// single declaration.
+fn expand_declaration(property: String, val: String, strict_units: bool) -> Vec<Declaration> {
+    if let Some(keyword) = translate_css_wide(&val) {
+        return longhands(&property)
+            .into_iter()
+            .map(|longhand| Declaration::new(longhand, Value::Global(keyword)))
+            .collect();
+    }
 
-    return match color {
-        "black" => Color::new(0.0, 0.0, 0.0, 1.0),
-        "white" => Color::new(1.0, 1.0, 1.0, 1.0),
-        "red" => Color::new(1.0, 0.0, 0.0, 1.0),
-        "green" => Color::new(0.0, 1.0, 0.0, 1.0),
-        "blue" => Color::new(0.0, 0.0, 1.0, 1.0),
-        _ => Color::new(0.0, 0.0, 0.0, 1.0),
-    };
+    match property.as_ref() {
+        "background" => expand_background(&val),
+        "margin" => expand_sides(&val, |side| format!("margin-{}", side), strict_units),
+        "padding" => expand_sides(&val, |side| format!("padding-{}", side), strict_units),
+        "border-width" => expand_sides(&val, |side| format!("border-{}-width", side), strict_units),
+        "border" => expand_border(&val, strict_units),
+        "flex" => expand_flex(&val, strict_units),
+        "gap" | "grid-gap" => expand_gap(&val, strict_units),
+        _ => {
+            let value = translate_value(&property, val, strict_units);
+            vec![Declaration::new(property, value)]
+        }
+    }
 }
 
-fn translate_length(length: &str) -> Value {
-    let mut num_str = String::new();
-    let mut unit = String::new();
-    let mut parsing_num = true;
+// Every property `expand_declaration` expands into longhands.
+const SHORTHANDS: &[&str] = &[
+    "background",
+    "margin",
+    "padding",
+    "border-width",
+    "border",
+    "flex",
+    "gap",
+    "grid-gap",
+];
 
-    for ch in length.chars() {
-        if ch.is_numeric() && parsing_num {
-            num_str.push(ch);
-        } else {
-            unit.push(ch);
-            parsing_num = false;
+// The properties a shorthand sets, for values like `margin: inherit` that
+// apply to all of them at once. Other properties stand for themselves.
+fn longhands(property: &str) -> Vec<String> {
+    let sides = |longhand: fn(&str) -> String| -> Vec<String> {
+        ["top", "right", "bottom", "left"]
+            .into_iter()
+            .map(longhand)
+            .collect()
+    };
+
+    match property {
+        "background" => ["color", "image", "repeat", "position"]
+            .iter()
+            .map(|part| format!("background-{}", part))
+            .collect(),
+        "margin" => sides(|side| format!("margin-{}", side)),
+        "padding" => sides(|side| format!("padding-{}", side)),
+        "border-width" => sides(|side| format!("border-{}-width", side)),
+        "border" => {
+            let mut longhands = sides(|side| format!("border-{}-width", side));
+            longhands.push(String::from("border-style"));
+            longhands.push(String::from("border-color"));
+            longhands
         }
+        "flex" => ["grow", "shrink", "basis"]
+            .iter()
+            .map(|part| format!("flex-{}", part))
+            .collect(),
+        "gap" | "grid-gap" => vec![String::from("row-gap"), String::from("column-gap")],
+        _ => vec![property.to_string()],
+    }
+}
+
+fn translate_css_wide(val: &str) -> Option<CssWide> {
+    match val {
+        "inherit" => Some(CssWide::Inherit),
+        "initial" => Some(CssWide::Initial),
+        "unset" => Some(CssWide::Unset),
+        _ => None,
     }
+}
+
+// Shorthands and the longhands they expand into count as known, as do
+// properties with a translation below.
+fn is_known_property(property: &str) -> bool {
+    SHORTHANDS.contains(&property)
+        || SHORTHANDS
+            .iter()
+            .any(|shorthand| longhands(shorthand).iter().any(|l| l == property))
+        || translate_known_value(property, String::new(), false).is_ok()
+}
+
+fn translate_value(property: &str, val: String, strict_units: bool) -> Value {
+    translate_known_value(property, val, strict_units).unwrap_or_else(Value::Other)
+}
+
+// `Err` hands back `val` for properties without a translation.
+fn translate_known_value(property: &str, val: String, strict_units: bool) -> Result<Value, String> {
+    let value = match property {
+        "background-color" | "border-color" | "color" => Value::Color(translate_color(&val)),
+        "margin-top"
+        | "margin-left"
+        | "margin-right"
+        | "margin-bottom"
+        | "padding-top"
+        | "padding-left"
+        | "padding-right"
+        | "padding-bottom"
+        | "border-top-width"
+        | "border-left-width"
+        | "border-right-width"
+        | "border-bottom-width"
+        | "width"
+        | "height"
+        | "font-size" => translate_length(&val, strict_units),
+        "line-height" => match val.parse::<f32>() {
+            Ok(n) if n.is_finite() => Value::Number(n),
+            Ok(_) => Value::Other(val),
+            Err(_) => translate_length(&val, strict_units),
+        },
+        "opacity" | "order" | "font-weight" | "flex-grow" | "flex-shrink" => translate_number(val),
+        "flex-basis" | "row-gap" | "column-gap" => translate_length(&val, strict_units),
+        "grid-template-columns" | "grid-template-rows" => translate_track_list(val, strict_units),
+        "font-family" => translate_font_families(val),
+        "box-shadow" => translate_shadows(val, strict_units),
+        "transform" | "filter" | "backdrop-filter" => translate_function_list(val),
+        "rotate" => translate_angle(&val),
+        "aspect-ratio" => translate_ratio(val),
+        "z-index" => translate_integer(val),
+        "display" | "position" | "float" | "clear" | "text-align" | "overflow" | "overflow-x"
+        | "overflow-y" | "white-space" | "font-style" | "text-transform" | "box-sizing"
+        | "border-style" | "list-style-type" | "cursor" | "visibility" | "flex-direction"
+        | "flex-wrap" | "justify-content" | "align-items" => translate_keyword(val),
+        _ => return Err(val),
+    };
 
-    let num: f32 = num_str.parse().unwrap_or(0.0);
+    Ok(value)
+}
 
-    match unit.as_ref() {
-        "px" => Value::Length(num, Unit::Px),
-        "em" => Value::Length(num, Unit::Em),
-        "rem" => Value::Length(num, Unit::Rem),
-        "vh" => Value::Length(num, Unit::Vh),
-        "vw" => Value::Length(num, Unit::Vw),
-        "vmin" => Value::Length(num, Unit::Vmin),
-        "vmax" => Value::Length(num, Unit::Vmax),
+// https://developer.mozilla.org/en-US/docs/Web/CSS/background
+// Components are recognised by type, so their order doesn't matter.
+// Attachment/origin/clip components are accepted but not expanded.
+fn expand_background(val: &str) -> Vec<Declaration> {
+    let mut color = None;
+    let mut image = None;
+    let mut repeat = Vec::new();
+    let mut position = Vec::new();
 
-        _ => Value::Length(num, Unit::Px),
+    for component in split_components(val) {
+        match component.as_ref() {
+            "repeat" | "no-repeat" | "repeat-x" | "repeat-y" | "space" | "round" => {
+                repeat.push(component)
+            }
+            "left" | "right" | "top" | "bottom" | "center" => position.push(component),
+            "none" => image = Some(Value::Keyword(component)),
+            c if c.starts_with("url(") => image = Some(Value::Other(component)),
+            c if c.starts_with(|ch: char| ch.is_ascii_digit() || ch == '.' || ch == '-') => {
+                position.push(component)
+            }
+            c => {
+                if let Some(parsed) = parse_color(c) {
+                    color = Some(parsed);
+                }
+            }
+        }
     }
+
+    let repeat = if repeat.is_empty() {
+        String::from("repeat")
+    } else {
+        repeat.join(" ")
+    };
+    let position = if position.is_empty() {
+        String::from("0% 0%")
+    } else {
+        position.join(" ")
+    };
+
+    vec![
+        Declaration::new(
+            String::from("background-color"),
+            Value::Color(color.unwrap_or(Color::new(0.0, 0.0, 0.0, 0.0))),
+        ),
+        Declaration::new(
+            String::from("background-image"),
+            image.unwrap_or(Value::Keyword(String::from("none"))),
+        ),
+        Declaration::new(String::from("background-repeat"), translate_keyword(repeat)),
+        Declaration::new(
+            String::from("background-position"),
+            translate_keyword(position),
+        ),
+    ]
 }
 
-fn is_valid_ident(c: char) -> bool {
-    is_valid_start_ident(c) || c.is_digit(10) || c == '-'
+// https://developer.mozilla.org/en-US/docs/Web/CSS/margin#syntax
+// One to four lengths, clockwise from the top, with missing sides copied
+// from the opposite one. Each component keeps its own unit. Any other
+// number of components drops the declaration.
+fn expand_sides(
+    val: &str,
+    longhand: impl Fn(&str) -> String,
+    strict_units: bool,
+) -> Vec<Declaration> {
+    let components = split_components(val);
+    let [top, right, bottom, left] = match components.as_slice() {
+        [all] => [all, all, all, all],
+        [vertical, horizontal] => [vertical, horizontal, vertical, horizontal],
+        [top, horizontal, bottom] => [top, horizontal, bottom, horizontal],
+        [top, right, bottom, left] => [top, right, bottom, left],
+        _ => return Vec::new(),
+    };
+
+    [
+        ("top", top),
+        ("right", right),
+        ("bottom", bottom),
+        ("left", left),
+    ]
+    .into_iter()
+    .map(|(side, component)| {
+        Declaration::new(longhand(side), translate_length(component, strict_units))
+    })
+    .collect()
 }
 
-fn is_valid_start_ident(c: char) -> bool {
-    is_letter(c) || is_non_ascii(c) || c == '_'
+// https://developer.mozilla.org/en-US/docs/Web/CSS/border
+// `<width> <style> <color>` in any order, each optional. The width applies
+// to all four sides.
+fn expand_border(val: &str, strict_units: bool) -> Vec<Declaration> {
+    let mut width = None;
+    let mut style = None;
+    let mut color = None;
+
+    for component in split_components(val) {
+        match component.as_ref() {
+            "none" | "hidden" | "dotted" | "dashed" | "solid" | "double" | "groove" | "ridge"
+            | "inset" | "outset" => style = Some(component),
+            "thin" | "medium" | "thick" => width = Some(component),
+            c if c.starts_with(|ch: char| ch.is_ascii_digit() || ch == '.') => {
+                width = Some(component)
+            }
+            c => color = parse_color(c).or(color),
+        }
+    }
+
+    let mut declarations = match width {
+        Some(width) => expand_sides(
+            &width,
+            |side| format!("border-{}-width", side),
+            strict_units,
+        ),
+        None => Vec::new(),
+    };
+    if let Some(style) = style {
+        declarations.push(Declaration::new(
+            String::from("border-style"),
+            Value::Keyword(style),
+        ));
+    }
+    if let Some(color) = color {
+        declarations.push(Declaration::new(
+            String::from("border-color"),
+            Value::Color(color),
+        ));
+    }
+
+    declarations
 }
 
-fn is_letter(c: char) -> bool {
-    is_upper_letter(c) || is_lower_letter(c)
+// https://developer.mozilla.org/en-US/docs/Web/CSS/flex
+// `<grow> <shrink>? <basis>?`, where a lone number means `n 1 0%`, plus the
+// `none` and `auto` keywords. Anything else drops the declaration.
+fn expand_flex(val: &str, strict_units: bool) -> Vec<Declaration> {
+    let components = split_components(val);
+    let numbers: Vec<Option<f32>> = components.iter().map(|c| c.parse().ok()).collect();
+    if numbers.iter().flatten().any(|n: &f32| !n.is_finite()) {
+        return Vec::new();
+    }
+
+    let (grow, shrink, basis) = match (components.as_slice(), numbers.as_slice()) {
+        ([keyword], _) if keyword == "none" => (0.0, 0.0, "auto"),
+        ([keyword], _) if keyword == "auto" => (1.0, 1.0, "auto"),
+        (_, [Some(grow)]) => (*grow, 1.0, "0%"),
+        ([basis], [None]) => (1.0, 1.0, basis.as_str()),
+        (_, [Some(grow), Some(shrink)]) => (*grow, *shrink, "0%"),
+        ([_, basis], [Some(grow), None]) => (*grow, 1.0, basis.as_str()),
+        ([_, _, basis], [Some(grow), Some(shrink), None]) => (*grow, *shrink, basis.as_str()),
+        // A unitless basis is only allowed as zero, as in `flex: 1 1 0`.
+        (_, [Some(grow), Some(shrink), Some(basis)]) if *basis == 0.0 => (*grow, *shrink, "0px"),
+        _ => return Vec::new(),
+    };
+
+    vec![
+        Declaration::new(String::from("flex-grow"), Value::Number(grow)),
+        Declaration::new(String::from("flex-shrink"), Value::Number(shrink)),
+        Declaration::new(
+            String::from("flex-basis"),
+            translate_length(basis, strict_units),
+        ),
+    ]
 }
 
-fn is_upper_letter(c: char) -> bool {
-    c >= 'A' && c <= 'Z'
+// https://developer.mozilla.org/en-US/docs/Web/CSS/font-family
+// A comma-separated list of quoted or unquoted family names and generic
+// families. Quotes are stripped; an unquoted name keeps single spaces
+// between its words. An empty entry keeps the whole value as raw text.
+fn translate_font_families(val: String) -> Value {
+    let mut families = Vec::new();
+    let mut current = String::new();
+    let mut quote: Option<char> = None;
+    let mut quoted = false;
+
+    let mut chars = val.chars().chain(std::iter::once(','));
+    while let Some(c) = chars.next() {
+        match quote {
+            Some(q) if c == q => quote = None,
+            Some(_) if c == '\\' => current.extend(chars.next()),
+            Some(_) => current.push(c),
+            None => match c {
+                '"' | '\'' if current.trim().is_empty() && !quoted => {
+                    current.clear();
+                    quote = Some(c);
+                    quoted = true;
+                }
+                ',' => {
+                    let name = if quoted {
+                        current.clone()
+                    } else {
+                        current.split_whitespace().collect::<Vec<_>>().join(" ")
+                    };
+                    if name.is_empty() {
+                        return Value::Other(val);
+                    }
+
+                    families.push(match generic_family(&name) {
+                        Some(generic) if !quoted => FontFamily::Generic(generic),
+                        _ => FontFamily::Name(name),
+                    });
+                    current.clear();
+                    quoted = false;
+                }
+                // Only whitespace may follow a quoted name before the comma.
+                _ if quoted && !c.is_whitespace() => return Value::Other(val),
+                _ if quoted => {}
+                _ => current.push(c),
+            },
+        }
+    }
+
+    Value::FontFamilyList(families)
 }
 
-fn is_lower_letter(c: char) -> bool {
-    c >= 'a' && c <= 'z'
+// https://developer.mozilla.org/en-US/docs/Web/CSS/box-shadow#syntax
+// Each layer takes two to four adjacent lengths plus an optional color and
+// `inset` on either side of them. One bad layer drops the whole value.
+fn translate_shadows(val: String, strict_units: bool) -> Value {
+    if val.eq_ignore_ascii_case("none") {
+        return Value::Keyword(val.to_ascii_lowercase());
+    }
+
+    match split_commas(&val)
+        .iter()
+        .map(|layer| translate_shadow(layer, strict_units))
+        .collect()
+    {
+        Some(shadows) => Value::ShadowList(shadows),
+        None => Value::Other(val),
+    }
 }
 
-fn is_non_ascii(c: char) -> bool {
-    c >= '\u{0080}'
+fn translate_shadow(layer: &str, strict_units: bool) -> Option<Shadow> {
+    let mut inset = false;
+    let mut color = None;
+    let mut lengths = Vec::new();
+    // Set once the lengths are over, so `1px red 2px` is refused.
+    let mut lengths_done = false;
+
+    for component in split_components(layer) {
+        if numeric_prefix_len(&component) > 0 || component.starts_with("calc(") {
+            if lengths_done {
+                return None;
+            }
+            match translate_length(&component, strict_units) {
+                Value::Other(_) => return None,
+                length => lengths.push(length),
+            }
+            continue;
+        }
+
+        lengths_done = !lengths.is_empty();
+        if component.eq_ignore_ascii_case("inset") && !inset {
+            inset = true;
+        } else if color.is_none() {
+            color = Some(parse_color(&component)?);
+        } else {
+            return None;
+        }
+    }
+
+    if !(2..=4).contains(&lengths.len()) {
+        return None;
+    }
+    let mut lengths = lengths.into_iter();
+    let mut next = || lengths.next().unwrap_or(Value::Length(0.0, Unit::Px));
+
+    Some(Shadow {
+        inset,
+        offset_x: next(),
+        offset_y: next(),
+        blur: next(),
+        spread: next(),
+        color,
+    })
+}
+
+fn generic_family(name: &str) -> Option<String> {
+    let name = name.to_ascii_lowercase();
+    match name.as_str() {
+        "serif" | "sans-serif" | "monospace" | "cursive" | "fantasy" | "system-ui" | "ui-serif"
+        | "ui-sans-serif" | "ui-monospace" | "ui-rounded" | "emoji" | "math" | "fangsong" => {
+            Some(name)
+        }
+        _ => None,
+    }
+}
+
+// https://developer.mozilla.org/en-US/docs/Web/CSS/gap
+// `<row-gap> <column-gap>?`, the column gap defaulting to the row gap.
+fn expand_gap(val: &str, strict_units: bool) -> Vec<Declaration> {
+    let components = split_components(val);
+    let (row, column) = match components.as_slice() {
+        [both] => (both, both),
+        [row, column] => (row, column),
+        _ => return Vec::new(),
+    };
+
+    vec![
+        Declaration::new(String::from("row-gap"), translate_length(row, strict_units)),
+        Declaration::new(
+            String::from("column-gap"),
+            translate_length(column, strict_units),
+        ),
+    ]
+}
+
+// https://developer.mozilla.org/en-US/docs/Web/CSS/grid-template-columns
+// `none`, or track sizes (`1fr`, `100px`, `auto`, ...) as a `Value::List`,
+// with `repeat(n, ...)` written out n times. Tracks this can't model, like
+// `minmax()` or `repeat(auto-fill, ...)`, are kept as `Value::Other`, as is
+// a repeat that would take the list past `MAX_TRACKS`, so a huge count
+// can't exhaust memory.
+fn translate_track_list(val: String, strict_units: bool) -> Value {
+    const MAX_TRACKS: usize = 10_000;
+
+    if val == "none" {
+        return Value::Keyword(val);
+    }
+
+    let mut tracks = Vec::new();
+    for component in split_components(&val) {
+        match parse_function(&component) {
+            Some(repeat) if repeat.name == "repeat" => {
+                let budget = MAX_TRACKS.saturating_sub(tracks.len());
+                match expand_repeat(&repeat, budget, strict_units) {
+                    Some(repeated) => tracks.extend(repeated),
+                    None => tracks.push(Value::Other(component)),
+                }
+            }
+            Some(_) => tracks.push(Value::Other(component)),
+            None => tracks.push(translate_track_size(&component, strict_units)),
+        }
+    }
+
+    Value::List(tracks)
+}
+
+// `repeat(3, 1fr 100px)` as six tracks. Only a fixed count is expanded,
+// and only when the result has at most `max_tracks` tracks.
+fn expand_repeat(
+    repeat: &CssFunction,
+    max_tracks: usize,
+    strict_units: bool,
+) -> Option<Vec<Value>> {
+    let [count, tracks] = repeat.args.as_slice() else {
+        return None;
+    };
+    let count: usize = count.parse().ok().filter(|n| *n >= 1)?;
+
+    let tracks: Vec<Value> = split_components(tracks)
+        .iter()
+        .map(|track| translate_track_size(track, strict_units))
+        .collect();
+    let total = tracks
+        .len()
+        .checked_mul(count)
+        .filter(|n| *n <= max_tracks)?;
+
+    Some(tracks.iter().cycle().take(total).cloned().collect())
+}
+
+fn translate_track_size(track: &str, strict_units: bool) -> Value {
+    match track.strip_suffix("fr") {
+        Some(n) => match parse_finite(n) {
+            Some(n) => Value::Length(n, Unit::Fr),
+            None => Value::Other(track.to_string()),
+        },
+        None => translate_length(track, strict_units),
+    }
+}
+
+// Splits a value on top-level commas, so the commas inside `rgba(0, 0, 0,
+// .3)` or a quoted string don't count. Parts are trimmed.
+fn split_commas(val: &str) -> Vec<&str> {
+    let mut parts = Vec::new();
+    let mut quote: Option<char> = None;
+    let mut depth = 0usize;
+    let mut start = 0;
+
+    for (i, c) in val.char_indices() {
+        match quote {
+            Some(q) if c == q => quote = None,
+            Some(_) => {}
+            None => match c {
+                '"' | '\'' => quote = Some(c),
+                '(' => depth += 1,
+                ')' => depth = depth.saturating_sub(1),
+                ',' if depth == 0 => {
+                    parts.push(val[start..i].trim());
+                    start = i + 1;
+                }
+                _ => {}
+            },
+        }
+    }
+    parts.push(val[start..].trim());
+
+    parts
+}
+
+// Splits a value on top-level whitespace, keeping `rgb(1, 2, 3)` or
+// `url("a b.png")` as one component.
+fn split_components(val: &str) -> Vec<String> {
+    let mut components = Vec::new();
+    let mut current = String::new();
+    let mut quote: Option<char> = None;
+    let mut depth = 0usize;
+
+    for c in val.chars() {
+        match quote {
+            Some(q) if c == q => quote = None,
+            Some(_) => {}
+            None => match c {
+                '"' | '\'' => quote = Some(c),
+                '(' => depth += 1,
+                ')' => depth = depth.saturating_sub(1),
+                _ if c.is_whitespace() && depth == 0 => {
+                    if !current.is_empty() {
+                        components.push(std::mem::take(&mut current));
+                    }
+                    continue;
+                }
+                _ => {}
+            },
+        }
+        current.push(c);
+    }
+
+    if !current.is_empty() {
+        components.push(current);
+    }
+
+    components
+}
+
+// The `An+B` micro-syntax plus the `odd`/`even` keywords.
+// https://www.w3.org/TR/css-syntax-3/#anb-microsyntax
+fn parse_nth(arg: &str) -> Option<(i32, i32)> {
+    let arg: String = arg
+        .chars()
+        .filter(|c| !c.is_whitespace())
+        .collect::<String>()
+        .to_lowercase();
+
+    match arg.as_ref() {
+        "odd" => return Some((2, 1)),
+        "even" => return Some((2, 0)),
+        _ => {}
+    }
+
+    match arg.split_once('n') {
+        Some((a, b)) => {
+            let a = match a {
+                "" | "+" => 1,
+                "-" => -1,
+                _ => a.parse().ok()?,
+            };
+            let b = match b {
+                "" => 0,
+                _ if b.starts_with(['+', '-']) => b.parse().ok()?,
+                _ => return None,
+            };
+            Some((a, b))
+        }
+        None => Some((0, arg.parse().ok()?)),
+    }
+}
+
+impl fmt::Display for CssParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            CssParseError::InvalidSelector => write!(f, "invalid selector"),
+            CssParseError::InputTooLarge { len, limit } => {
+                write!(f, "input of {} bytes exceeds the {} byte limit", len, limit)
+            }
+        }
+    }
+}
+
+impl std::error::Error for CssParseError {}
+
+// The prelude of `@supports`: `(prop: value)` tests combined with `not`,
+// `and` and `or`. Mixing `and` and `or` at one level needs parentheses.
+fn parse_supports_condition(text: &str) -> Option<SupportsCondition> {
+    let mut chars = text.chars().peekable();
+    let condition = parse_supports_expr(&mut chars)?;
+
+    chars.all(char::is_whitespace).then_some(condition)
+}
+
+fn parse_supports_expr(chars: &mut Peekable<Chars>) -> Option<SupportsCondition> {
+    skip_whitespace(chars);
+    if chars
+        .clone()
+        .take_while(|c| c.is_alphabetic())
+        .collect::<String>()
+        == "not"
+    {
+        chars.nth(2);
+        let operand = parse_supports_operand(chars)?;
+        return Some(SupportsCondition::Not(Box::new(operand)));
+    }
+
+    let mut operands = vec![parse_supports_operand(chars)?];
+    let mut op: Option<String> = None;
+
+    loop {
+        skip_whitespace(chars);
+        let word: String = chars.clone().take_while(|c| c.is_alphabetic()).collect();
+        if word != "and" && word != "or" {
+            break;
+        }
+        if op.as_ref().is_some_and(|op| *op != word) {
+            return None;
+        }
+        chars.nth(word.len() - 1);
+        operands.push(parse_supports_operand(chars)?);
+        op = Some(word);
+    }
+
+    Some(match op.as_deref() {
+        None => operands.pop()?,
+        Some("and") => SupportsCondition::And(operands),
+        _ => SupportsCondition::Or(operands),
+    })
+}
+
+// `(prop: value)` or a parenthesised condition.
+fn parse_supports_operand(chars: &mut Peekable<Chars>) -> Option<SupportsCondition> {
+    skip_whitespace(chars);
+    if chars.next()? != '(' {
+        return None;
+    }
+    skip_whitespace(chars);
+
+    let is_declaration = chars
+        .clone()
+        .take_while(|c| *c != '(' && *c != ')')
+        .any(|c| c == ':');
+    if !is_declaration {
+        let inner = parse_supports_expr(chars)?;
+        skip_whitespace(chars);
+        return (chars.next()? == ')').then_some(inner);
+    }
+
+    let mut text = String::new();
+    let mut depth = 0usize;
+    loop {
+        match chars.next()? {
+            ')' if depth == 0 => break,
+            c => {
+                match c {
+                    '(' => depth += 1,
+                    ')' => depth -= 1,
+                    _ => {}
+                }
+                text.push(c);
+            }
+        }
+    }
+
+    let (property, value) = text.split_once(':')?;
+    let (property, value) = (property.trim(), value.trim());
+    if property.is_empty() || value.is_empty() {
+        return None;
+    }
+
+    Some(SupportsCondition::Declaration(
+        property.to_string(),
+        value.to_string(),
+    ))
+}
+
+fn skip_whitespace(chars: &mut Peekable<Chars>) {
+    while chars.next_if(|c| c.is_whitespace()).is_some() {}
+}
+
+// Splits a trailing `!important` flag off a value. The `!` is required, so a
+// bare `important` stays a value, and a quoted `"!important"` never matches
+// since the value would end with the quote.
+fn strip_important(val: &str) -> (&str, bool) {
+    let keyword_at = val.len().saturating_sub("important".len());
+    match val
+        .get(keyword_at..)
+        .filter(|keyword| keyword.eq_ignore_ascii_case("important"))
+        .map(|_| val[..keyword_at].trim_end())
+        .and_then(|rest| rest.strip_suffix('!'))
+    {
+        Some(rest) => (rest.trim_end(), true),
+        None => (val, false),
+    }
+}
+
+fn translate_color(color: &str) -> Color {
+    parse_color(color).unwrap_or(Color::new(0.0, 0.0, 0.0, 1.0))
+}
+
+fn parse_color(color: &str) -> Option<Color> {
+    // Все цвета: https://colorscheme.ru/html-colors.html
+    // TODO: Дописать все цвета. Сюда напишу основные.
+    // TODO: Дописать все форматы. Пока будут только текстовые идентификаторы.
+
+    // занятие блять на недельку другую
+
+    if let Some(hex) = color.strip_prefix('#') {
+        return parse_hex_color(hex);
+    }
+
+    if let Some(args) = color
+        .strip_prefix("rgba(")
+        .or_else(|| color.strip_prefix("rgb("))
+        .and_then(|rest| rest.strip_suffix(')'))
+    {
+        return parse_rgb_function(args);
+    }
+
+    match color {
+        "black" => Some(Color::new(0.0, 0.0, 0.0, 1.0)),
+        "white" => Some(Color::new(1.0, 1.0, 1.0, 1.0)),
+        "red" => Some(Color::new(1.0, 0.0, 0.0, 1.0)),
+        "green" => Some(Color::new(0.0, 1.0, 0.0, 1.0)),
+        "blue" => Some(Color::new(0.0, 0.0, 1.0, 1.0)),
+        "transparent" => Some(Color::new(0.0, 0.0, 0.0, 0.0)),
+        _ => None,
+    }
+}
+
+// #rgb, #rgba, #rrggbb and #rrggbbaa.
+fn parse_hex_color(hex: &str) -> Option<Color> {
+    if !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+        return None;
+    }
+
+    let channels: Vec<u8> = match hex.len() {
+        3 | 4 => hex
+            .chars()
+            .map(|c| c.to_digit(16).map(|d| (d * 17) as u8))
+            .collect::<Option<_>>()?,
+        6 | 8 => (0..hex.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).ok())
+            .collect::<Option<_>>()?,
+        _ => return None,
+    };
+
+    let channel = |i: usize| channels.get(i).map_or(1.0, |&c| c as f32 / 255.0);
+    Some(Color::new(channel(0), channel(1), channel(2), channel(3)))
+}
+
+// The arguments of rgb()/rgba(). Commas mean the legacy syntax
+// (`50%, 50%, 50%, 0.5`); otherwise it's the modern one, with
+// space-separated channels and an optional `/ alpha`.
+fn parse_rgb_function(args: &str) -> Option<Color> {
+    let (channels, alpha): (Vec<&str>, Option<&str>) = if args.contains(',') {
+        let mut parts: Vec<&str> = args.split(',').map(str::trim).collect();
+        let alpha = if parts.len() == 4 { parts.pop() } else { None };
+        (parts, alpha)
+    } else {
+        let (channels, alpha) = match args.split_once('/') {
+            Some((channels, alpha)) => (channels, Some(alpha.trim())),
+            None => (args, None),
+        };
+        (channels.split_whitespace().collect(), alpha)
+    };
+
+    if channels.len() != 3 {
+        return None;
+    }
+
+    let channel = |c: &str| parse_color_component(c, 255.0);
+    let a = match alpha {
+        Some(a) => parse_color_component(a, 1.0)?,
+        None => 1.0,
+    };
+
+    Some(Color::new(
+        channel(channels[0])?,
+        channel(channels[1])?,
+        channel(channels[2])?,
+        a,
+    ))
+}
+
+// A number out of `max` or a percentage, as a 0.0..=1.0 fraction.
+fn parse_color_component(component: &str, max: f32) -> Option<f32> {
+    let value = match component.strip_suffix('%') {
+        Some(percent) => parse_finite(percent)? / 100.0,
+        None => parse_finite(component)? / max,
+    };
+
+    value.is_finite().then(|| value.clamp(0.0, 1.0))
+}
+
+// `none`, or a list of calls like `translate(10px, 20px) rotate(45deg)`.
+// Anything that isn't entirely function calls is kept as raw text.
+fn translate_function_list(val: String) -> Value {
+    if val == "none" {
+        return Value::Keyword(val);
+    }
+
+    let functions: Option<Vec<CssFunction>> = split_components(&val)
+        .iter()
+        .map(|component| parse_function(component))
+        .collect();
+
+    match functions {
+        Some(functions) => Value::FunctionList(functions),
+        None => Value::Other(val),
+    }
+}
+
+// `name(arg, arg)`, with the parenthesis opened after the name closing at
+// the very end. Commas nested in inner parentheses stay in their argument.
+fn parse_function(component: &str) -> Option<CssFunction> {
+    let (name, rest) = component.split_once('(')?;
+    let inner = rest.strip_suffix(')')?;
+    if name.is_empty() || !name.chars().all(is_valid_ident) {
+        return None;
+    }
+
+    let mut args = Vec::new();
+    let mut current = String::new();
+    let mut depth = 0usize;
+
+    for c in inner.chars() {
+        match c {
+            '(' => depth += 1,
+            ')' => depth = depth.checked_sub(1)?,
+            ',' if depth == 0 => {
+                args.push(current.trim().to_string());
+                current.clear();
+                continue;
+            }
+            _ => {}
+        }
+        current.push(c);
+    }
+
+    if depth != 0 {
+        return None;
+    }
+    if !current.trim().is_empty() || !args.is_empty() {
+        args.push(current.trim().to_string());
+    }
+
+    Some(CssFunction {
+        name: name.to_string(),
+        args,
+    })
+}
+
+// Keyword-valued properties take a single identifier; anything else
+// (`url(...)`, several words) is kept as raw text.
+fn translate_keyword(val: String) -> Value {
+    if !val.is_empty() && val.chars().all(is_valid_ident) {
+        Value::Keyword(val)
+    } else {
+        Value::Other(val)
+    }
+}
+
+// Properties taking a plain number, with keywords like `bold` or `auto`
+// allowed in its place.
+fn translate_number(val: String) -> Value {
+    match val.parse::<f32>() {
+        Ok(n) if n.is_finite() => Value::Number(n),
+        Ok(_) => Value::Other(val),
+        Err(_) => translate_keyword(val),
+    }
+}
+
+// `16 / 9`, or a lone number standing for `n / 1`. Both sides must be
+// non-negative; `auto` is kept as a keyword.
+fn translate_ratio(val: String) -> Value {
+    let parse_side = |side: &str| parse_finite(side.trim()).filter(|n| *n >= 0.0);
+
+    match val.split_once('/') {
+        Some((width, height)) => match (parse_side(width), parse_side(height)) {
+            (Some(width), Some(height)) => Value::Ratio(width, height),
+            _ => Value::Other(val),
+        },
+        None => match parse_side(&val) {
+            Some(n) => Value::Number(n),
+            None => translate_keyword(val),
+        },
+    }
+}
+
+// Like `translate_number`, for properties that take only whole numbers.
+fn translate_integer(val: String) -> Value {
+    match val.parse() {
+        Ok(n) => Value::Integer(n),
+        Err(_) => translate_keyword(val),
+    }
+}
+
+// `str::parse` also reads `inf`, `nan` and `infinity`, and turns digit
+// strings too long for an f32 into infinity. CSS has no such numbers.
+fn parse_finite(num: &str) -> Option<f32> {
+    num.parse::<f32>().ok().filter(|n| n.is_finite())
+}
+
+// Unknown units fall back to px unless `strict` is set, in which case the
+// raw text is kept. Strict mode also requires a unit on non-zero numbers.
+fn translate_length(length: &str, strict: bool) -> Value {
+    if let Some(expr) = length
+        .strip_prefix("calc(")
+        .and_then(|rest| rest.strip_suffix(')'))
+    {
+        return match translate_calc(expr) {
+            Some(node) => Value::Calc(node),
+            None => Value::Other(length.to_string()),
+        };
+    }
+
+    let (num_str, unit) = length.split_at(numeric_prefix_len(length));
+
+    // `auto` and other keywords aren't lengths at all.
+    if num_str.is_empty() && !unit.is_empty() && unit.chars().all(is_valid_ident) {
+        return Value::Keyword(unit.to_string());
+    }
+
+    // No number to read, as in `..5px`: keep the text instead of guessing 0.
+    let num: f32 = match parse_finite(num_str) {
+        Some(num) => num,
+        None => return Value::Other(length.to_string()),
+    };
+
+    match translate_unit(unit) {
+        Some(unit) => Value::Length(num, unit),
+        None if !strict => Value::Length(num, Unit::Px),
+        None if unit.is_empty() && !num_str.is_empty() && num == 0.0 => {
+            Value::Length(num, Unit::Px)
+        }
+        None => Value::Other(length.to_string()),
+    }
+}
+
+// Length in bytes of the number `val` starts with: an optional sign,
+// digits, then a `.` only if digits follow it. Scanning stops there, so
+// `1.2.3px` reads as `1.2` followed by the unit `.3px`.
+fn numeric_prefix_len(val: &str) -> usize {
+    let bytes = val.as_bytes();
+    let digits_from = |i: usize| bytes[i..].iter().take_while(|b| b.is_ascii_digit()).count();
+
+    let mut end = usize::from(matches!(bytes.first(), Some(b'+' | b'-')));
+    let int_digits = digits_from(end);
+    end += int_digits;
+
+    if bytes.get(end) == Some(&b'.') {
+        let frac_digits = digits_from(end + 1);
+        if frac_digits > 0 {
+            end += 1 + frac_digits;
+        }
+    }
+
+    if int_digits == 0 && end <= 1 {
+        return 0;
+    }
+    end
+}
+
+// An angle such as `45deg` or `0.5turn`, kept in its own unit; use
+// `Unit::to_degrees` to compare them. A bare `0` is taken as `0deg`.
+fn translate_angle(angle: &str) -> Value {
+    let (num_str, unit) = angle.split_at(numeric_prefix_len(angle));
+
+    if num_str.is_empty() && !unit.is_empty() && unit.chars().all(is_valid_ident) {
+        return Value::Keyword(unit.to_string());
+    }
+
+    let num: f32 = match parse_finite(num_str) {
+        Some(num) => num,
+        None => return Value::Other(angle.to_string()),
+    };
+
+    match translate_angle_unit(unit) {
+        Some(unit) => Value::Length(num, unit),
+        None if unit.is_empty() && num == 0.0 => Value::Length(num, Unit::Deg),
+        None => Value::Other(angle.to_string()),
+    }
+}
+
+fn translate_angle_unit(unit: &str) -> Option<Unit> {
+    match unit {
+        "deg" => Some(Unit::Deg),
+        "rad" => Some(Unit::Rad),
+        "grad" => Some(Unit::Grad),
+        "turn" => Some(Unit::Turn),
+        _ => None,
+    }
+}
+
+fn translate_unit(unit: &str) -> Option<Unit> {
+    match unit {
+        "px" => Some(Unit::Px),
+        "em" => Some(Unit::Em),
+        "rem" => Some(Unit::Rem),
+        "vh" => Some(Unit::Vh),
+        "vw" => Some(Unit::Vw),
+        "vmin" => Some(Unit::Vmin),
+        "vmax" => Some(Unit::Vmax),
+        "%" => Some(Unit::Percent),
+        _ => None,
+    }
+}
+
+enum CalcToken {
+    Operand(CalcNode),
+    Op(char),
+    Open,
+    Close,
+}
+
+type CalcTokens = Peekable<std::vec::IntoIter<CalcToken>>;
+
+// Parses the inside of `calc(...)`. Usual precedence: `*` and `/` bind
+// tighter than `+` and `-`, parentheses group.
+fn translate_calc(expr: &str) -> Option<CalcNode> {
+    let mut tokens = tokenize_calc(expr)?.into_iter().peekable();
+    let node = parse_calc_sum(&mut tokens)?;
+
+    match tokens.next() {
+        None => Some(node),
+        Some(_) => None,
+    }
+}
+
+fn tokenize_calc(expr: &str) -> Option<Vec<CalcToken>> {
+    let mut tokens = Vec::new();
+    let mut chars = expr.chars().peekable();
+
+    while let Some(&c) = chars.peek() {
+        let expects_operand = matches!(
+            tokens.last(),
+            None | Some(CalcToken::Op(_)) | Some(CalcToken::Open)
+        );
+
+        match c {
+            _ if c.is_whitespace() => {
+                chars.next();
+            }
+            '(' => {
+                chars.next();
+                tokens.push(CalcToken::Open);
+            }
+            ')' => {
+                chars.next();
+                tokens.push(CalcToken::Close);
+            }
+            '*' | '/' => {
+                chars.next();
+                tokens.push(CalcToken::Op(c));
+            }
+            '+' | '-' if !expects_operand => {
+                chars.next();
+                tokens.push(CalcToken::Op(c));
+            }
+            _ if c.is_ascii_alphabetic() => {
+                // Nested `calc(` is just a parenthesized group.
+                let mut name = String::new();
+                while let Some(&c) = chars.peek().filter(|c| c.is_ascii_alphabetic()) {
+                    name.push(c);
+                    chars.next();
+                }
+                if name != "calc" || chars.peek() != Some(&'(') {
+                    return None;
+                }
+            }
+            _ => {
+                let mut num_str = String::new();
+                if c == '+' || c == '-' {
+                    num_str.push(c);
+                    chars.next();
+                }
+                while let Some(&c) = chars.peek().filter(|c| c.is_ascii_digit() || **c == '.') {
+                    num_str.push(c);
+                    chars.next();
+                }
+
+                let mut unit = String::new();
+                while let Some(&c) = chars.peek().filter(|c| c.is_alphabetic() || **c == '%') {
+                    unit.push(c);
+                    chars.next();
+                }
+
+                let num: f32 = parse_finite(&num_str)?;
+                let operand = if unit.is_empty() {
+                    CalcNode::Number(num)
+                } else {
+                    CalcNode::Length(num, translate_unit(&unit)?)
+                };
+                tokens.push(CalcToken::Operand(operand));
+            }
+        }
+    }
+
+    Some(tokens)
+}
+
+fn parse_calc_sum(tokens: &mut CalcTokens) -> Option<CalcNode> {
+    let mut node = parse_calc_product(tokens)?;
+
+    while let Some(&CalcToken::Op(op @ ('+' | '-'))) = tokens.peek() {
+        tokens.next();
+        let rhs = Box::new(parse_calc_product(tokens)?);
+        node = match op {
+            '+' => CalcNode::Add(Box::new(node), rhs),
+            _ => CalcNode::Sub(Box::new(node), rhs),
+        };
+    }
+
+    Some(node)
+}
+
+fn parse_calc_product(tokens: &mut CalcTokens) -> Option<CalcNode> {
+    let mut node = parse_calc_operand(tokens)?;
+
+    while let Some(&CalcToken::Op(op @ ('*' | '/'))) = tokens.peek() {
+        tokens.next();
+        let rhs = Box::new(parse_calc_operand(tokens)?);
+        node = match op {
+            '*' => CalcNode::Mul(Box::new(node), rhs),
+            _ => CalcNode::Div(Box::new(node), rhs),
+        };
+    }
+
+    Some(node)
+}
+
+fn parse_calc_operand(tokens: &mut CalcTokens) -> Option<CalcNode> {
+    match tokens.next()? {
+        CalcToken::Open => {
+            let node = parse_calc_sum(tokens)?;
+            match tokens.next()? {
+                CalcToken::Close => Some(node),
+                _ => None,
+            }
+        }
+        CalcToken::Operand(operand) => Some(operand),
+        _ => None,
+    }
+}
+
+fn is_valid_ident(c: char) -> bool {
+    is_valid_start_ident(c) || c.is_ascii_digit() || c == '-'
+}
+
+// Whether `chars` begins with an identifier: a name-start character or an
+// escape, optionally after one `-`, or `--`.
+fn starts_identifier(mut chars: Peekable<Chars>) -> bool {
+    match chars.peek() {
+        Some('-') => {
+            chars.next();
+            match chars.peek() {
+                Some(&c) if is_valid_start_ident(c) || c == '-' => true,
+                Some('\\') => is_valid_escape(chars),
+                _ => false,
+            }
+        }
+        Some('\\') => is_valid_escape(chars),
+        Some(&c) => is_valid_start_ident(c),
+        None => false,
+    }
+}
+
+// A backslash not followed by a newline or the end of input.
+fn is_valid_escape(mut chars: Peekable<Chars>) -> bool {
+    chars.next() == Some('\\')
+        && chars
+            .next()
+            .is_some_and(|c| !matches!(c, '\n' | '\r' | '\x0c'))
+}
+
+fn is_valid_start_ident(c: char) -> bool {
+    is_letter(c) || is_non_ascii(c) || c == '_'
+}
+
+fn is_letter(c: char) -> bool {
+    is_upper_letter(c) || is_lower_letter(c)
+}
+
+fn is_upper_letter(c: char) -> bool {
+    c.is_ascii_uppercase()
+}
+
+fn is_lower_letter(c: char) -> bool {
+    c.is_ascii_lowercase()
+}
+
+fn is_non_ascii(c: char) -> bool {
+    c >= '\u{0080}'
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::css::LengthContext;
+    use crate::html_parser::HtmlParser;
+
+    fn declarations(css: &str) -> Vec<Declaration> {
+        let mut stylesheet = CssParser::new(css).parse_stylesheet();
+        assert!(
+            !stylesheet.rules.is_empty(),
+            "no rule parsed from {:?}",
+            css
+        );
+        stylesheet.rules.remove(0).declarations
+    }
+
+    #[test]
+    fn flex_accepts_unitless_zero_basis() {
+        for (css, grow, shrink) in [
+            ("a { flex: 1 1 0 }", 1.0, 1.0),
+            ("a { flex: 0 0 0 }", 0.0, 0.0),
+        ] {
+            let decls = declarations(css);
+            let values: Vec<(&str, &Value)> = decls
+                .iter()
+                .map(|d| (d.property.as_str(), &d.value))
+                .collect();
+            assert_eq!(
+                values,
+                [
+                    ("flex-grow", &Value::Number(grow)),
+                    ("flex-shrink", &Value::Number(shrink)),
+                    ("flex-basis", &Value::Length(0.0, Unit::Px)),
+                ]
+            );
+        }
+
+        assert!(declarations("a { flex: 1 1 5 }").is_empty());
+    }
+
+    #[test]
+    fn flex_expands_into_typed_longhands() {
+        let longhands = |css: &str| -> Vec<(String, Value)> {
+            declarations(css)
+                .into_iter()
+                .map(|d| (d.property, d.value))
+                .collect()
+        };
+        let expected = |grow: f32, shrink: f32, basis: Value| {
+            vec![
+                (String::from("flex-grow"), Value::Number(grow)),
+                (String::from("flex-shrink"), Value::Number(shrink)),
+                (String::from("flex-basis"), basis),
+            ]
+        };
+        let auto = || Value::Keyword(String::from("auto"));
+
+        assert_eq!(
+            longhands("a { flex: 1 1 auto }"),
+            expected(1.0, 1.0, auto())
+        );
+        assert_eq!(
+            longhands("a { flex: 1 }"),
+            expected(1.0, 1.0, Value::Length(0.0, Unit::Percent))
+        );
+        assert_eq!(
+            longhands("a { flex: 2 10em }"),
+            expected(2.0, 1.0, Value::Length(10.0, Unit::Em))
+        );
+        assert_eq!(longhands("a { flex: none }"), expected(0.0, 0.0, auto()));
+        assert_eq!(
+            longhands("a { flex: initial }"),
+            ["flex-grow", "flex-shrink", "flex-basis"]
+                .map(|p| (String::from(p), Value::Global(CssWide::Initial)))
+        );
+    }
+
+    #[test]
+    fn unitless_line_height_is_a_number() {
+        let decls = declarations("a { line-height: 1.5 } ");
+        assert_eq!(decls[0].value, Value::Number(1.5));
+
+        let decls = declarations("a { line-height: 20px; line-height: normal; line-height: 150% }");
+        assert_eq!(decls[0].value, Value::Length(20.0, Unit::Px));
+        assert_eq!(decls[1].value, Value::Keyword(String::from("normal")));
+        assert_eq!(decls[2].value, Value::Length(150.0, Unit::Percent));
+    }
+
+    #[test]
+    fn malformed_numbers_keep_their_valid_prefix_or_their_text() {
+        let decls = declarations(
+            "a { width: 1.2.3px; height: ..5px; margin-top: -.5em; margin-left: +2px; margin-right: .5.px }",
+        );
+        assert_eq!(decls[0].value, Value::Length(1.2, Unit::Px));
+        assert_eq!(decls[1].value, Value::Other(String::from("..5px")));
+        assert_eq!(decls[2].value, Value::Length(-0.5, Unit::Em));
+        assert_eq!(decls[3].value, Value::Length(2.0, Unit::Px));
+        assert_eq!(decls[4].value, Value::Length(0.5, Unit::Px));
+
+        let strict = CssParser::new("a { width: 1.2.3px; height: 1.5px }")
+            .strict_units(true)
+            .parse_stylesheet();
+        let strict = &strict.rules[0].declarations;
+        assert_eq!(strict[0].value, Value::Other(String::from("1.2.3px")));
+        assert_eq!(strict[1].value, Value::Length(1.5, Unit::Px));
+    }
+
+    #[test]
+    fn grid_templates_expand_into_track_lists() {
+        let decls = declarations(
+            "a { grid-template-columns: 1fr 2fr; grid-template-rows: repeat(3, 100px); \
+             gap: 4px 8px }",
+        );
+        assert_eq!(
+            decls[0].value,
+            Value::List(vec![
+                Value::Length(1.0, Unit::Fr),
+                Value::Length(2.0, Unit::Fr),
+            ])
+        );
+        assert_eq!(
+            decls[1].value,
+            Value::List(vec![Value::Length(100.0, Unit::Px); 3])
+        );
+        let gaps: Vec<(&str, &Value)> = decls[2..]
+            .iter()
+            .map(|d| (d.property.as_str(), &d.value))
+            .collect();
+        assert_eq!(
+            gaps,
+            [
+                ("row-gap", &Value::Length(4.0, Unit::Px)),
+                ("column-gap", &Value::Length(8.0, Unit::Px)),
+            ]
+        );
+    }
+
+    #[test]
+    fn font_families_strip_quotes_and_recognize_generics() {
+        let name = |n: &str| FontFamily::Name(String::from(n));
+        let decls = declarations("a { font-family: \"Helvetica Neue\", Arial, sans-serif }");
+        assert_eq!(
+            decls[0].value,
+            Value::FontFamilyList(vec![
+                name("Helvetica Neue"),
+                name("Arial"),
+                FontFamily::Generic(String::from("sans-serif")),
+            ])
+        );
+
+        let decls = declarations(
+            "b { font-family: 'Semi; Colon, Inc',  Times   New Roman , \"serif\"; color: red }",
+        );
+        assert_eq!(
+            decls[0].value,
+            Value::FontFamilyList(vec![
+                name("Semi; Colon, Inc"),
+                name("Times New Roman"),
+                name("serif"),
+            ])
+        );
+        assert_eq!(decls[1].property, "color");
+    }
+
+    #[test]
+    fn crlf_and_cr_line_endings_parse_like_lf() {
+        let lf = "a {\n\tcolor: red;\n\twidth: 4px\n}\nb { content: \"x\";\n height: 1px }\n";
+        let expected = CssParser::new(lf).parse_stylesheet();
+        assert_eq!(expected.rules[0].declarations.len(), 2);
+
+        for source in [lf.replace('\n', "\r\n"), lf.replace('\n', "\r")] {
+            let stylesheet = CssParser::new(&source).parse_stylesheet();
+            assert_eq!(stylesheet.to_string(), expected.to_string(), "{:?}", source);
+            assert!(!stylesheet.to_string().contains('\r'));
+        }
+    }
+
+    #[test]
+    fn aspect_ratio_parses_slash_pairs() {
+        let decls = declarations(
+            "a { aspect-ratio: 16 / 9; aspect-ratio: 1; aspect-ratio: 4/3; aspect-ratio: auto; \
+             aspect-ratio: -1 / 2 }",
+        );
+        let values: Vec<&Value> = decls.iter().map(|d| &d.value).collect();
+        assert_eq!(
+            values,
+            [
+                &Value::Ratio(16.0, 9.0),
+                &Value::Number(1.0),
+                &Value::Ratio(4.0, 3.0),
+                &Value::Keyword(String::from("auto")),
+                &Value::Other(String::from("-1 / 2")),
+            ]
+        );
+    }
+
+    #[test]
+    fn leading_charset_is_recorded() {
+        let stylesheet = CssParser::new("@charset \"UTF-8\";\np { color: red }").parse_stylesheet();
+        assert_eq!(stylesheet.charset.as_deref(), Some("UTF-8"));
+        assert_eq!(stylesheet.rules.len(), 1);
+        assert_eq!(stylesheet.rules[0].selectors[0].to_string(), "p");
+        assert!(stylesheet.to_string().starts_with("@charset \"UTF-8\";\n"));
+
+        // Only the very first bytes count; elsewhere it's an ignored at-rule.
+        for css in [
+            " @charset \"UTF-8\"; p { color: red }",
+            "p { color: red } @charset \"UTF-8\";",
+            "@charset 'UTF-8'; p { color: red }",
+        ] {
+            let stylesheet = CssParser::new(css).parse_stylesheet();
+            assert_eq!(stylesheet.charset, None, "{}", css);
+            assert_eq!(stylesheet.rules.len(), 1, "{}", css);
+        }
+    }
+
+    #[test]
+    fn box_shadow_layers_split_outside_functions() {
+        let px = |n: f32| Value::Length(n, Unit::Px);
+        let shadow = |inset, lengths: [f32; 4], color| Shadow {
+            inset,
+            offset_x: px(lengths[0]),
+            offset_y: px(lengths[1]),
+            blur: px(lengths[2]),
+            spread: px(lengths[3]),
+            color,
+        };
+        let decls = declarations(
+            "a { box-shadow: 2px 3px; box-shadow: 0 1px 2px rgba(0, 0, 0, .5); \
+             box-shadow: 0 1px 2px rgba(0,0,0,.5), inset 0 0 4px 1px red; box-shadow: 1px red 2px }",
+        );
+        let translucent = Color::new(0.0, 0.0, 0.0, 0.5);
+        let red = Color::new(1.0, 0.0, 0.0, 1.0);
+
+        assert_eq!(
+            decls[0].value,
+            Value::ShadowList(vec![shadow(false, [2.0, 3.0, 0.0, 0.0], None)])
+        );
+        assert_eq!(
+            decls[1].value,
+            Value::ShadowList(vec![shadow(false, [0.0, 1.0, 2.0, 0.0], Some(translucent))])
+        );
+        assert_eq!(
+            decls[2].value,
+            Value::ShadowList(vec![
+                shadow(false, [0.0, 1.0, 2.0, 0.0], Some(translucent)),
+                shadow(true, [0.0, 0.0, 4.0, 1.0], Some(red)),
+            ])
+        );
+        assert_eq!(decls[3].value, Value::Other(String::from("1px red 2px")));
+    }
+
+    #[test]
+    fn grid_tracks_reject_non_finite_and_oversized_lists() {
+        let decls = declarations("a { grid-template-columns: 1e39fr nanfr 2fr }");
+        assert_eq!(
+            decls[0].value,
+            Value::List(vec![
+                Value::Other(String::from("1e39fr")),
+                Value::Other(String::from("nanfr")),
+                Value::Length(2.0, Unit::Fr),
+            ])
+        );
+
+        let many = vec!["1px"; 100].join(" ");
+        let css = format!(
+            "a {{ grid-template-rows: repeat(50, {0}) repeat(60, {0}) repeat(1, 1fr) }}",
+            many
+        );
+        let tracks = match declarations(&css).remove(0).value {
+            Value::List(tracks) => tracks,
+            other => panic!("expected a track list, got {:?}", other),
+        };
+        assert_eq!(tracks.len(), 5_000 + 1 + 1);
+        assert!(matches!(tracks[5_000], Value::Other(ref t) if t.starts_with("repeat(60,")));
+        assert_eq!(tracks[5_001], Value::Length(1.0, Unit::Fr));
+
+        let css = format!("a {{ grid-template-rows: repeat(10000, {}) }}", many);
+        assert!(matches!(
+            declarations(&css)[0].value,
+            Value::List(ref tracks) if tracks.len() == 1
+        ));
+    }
+
+    #[test]
+    fn unterminated_string_drops_only_its_declaration() {
+        let stylesheet = CssParser::new(
+            "a { content: \"oops; color: red }\nb { color: blue }\np { margin-top: 0; content: 'x\\\ny' }",
+        )
+        .parse_stylesheet();
+        let rules: Vec<(String, Vec<&str>)> = stylesheet
+            .rules
+            .iter()
+            .map(|rule| {
+                let properties = rule.declarations.iter().map(|d| d.property.as_str());
+                (rule.selectors[0].to_string(), properties.collect())
+            })
+            .collect();
+        assert_eq!(
+            rules,
+            [
+                (String::from("a"), vec!["color"]),
+                (String::from("b"), vec!["color"]),
+                (String::from("p"), vec!["margin-top", "content"]),
+            ]
+        );
+
+        // A string closed later on the same line, or by the end of input, is fine.
+        let decls = declarations("a { content: \"a;b\"; color: red; content: 'open");
+        assert_eq!(decls.len(), 3);
+    }
+
+    #[test]
+    fn non_finite_numbers_are_invalid() {
+        let css = format!(
+            "a {{ opacity: nan; line-height: inf; flex-grow: infinity; width: 1{}px; aspect-ratio: inf / 1 }}",
+            "0".repeat(40)
+        );
+        let decls = declarations(&css);
+        assert_eq!(decls.len(), 5);
+        for decl in &decls {
+            assert!(
+                matches!(decl.value, Value::Other(_)),
+                "{} kept as {:?}",
+                decl.property,
+                decl.value
+            );
+        }
+
+        assert!(declarations("a { flex: infinity }").is_empty());
+    }
+
+    #[test]
+    fn longhands_of_shorthands_are_known() {
+        let samples = [
+            ("background", "url(a.png) no-repeat left red"),
+            ("margin", "1px"),
+            ("padding", "1px"),
+            ("border-width", "1px"),
+            ("border", "1px solid red"),
+            ("flex", "1 1 auto"),
+            ("gap", "1px"),
+            ("grid-gap", "1px"),
+        ];
+        assert_eq!(samples.len(), SHORTHANDS.len());
+
+        for (shorthand, val) in samples {
+            assert!(is_known_property(shorthand));
+            let expanded = expand_declaration(shorthand.to_string(), val.to_string(), false);
+            assert!(!expanded.is_empty(), "{} didn't expand", shorthand);
+            for declaration in expanded {
+                assert!(
+                    is_known_property(&declaration.property),
+                    "{} from {} is unknown",
+                    declaration.property,
+                    shorthand
+                );
+            }
+        }
+
+        let mut unknown = Vec::new();
+        CssParser::new("a { background-image: none; background-repeat: repeat-x; colr: red }")
+            .on_unknown_property(|property, _| unknown.push(property.to_string()))
+            .parse_stylesheet();
+        assert_eq!(unknown, ["colr"]);
+
+        let mut reported = Vec::new();
+        CssParser::new("a { Foo-Bar: SomeValue !important; empty: ; bad: \"open\n; good: 'A;b' }")
+            .on_unknown_property(|property, value| {
+                reported.push((property.to_string(), value.to_string()))
+            })
+            .parse_stylesheet();
+        assert_eq!(
+            reported,
+            [
+                (
+                    String::from("foo-bar"),
+                    String::from("SomeValue !important")
+                ),
+                (String::from("good"), String::from("'A;b'")),
+            ]
+        );
+    }
+
+    #[test]
+    fn vendor_prefixed_properties_keep_their_names_and_neighbours() {
+        let decls = declarations(
+            "a { color: red; -webkit-transform: rotate(4deg); -MOZ-Box-Sizing : border-box; width: 4px }",
+        );
+        let properties: Vec<&str> = decls.iter().map(|d| d.property.as_str()).collect();
+        assert_eq!(
+            properties,
+            ["color", "-webkit-transform", "-moz-box-sizing", "width"]
+        );
+        assert_eq!(decls[0].value, Value::Color(Color::new(1.0, 0.0, 0.0, 1.0)));
+        assert_eq!(decls[1].value, Value::Other(String::from("rotate(4deg)")));
+        assert_eq!(decls[2].value, Value::Other(String::from("border-box")));
+        assert_eq!(decls[3].value, Value::Length(4.0, Unit::Px));
+    }
+
+    #[test]
+    fn declarations_are_trimmed_around_colons_and_values() {
+        let decls = declarations("a {   color :  red  ; width\t:\t4px\t;margin-top:1px ;  }");
+        let properties: Vec<&str> = decls.iter().map(|d| d.property.as_str()).collect();
+        assert_eq!(properties, ["color", "width", "margin-top"]);
+        assert_eq!(decls[0].value, Value::Color(Color::new(1.0, 0.0, 0.0, 1.0)));
+        assert_eq!(decls[1].value, Value::Length(4.0, Unit::Px));
+        assert_eq!(decls[2].value, Value::Length(1.0, Unit::Px));
+
+        let decls = declarations("a { font-family :   Arial  ; content:  \"  x  \"  }");
+        assert_eq!(
+            decls[0].value,
+            Value::FontFamilyList(vec![FontFamily::Name(String::from("Arial"))])
+        );
+        assert_eq!(decls[1].value, Value::Other(String::from("\"  x  \"")));
+    }
+
+    #[test]
+    fn strict_units_keep_unknown_units_raw() {
+        let css = "a { width: 10ptx; height: 4px; margin: 1qq 2px }";
+
+        let lenient = CssParser::new(css).parse_stylesheet();
+        let lenient = &lenient.rules[0].declarations;
+        assert_eq!(lenient[0].value, Value::Length(10.0, Unit::Px));
+        assert_eq!(lenient[1].value, Value::Length(4.0, Unit::Px));
+        assert_eq!(lenient[2].value, Value::Length(1.0, Unit::Px));
+
+        let strict = CssParser::new(css).strict_units(true).parse_stylesheet();
+        let strict = &strict.rules[0].declarations;
+        assert_eq!(strict[0].value, Value::Other(String::from("10ptx")));
+        assert_eq!(strict[1].value, Value::Length(4.0, Unit::Px));
+        assert_eq!(strict[2].value, Value::Other(String::from("1qq")));
+        assert_eq!(strict[3].value, Value::Length(2.0, Unit::Px));
+    }
+
+    #[test]
+    fn keyframes_keep_their_offsets_and_declarations() {
+        let stylesheet = CssParser::new(
+            "a { color: red } @keyframes spin { from { width: 0px } 50%, 75% { width: 5px; opacity: 0.5 } to { width: 10px } } p { width: 1px }",
+        )
+        .parse_stylesheet();
+
+        assert_eq!(stylesheet.rules.len(), 2);
+        assert_eq!(stylesheet.keyframes.len(), 1);
+        let spin = &stylesheet.keyframes[0];
+        assert_eq!(spin.name, "spin");
+
+        let offsets: Vec<&[f32]> = spin.frames.iter().map(|f| f.offsets.as_slice()).collect();
+        assert_eq!(offsets, [&[0.0][..], &[50.0, 75.0], &[100.0]]);
+        assert_eq!(spin.frames[1].declarations.len(), 2);
+        assert_eq!(spin.frames[1].declarations[1].value, Value::Number(0.5));
+        assert_eq!(
+            spin.frames[2].declarations[0].value,
+            Value::Length(10.0, Unit::Px)
+        );
+    }
+
+    fn color(css: &str) -> Color {
+        match declarations(&format!("a {{ color: {} }}", css))
+            .remove(0)
+            .value
+        {
+            Value::Color(color) => color,
+            other => panic!("{} gave {:?}", css, other),
+        }
+    }
+
+    #[test]
+    fn rgb_accepts_legacy_and_modern_syntax() {
+        assert_eq!(color("rgb(50%, 50%, 50%)"), Color::new(0.5, 0.5, 0.5, 1.0));
+        assert_eq!(
+            color("rgba(255, 0, 0, 0.25)"),
+            Color::new(1.0, 0.0, 0.0, 0.25)
+        );
+        assert_eq!(
+            color("rgba(50% 50% 50% / 0.5)"),
+            Color::new(0.5, 0.5, 0.5, 0.5)
+        );
+        assert_eq!(color("rgb(255 0 0 / 25%)"), Color::new(1.0, 0.0, 0.0, 0.25));
+        assert_eq!(color("rgb(0 0 0)"), Color::new(0.0, 0.0, 0.0, 1.0));
+
+        for invalid in [
+            "rgb(1, 2 3)",
+            "rgb(1, 2, 3 / 1)",
+            "rgb(1 2)",
+            "rgb(1 2 3 4)",
+        ] {
+            assert!(parse_color(invalid).is_none(), "{} parsed", invalid);
+        }
+    }
+
+    #[test]
+    fn max_len_rejects_only_longer_input() {
+        let css = "a { color: red }";
+        assert_eq!(
+            CssParser::with_max_len(css, css.len() - 1).err(),
+            Some(CssParseError::InputTooLarge {
+                len: css.len(),
+                limit: css.len() - 1
+            })
+        );
+
+        let stylesheet = CssParser::with_max_len(css, css.len())
+            .unwrap()
+            .parse_stylesheet();
+        assert_eq!(stylesheet.rules.len(), 1);
+    }
+
+    #[test]
+    fn important_is_only_a_trailing_flag() {
+        let decls = declarations(
+            r#"a { color: red!important; width: 4px ! IMPORTANT; content: "!important"; font-style: important; quotes: "a" !important "b" }"#,
+        );
+        let flags: Vec<(&str, bool)> = decls
+            .iter()
+            .map(|d| (d.property.as_str(), d.important))
+            .collect();
+        assert_eq!(
+            flags,
+            [
+                ("color", true),
+                ("width", true),
+                ("content", false),
+                ("font-style", false),
+                ("quotes", false),
+            ]
+        );
+
+        assert_eq!(decls[0].value, Value::Color(Color::new(1.0, 0.0, 0.0, 1.0)));
+        assert_eq!(decls[1].value, Value::Length(4.0, Unit::Px));
+        assert_eq!(decls[2].value, Value::Other(String::from("\"!important\"")));
+        assert_eq!(decls[3].value, Value::Keyword(String::from("important")));
+    }
+
+    #[test]
+    fn shorthand_sides_keep_their_own_units() {
+        let decls = declarations("a { padding: 1em 2px 3rem 4%; margin: 1em 2rem }");
+        let values: Vec<(&str, &Value)> = decls
+            .iter()
+            .map(|d| (d.property.as_str(), &d.value))
+            .collect();
+        assert_eq!(
+            values,
+            [
+                ("padding-top", &Value::Length(1.0, Unit::Em)),
+                ("padding-right", &Value::Length(2.0, Unit::Px)),
+                ("padding-bottom", &Value::Length(3.0, Unit::Rem)),
+                ("padding-left", &Value::Length(4.0, Unit::Percent)),
+                ("margin-top", &Value::Length(1.0, Unit::Em)),
+                ("margin-right", &Value::Length(2.0, Unit::Rem)),
+                ("margin-bottom", &Value::Length(1.0, Unit::Em)),
+                ("margin-left", &Value::Length(2.0, Unit::Rem)),
+            ]
+        );
+
+        let border = declarations("a { border-width: 1em 2rem 3px }");
+        assert_eq!(border[1].value, Value::Length(2.0, Unit::Rem));
+        assert_eq!(border[3].value, Value::Length(2.0, Unit::Rem));
+    }
+
+    #[test]
+    fn supports_rules_keep_condition_and_inner_rules() {
+        let stylesheet = CssParser::new(
+            "a { color: red } @supports (display: grid) and (not (display: inline-grid)) { .grid { display: grid } p { width: 1px } } b { width: 2px }",
+        )
+        .parse_stylesheet();
+
+        assert_eq!(stylesheet.rules.len(), 2);
+        assert_eq!(stylesheet.supports.len(), 1);
+        let supports = &stylesheet.supports[0];
+
+        let declaration =
+            |p: &str, v: &str| SupportsCondition::Declaration(p.to_string(), v.to_string());
+        assert!(
+            supports.condition
+                == SupportsCondition::And(vec![
+                    declaration("display", "grid"),
+                    SupportsCondition::Not(Box::new(declaration("display", "inline-grid"))),
+                ])
+        );
+        assert_eq!(
+            supports.condition.to_string(),
+            "(display: grid) and (not (display: inline-grid))"
+        );
+        assert_eq!(supports.rules.len(), 2);
+        assert_eq!(supports.rules[0].selectors[0].to_string(), ".grid");
+
+        assert!(supports.condition.evaluate(&|_, value| value == "grid"));
+        assert!(!supports.condition.evaluate(&|_, _| true));
+
+        let or = CssParser::new("@supports (gap: 1px) or (grid-gap: 1px) { }").parse_stylesheet();
+        assert!(matches!(or.supports[0].condition, SupportsCondition::Or(ref c) if c.len() == 2));
+    }
+
+    #[test]
+    fn selector_lists_and_declaration_blocks_parse_on_their_own() {
+        let selectors = CssParser::new("h1, .x").parse_selector_list().unwrap();
+        let selectors: Vec<String> = selectors.iter().map(Selector::to_string).collect();
+        assert_eq!(selectors, ["h1", ".x"]);
+        assert_eq!(
+            CssParser::new("h1, ").parse_selector_list(),
+            Err(CssParseError::InvalidSelector)
+        );
+        assert_eq!(
+            CssParser::new("h1 { color: red }").parse_selector_list(),
+            Err(CssParseError::InvalidSelector)
+        );
+
+        let decls = CssParser::new("color: red; margin: 4px").parse_declaration_block();
+        let values: Vec<(&str, &Value)> = decls
+            .iter()
+            .map(|d| (d.property.as_str(), &d.value))
+            .collect();
+        assert_eq!(
+            values,
+            [
+                ("color", &Value::Color(Color::new(1.0, 0.0, 0.0, 1.0))),
+                ("margin-top", &Value::Length(4.0, Unit::Px)),
+                ("margin-right", &Value::Length(4.0, Unit::Px)),
+                ("margin-bottom", &Value::Length(4.0, Unit::Px)),
+                ("margin-left", &Value::Length(4.0, Unit::Px)),
+            ]
+        );
+    }
+
+    #[test]
+    fn transforms_parse_into_function_lists() {
+        let function = |name: &str, args: &[&str]| CssFunction {
+            name: name.to_string(),
+            args: args.iter().map(|a| a.to_string()).collect(),
+        };
+
+        let decls = declarations(
+            "a { transform: translate(10px, 20px) rotate(45deg); color: red; transform: rotate(45deg); transform: none }",
+        );
+        assert_eq!(
+            decls[0].value,
+            Value::FunctionList(vec![
+                function("translate", &["10px", "20px"]),
+                function("rotate", &["45deg"]),
+            ])
+        );
+        assert_eq!(decls[1].property, "color");
+        assert_eq!(
+            decls[2].value,
+            Value::FunctionList(vec![function("rotate", &["45deg"])])
+        );
+        assert_eq!(decls[3].value, Value::Keyword(String::from("none")));
+        assert_eq!(
+            decls[0].value.to_string(),
+            "translate(10px, 20px) rotate(45deg)"
+        );
+    }
+
+    #[test]
+    fn angles_keep_their_unit_and_convert_to_degrees() {
+        let decls = declarations(
+            "a { rotate: 45deg; rotate: 0.5turn; rotate: 1.5708rad; rotate: 100grad; rotate: 0; rotate: 45px }",
+        );
+        let degrees: Vec<Option<f32>> = decls
+            .iter()
+            .map(|d| match d.value {
+                Value::Length(n, unit) => unit.to_degrees(n),
+                _ => None,
+            })
+            .collect();
+
+        assert_eq!(decls[1].value, Value::Length(0.5, Unit::Turn));
+        assert_eq!(degrees[0], Some(45.0));
+        assert_eq!(degrees[1], Some(180.0));
+        assert!((degrees[2].unwrap() - 90.0).abs() < 0.01);
+        assert_eq!(degrees[3], Some(90.0));
+        assert_eq!(degrees[4], Some(0.0));
+        assert_eq!(decls[5].value, Value::Other(String::from("45px")));
+        assert_eq!(Unit::Px.to_degrees(1.0), None);
+    }
+
+    fn simple_selector(css: &str) -> SimpleSelector {
+        let mut stylesheet = CssParser::new(css).parse_stylesheet();
+        let mut selector = stylesheet.rules.remove(0).selectors.remove(0);
+        assert_eq!(selector.simple.len(), 1);
+        selector.simple.remove(0)
+    }
+
+    #[test]
+    fn escaped_identifiers_keep_the_escaped_characters() {
+        let class = simple_selector(".foo\\:bar { color: red }");
+        assert_eq!(class.classes, ["foo:bar"]);
+
+        let id = simple_selector("#\\31 23 { color: red }");
+        assert_eq!(id.id.as_deref(), Some("123"));
+
+        let hex = simple_selector(".a\\2e b\\.c { color: red }");
+        assert_eq!(hex.classes, ["a.b.c"]);
+
+        let root = HtmlParser::new("<div><p class=\"foo:bar\"></p><p id=123></p></div>")
+            .parse_nodes()
+            .remove(0);
+        assert_eq!(root.query_str(".foo\\:bar, #\\31 23").unwrap().len(), 2);
+    }
+
+    #[test]
+    fn invalid_class_or_id_gives_no_selector() {
+        let stylesheet =
+            CssParser::new(".123 { color: red } # { width: 1px } .ok, .4x { height: 1px }")
+                .parse_stylesheet();
+        assert!(stylesheet.rules[0].selectors.is_empty());
+        assert!(stylesheet.rules[1].selectors.is_empty());
+        assert_eq!(stylesheet.rules[2].selectors.len(), 1);
+        assert_eq!(stylesheet.rules[2].selectors[0].to_string(), ".ok");
+
+        for invalid in [".123", "#", "p.", "p#", ".a.", "#1a"] {
+            assert_eq!(
+                CssParser::new(invalid).parse_selector_list(),
+                Err(CssParseError::InvalidSelector),
+                "{}",
+                invalid
+            );
+        }
+        assert_eq!(simple_selector("#-x { }").id.as_deref(), Some("-x"));
+    }
+
+    #[test]
+    fn selectors_may_start_with_an_id_or_class() {
+        let main = simple_selector("#main { }");
+        assert_eq!(main.tag_name, None);
+        assert_eq!(main.id.as_deref(), Some("main"));
+        assert!(main.classes.is_empty());
+
+        let btn = simple_selector(".btn { }");
+        assert_eq!(btn.tag_name, None);
+        assert_eq!(btn.id, None);
+        assert_eq!(btn.classes, ["btn"]);
+
+        let primary = simple_selector(".btn.primary { }");
+        assert_eq!(primary.tag_name, None);
+        assert_eq!(primary.classes, ["btn", "primary"]);
+
+        let tagged = simple_selector("a#main.btn { }");
+        assert_eq!(tagged.tag_name.as_deref(), Some("a"));
+        assert_eq!(tagged.id.as_deref(), Some("main"));
+        assert_eq!(tagged.classes, ["btn"]);
+    }
+
+    #[test]
+    fn keyword_properties_give_keywords() {
+        let decls = declarations(
+            "a { text-align: center; display: flex; position: absolute; shape-outside: circle }",
+        );
+        assert_eq!(decls[0].value, Value::Keyword(String::from("center")));
+        assert_eq!(decls[1].value, Value::Keyword(String::from("flex")));
+        assert_eq!(decls[2].value, Value::Keyword(String::from("absolute")));
+        assert_eq!(decls[3].value, Value::Other(String::from("circle")));
+    }
+
+    fn background(val: &str) -> [Value; 4] {
+        let decls = declarations(&format!("a {{ background: {} }}", val));
+        let properties: Vec<&str> = decls.iter().map(|d| d.property.as_str()).collect();
+        assert_eq!(
+            properties,
+            [
+                "background-color",
+                "background-image",
+                "background-repeat",
+                "background-position"
+            ]
+        );
+        let mut values = decls.into_iter().map(|d| d.value);
+        std::array::from_fn(|_| values.next().unwrap())
+    }
+
+    #[test]
+    fn background_expands_to_longhands() {
+        let keyword = |k: &str| Value::Keyword(String::from(k));
+        let transparent = Value::Color(Color::new(0.0, 0.0, 0.0, 0.0));
+
+        assert_eq!(
+            background("#fff"),
+            [
+                Value::Color(Color::new(1.0, 1.0, 1.0, 1.0)),
+                keyword("none"),
+                keyword("repeat"),
+                Value::Other(String::from("0% 0%")),
+            ]
+        );
+        assert_eq!(
+            background("url(bg.png) repeat-x"),
+            [
+                transparent,
+                Value::Other(String::from("url(bg.png)")),
+                keyword("repeat-x"),
+                Value::Other(String::from("0% 0%")),
+            ]
+        );
+        assert_eq!(
+            background("#fff url(bg.png) no-repeat center"),
+            [
+                Value::Color(Color::new(1.0, 1.0, 1.0, 1.0)),
+                Value::Other(String::from("url(bg.png)")),
+                keyword("no-repeat"),
+                keyword("center"),
+            ]
+        );
+    }
+
+    fn calc(css: &str) -> CalcNode {
+        match declarations(css).remove(0).value {
+            Value::Calc(node) => node,
+            other => panic!("expected calc, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn calc_evaluates_with_precedence() {
+        let ctx = LengthContext {
+            containing_width: 200.0,
+            ..LengthContext::default()
+        };
+        assert_eq!(calc("a { width: calc(100% - 20px) }").eval(&ctx), 180.0);
+        assert_eq!(calc("a { width: calc(2 * 10px) }").eval(&ctx), 20.0);
+        assert_eq!(calc("a { width: calc(10px + 2 * 5px) }").eval(&ctx), 20.0);
+        assert_eq!(calc("a { width: calc((10px + 2px) * 2) }").eval(&ctx), 24.0);
+        assert_eq!(calc("a { width: calc(50% / 2) }").eval(&ctx), 50.0);
+
+        assert_eq!(
+            declarations("a { width: calc(10px +) }")[0].value,
+            Value::Other(String::from("calc(10px +)"))
+        );
+    }
+
+    #[test]
+    fn semicolons_inside_quotes_and_urls_stay_in_the_value() {
+        let decls = declarations(
+            r#"a { content: "a;b"; quotes: 'x;y'; background-image: url(a;b.png); color: red }"#,
+        );
+        let values: Vec<(&str, &Value)> = decls
+            .iter()
+            .map(|d| (d.property.as_str(), &d.value))
+            .collect();
+        assert_eq!(
+            values,
+            [
+                ("content", &Value::Other(String::from("\"a;b\""))),
+                ("quotes", &Value::Other(String::from("'x;y'"))),
+                (
+                    "background-image",
+                    &Value::Other(String::from("url(a;b.png)"))
+                ),
+                ("color", &Value::Color(Color::new(1.0, 0.0, 0.0, 1.0))),
+            ]
+        );
+    }
+
+    #[test]
+    fn values_may_span_lines() {
+        let decls = declarations(
+            "a { box-shadow: 0 1px red,\n  0 2px blue; font-family: Arial,\r\n sans-serif; color: red }",
+        );
+        let properties: Vec<&str> = decls.iter().map(|d| d.property.as_str()).collect();
+        assert_eq!(properties, ["box-shadow", "font-family", "color"]);
+
+        match &decls[0].value {
+            Value::ShadowList(shadows) => assert_eq!(shadows.len(), 2),
+            other => panic!("expected shadows, got {:?}", other),
+        }
+        assert_eq!(
+            decls[1].value,
+            Value::FontFamilyList(vec![
+                FontFamily::Name(String::from("Arial")),
+                FontFamily::Generic(String::from("sans-serif")),
+            ])
+        );
+    }
 }
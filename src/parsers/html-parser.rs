@@ -1,85 +1,412 @@
-use crate::dom::{AttrMap, ElementData, Node, NodeType};
+use crate::dom::{is_void_element, AttrMap, ElementData, Node, NodeType};
+use crate::parse_options::ParseOptions;
+use std::cell::Cell;
+use std::collections::HashSet;
+use std::fmt;
 use std::iter::Peekable;
+use std::rc::Rc;
 use std::str::Chars;
 
 pub struct HtmlParser<'a> {
-    chars: Peekable<Chars<'a>>,
-    node_q: Vec<String>,
+    chars: Peekable<CountingChars<'a>>,
+    // Bytes taken from the input so far, shared with `chars`. Includes a
+    // char sitting in the peek buffer; `pos` accounts for that.
+    consumed: Rc<Cell<usize>>,
+    // Tag names of the elements currently being parsed, outermost first.
+    open: Vec<String>,
+    // An end tag that closes an element further up than the current one.
+    // It bubbles up until the matching element takes it.
+    pending_close: Option<String>,
+    // Each with the byte offset of the tag it concerns.
+    diagnostics: Vec<(usize, Diagnostic)>,
+    options: ParseOptions,
+    // Set once an element nests deeper than `options.max_depth`.
+    too_deep: bool,
+    // Every tag and attribute name seen so far, so repeats share storage.
+    names: HashSet<Rc<str>>,
+    // Where the end tag that set `pending_close` starts, which is where the
+    // elements it closes implicitly end.
+    close_start: usize,
+    // What follows `<!DOCTYPE` in the first doctype seen, e.g. `html`.
+    doctype: Option<String>,
+}
+
+// `Chars` that counts the bytes it hands out, so the parser can tell where
+// it is in the input while reading through a `Peekable`.
+struct CountingChars<'a> {
+    chars: Chars<'a>,
+    consumed: Rc<Cell<usize>>,
+}
+
+impl Iterator for CountingChars<'_> {
+    type Item = char;
+
+    fn next(&mut self) -> Option<char> {
+        let c = self.chars.next()?;
+        self.consumed.set(self.consumed.get() + c.len_utf8());
+        Some(c)
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum ParseError {
+    UnsupportedEncoding(String),
+    // The input is longer, in bytes, than the limit given to `with_max_len`.
+    InputTooLarge { len: usize, limit: usize },
+    // Elements nest deeper than `ParseOptions::max_depth`.
+    TooDeep { limit: usize },
+    // `Node::reparse_subtree` only accepts markup that needs no repair.
+    Malformed(Vec<Diagnostic>),
+    // `Node::reparse_subtree` was called on a text or comment node.
+    NotAnElement,
+    // The offset given to `with_offset` is past the end of the input or
+    // inside a multibyte char.
+    InvalidOffset(usize),
+}
+
+// Recoverable problems noticed while parsing. The tree is still built.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Diagnostic {
+    // An element closed implicitly, by an ancestor's end tag or by the end
+    // of input, rather than by its own end tag.
+    UnclosedElement(String),
+    // An end tag with no matching open element; it is ignored.
+    UnexpectedEndTag(String),
+    // An attribute given twice on one element. The first value is kept.
+    DuplicateAttribute(String),
+}
+
+enum Encoding {
+    Utf8,
+    Utf16Le,
+    Utf16Be,
+    // Also what the Latin-1 labels mean, per the Encoding standard.
+    Windows1252,
 }
 
 impl<'a> HtmlParser<'a> {
-    pub fn new(full_html: &str) -> HtmlParser {
+    pub fn new(full_html: &str) -> HtmlParser<'_> {
+        let consumed = Rc::new(Cell::new(0));
+        let chars = CountingChars {
+            chars: full_html.chars(),
+            consumed: consumed.clone(),
+        };
+
         HtmlParser {
-            chars: full_html.chars().peekable(),
-            node_q: Vec::new(),
+            chars: chars.peekable(),
+            consumed,
+            open: Vec::new(),
+            pending_close: None,
+            diagnostics: Vec::new(),
+            options: ParseOptions::default(),
+            too_deep: false,
+            names: HashSet::new(),
+            close_start: 0,
+            doctype: None,
+        }
+    }
+
+    // A parser that starts reading `full_html` at byte offset `pos`, for
+    // tools that only want a region of a large document, such as one element
+    // found through an index of spans. Parsing runs to the end of the input
+    // and gives the same nodes as parsing `&full_html[pos..]`, but any
+    // offsets it records stay relative to the whole of `full_html`. `pos`
+    // must be on a char boundary.
+    pub fn with_offset(full_html: &str, pos: usize) -> Result<HtmlParser<'_>, ParseError> {
+        let rest = full_html.get(pos..).ok_or(ParseError::InvalidOffset(pos))?;
+
+        let parser = HtmlParser::new(rest);
+        parser.consumed.set(pos);
+        Ok(parser)
+    }
+
+    pub fn parse_with_options(
+        full_html: &str,
+        options: &ParseOptions,
+    ) -> Result<Vec<Node>, ParseError> {
+        let mut parser = match options.max_len {
+            Some(limit) => HtmlParser::with_max_len(full_html, limit)?,
+            None => HtmlParser::new(full_html),
+        };
+        parser.options = options.clone();
+
+        let nodes = parser.parse_nodes();
+        match options.max_depth {
+            Some(limit) if parser.too_deep => Err(ParseError::TooDeep { limit }),
+            _ if options.insert_skeleton => Ok(insert_skeleton(nodes)),
+            _ => Ok(nodes),
+        }
+    }
+
+    // Like `new`, but refuses inputs over `limit` bytes up front instead of
+    // parsing them. `new` has no limit.
+    pub fn with_max_len(full_html: &str, limit: usize) -> Result<HtmlParser<'_>, ParseError> {
+        if full_html.len() > limit {
+            return Err(ParseError::InputTooLarge {
+                len: full_html.len(),
+                limit,
+            });
         }
+
+        Ok(HtmlParser::new(full_html))
+    }
+
+    // Decodes raw document bytes before parsing. A BOM wins, then a
+    // `<meta charset>` in the first 1024 bytes, otherwise UTF-8 is assumed.
+    pub fn parse_bytes(bytes: &[u8]) -> Result<Vec<Node>, ParseError> {
+        let html = decode(bytes)?;
+        Ok(HtmlParser::new(&html).parse_nodes())
+    }
+
+    // Like `parse_nodes`, but also reports the elements that had to be closed
+    // implicitly and any end tags that were ignored.
+    pub fn parse_with_diagnostics(&mut self) -> (Vec<Node>, Vec<Diagnostic>) {
+        let nodes = self.parse_nodes();
+        let diagnostics = std::mem::take(&mut self.diagnostics);
+        (nodes, diagnostics.into_iter().map(|(_, d)| d).collect())
+    }
+
+    // Parses all of `source`, recovering from every problem, and returns the
+    // tree along with everything that went wrong, in input order. Each
+    // diagnostic comes with the byte offset of the `<` of the tag it is
+    // about: the stray end tag, or the start tag of the unclosed element.
+    // A duplicate attribute points at the repeated name instead.
+    pub fn parse_all_errors(source: &str) -> (Vec<Node>, Vec<(usize, Diagnostic)>) {
+        let mut parser = HtmlParser::new(source);
+        let nodes = parser.parse_nodes();
+        (nodes, parser.take_located_diagnostics())
+    }
+
+    pub(crate) fn take_located_diagnostics(&mut self) -> Vec<(usize, Diagnostic)> {
+        let mut diagnostics = std::mem::take(&mut self.diagnostics);
+        diagnostics.sort_by_key(|(offset, _)| *offset);
+        diagnostics
+    }
+
+    // What followed `<!DOCTYPE` in the input parsed so far, if anything.
+    pub fn doctype(&self) -> Option<&str> {
+        self.doctype.as_deref()
     }
 
     pub fn parse_nodes(&mut self) -> Vec<Node> {
         let mut nodes = Vec::new();
 
         while self.chars.peek().is_some() {
-            self.consume_while(char::is_whitespace);
+            let space_start = self.pos();
+            let skipped_space =
+                self.normalizes_whitespace() && !self.consume_while(char::is_whitespace).is_empty();
+            // Trailing whitespace, or a whitespace-only input, adds no node.
+            if self.chars.peek().is_none() {
+                break;
+            }
 
-            if self.chars.peek().map_or(false, |c| *c == '<') {
+            let start = self.pos();
+            if self.chars.peek().is_some_and(|c| *c == '<') {
                 self.chars.next();
-                if self.chars.peek().map_or(false, |c| *c == '/') {
+                if self.chars.peek().is_some_and(|c| *c == '/') {
                     self.chars.next();
                     self.consume_while(char::is_whitespace);
 
-                    let close_tag_name = self.consume_while(is_valid_tag_name);
+                    let close_tag_name = self.consume_while(is_valid_tag_name).to_lowercase();
                     self.consume_while(|x| x != '>');
                     self.chars.next();
 
-                    self.node_q.push(close_tag_name);
-                    break;
-                } else if self.chars.peek().map_or(false, |c| *c == '!') {
+                    if self.open.contains(&close_tag_name) {
+                        self.pending_close = Some(close_tag_name);
+                        self.close_start = start;
+                        break;
+                    }
+                    self.diagnostics
+                        .push((start, Diagnostic::UnexpectedEndTag(close_tag_name)));
+                } else if self.chars.peek().is_some_and(|c| *c == '?') {
+                    self.skip_processing_instruction();
+                } else if self.chars.peek().is_some_and(|c| *c == '!') {
                     self.chars.next();
-                    nodes.push(self.parse_comment_node());
-                } else {
-                    let mut node: Node = self.parse_node();
-                    let insert_index = nodes.len();
-
-                    match node.node_type {
-                        NodeType::Element(ref e) => {
-                            if self.node_q.len() > 0 {
-                                let assumed_tag = self.node_q.remove(0);
+                    let comment = if self.chars.peek() == Some(&'-') {
+                        Some(self.parse_comment_node())
+                    } else {
+                        self.parse_declaration()
+                    };
+                    if let Some(comment) = comment.filter(|_| self.options.keep_comments) {
+                        let end = self.pos();
+                        nodes.push(self.with_span(comment, start, end));
+                    }
+                } else if !self.chars.peek().is_some_and(|c| c.is_alphabetic()) {
+                    // A `<` that can't start a tag, as in `a < b`, `1<2` or at
+                    // the very end, is plain text.
+                    let mut text = String::from("<");
+                    if let NodeType::Text(rest) = self.parse_text_node().node_type {
+                        text.push_str(&rest);
+                    }
+                    let end = self.pos();
 
-                                if e.tag_name != assumed_tag {
-                                    nodes.append(&mut node.children);
-                                    self.node_q.insert(0, assumed_tag);
-                                }
+                    match nodes.last_mut() {
+                        Some(Node {
+                            node_type: NodeType::Text(ref mut prev),
+                            span,
+                            ..
+                        }) => {
+                            prev.push_str(&text);
+                            if let Some((_, prev_end)) = span {
+                                *prev_end = end;
                             }
                         }
-                        _ => {}
+                        _ => {
+                            let node = Node::new(NodeType::Text(text), Vec::new());
+                            nodes.push(self.with_span(node, start, end));
+                        }
+                    }
+                } else {
+                    let node = self.parse_node(start);
+                    // Still pending means an ancestor's end tag closed this
+                    // element too, so it ends where that tag begins.
+                    let end = if self.pending_close.is_some() {
+                        self.close_start
+                    } else {
+                        self.pos()
+                    };
+                    nodes.push(self.with_span(node, start, end));
+                    if self.pending_close.is_some() {
+                        break;
                     }
-
-                    nodes.insert(insert_index, node);
                 }
             } else {
-                nodes.push(self.parse_text_node());
+                let mut text = self.parse_text_node();
+                let end = self.pos();
+                // Whitespace-only runs between tags are dropped, but a space
+                // after an element still separates it from the text, as in
+                // `<b>bold</b> text`.
+                if skipped_space && !nodes.is_empty() {
+                    if let NodeType::Text(ref mut t) = text.node_type {
+                        t.insert(0, ' ');
+                    }
+                    nodes.push(self.with_span(text, space_start, end));
+                } else {
+                    nodes.push(self.with_span(text, start, end));
+                }
             }
         }
 
         nodes
     }
 
-    fn parse_node(&mut self) -> Node {
-        let tagname = self.consume_while(is_valid_tag_name);
-        let attrs = self.parse_attributes();
+    // `start` is the offset of the `<` already consumed.
+    fn parse_node(&mut self, start: usize) -> Node {
+        let tagname = self.consume_while(is_valid_tag_name).to_lowercase();
+        let (attrs, self_closing) = self.parse_attributes();
 
-        let elem = ElementData::new(tagname, attrs);
-        let children = self.parse_nodes();
+        // A newline right after `<pre>` or `<textarea>` is there for
+        // readability of the source and isn't part of the content.
+        if matches!(tagname.as_str(), "pre" | "textarea") {
+            self.chars.next_if_eq(&'\r');
+            self.chars.next_if_eq(&'\n');
+        }
 
+        // Past the depth limit, give up on the rest of the input rather than
+        // keep building a tree that will be rejected.
+        if self
+            .options
+            .max_depth
+            .is_some_and(|limit| self.open.len() >= limit)
+        {
+            self.too_deep = true;
+            self.chars.by_ref().for_each(drop);
+        }
+
+        // Browsers ignore the `/` of `<div/>` and open the element as usual;
+        // only SVG and MathML elements can close themselves that way.
+        let self_closed = self_closing && (is_foreign_root(&tagname) || self.in_foreign_content());
+
+        let children = if is_void_element(&tagname) || self_closed || self.too_deep {
+            Vec::new()
+        } else if is_raw_text_element(&tagname) {
+            self.parse_raw_text(&tagname, start)
+        } else {
+            self.open.push(tagname.clone());
+            let children = self.parse_nodes();
+            self.open.pop();
+
+            if self.pending_close.as_ref() == Some(&tagname) {
+                self.pending_close = None;
+            } else {
+                self.diagnostics
+                    .push((start, Diagnostic::UnclosedElement(tagname.clone())));
+            }
+
+            children
+        };
+
+        let elem = ElementData::new(self.intern(tagname), attrs);
         Node::new(NodeType::Element(elem), children)
     }
 
+    // Script and style bodies are taken verbatim up to the matching end tag,
+    // so `a < b` or `p > a` inside them isn't read as markup.
+    fn parse_raw_text(&mut self, tagname: &str, tag_start: usize) -> Vec<Node> {
+        let start = self.pos();
+        let end_tag = format!("</{}", tagname);
+        let mut text = String::new();
+        let mut closed = false;
+
+        while let Some(c) = self.chars.next() {
+            text.push(c);
+
+            if text.len() >= end_tag.len()
+                && text.is_char_boundary(text.len() - end_tag.len())
+                && text[text.len() - end_tag.len()..].eq_ignore_ascii_case(&end_tag)
+            {
+                text.truncate(text.len() - end_tag.len());
+                self.consume_while(|c| c != '>');
+                self.chars.next();
+                closed = true;
+                break;
+            }
+        }
+
+        if !closed {
+            self.diagnostics
+                .push((tag_start, Diagnostic::UnclosedElement(tagname.to_string())));
+        }
+
+        if text.is_empty() {
+            Vec::new()
+        } else {
+            let end = start + text.len();
+            let node = Node::new(NodeType::Text(text), Vec::new());
+            vec![self.with_span(node, start, end)]
+        }
+    }
+
+    // Whitespace inside `<pre>` is content, so it's kept even when
+    // normalization is on. Textarea content never goes through here.
+    fn normalizes_whitespace(&self) -> bool {
+        self.options.normalize_whitespace && !self.open.iter().any(|tag| tag == "pre")
+    }
+
+    // Byte offset of the next unread char.
+    fn pos(&mut self) -> usize {
+        let peeked = self.chars.peek().map_or(0, |c| c.len_utf8());
+        self.consumed.get() - peeked
+    }
+
+    fn with_span(&self, mut node: Node, start: usize, end: usize) -> Node {
+        if self.options.record_spans {
+            node.span = Some((start, end));
+        }
+        node
+    }
+
     fn parse_text_node(&mut self) -> Node {
+        if !self.normalizes_whitespace() {
+            let text = self.consume_while(|c| c != '<');
+            return Node::new(NodeType::Text(text), Vec::new());
+        }
+
         let mut text_content = String::new();
 
-        while self.chars.peek().map_or(false, |c| *c != '<') {
+        while self.chars.peek().is_some_and(|c| *c != '<') {
             let ws = self.consume_while(char::is_whitespace);
-            if ws.len() > 0 {
+            if !ws.is_empty() {
                 text_content.push(' ');
             }
             let text = self.consume_while(|x| !x.is_whitespace() && x != '<');
@@ -89,33 +416,72 @@ impl<'a> HtmlParser<'a> {
         Node::new(NodeType::Text(text_content), Vec::new())
     }
 
+    // `<?xml version="1.0"?>` and other processing instructions, read from
+    // the `?` through `?>`. They carry nothing the tree needs, so they are
+    // dropped.
+    fn skip_processing_instruction(&mut self) {
+        self.chars.next();
+
+        while let Some(c) = self.chars.next() {
+            if c == '?' && self.chars.peek() == Some(&'>') {
+                self.chars.next();
+                break;
+            }
+        }
+    }
+
+    // `<!DOCTYPE ...>` or another declaration, after the `<!`. The doctype
+    // is kept on the parser rather than in the tree, so it can't pass for a
+    // comment ahead of the root element; anything else is an empty comment.
+    fn parse_declaration(&mut self) -> Option<Node> {
+        let declaration = self.consume_while(|c| c != '>');
+        self.chars.next();
+
+        match declaration
+            .get(..7)
+            .filter(|keyword| keyword.eq_ignore_ascii_case("doctype"))
+        {
+            Some(_) => {
+                let doctype = declaration[7..].trim();
+                self.doctype.get_or_insert_with(|| doctype.to_string());
+                None
+            }
+            None => Some(Node::new(NodeType::Comment(String::new()), Vec::new())),
+        }
+    }
+
+    // After the `<!` of a comment, which must start with `-`.
     fn parse_comment_node(&mut self) -> Node {
         // I hate this once
         let mut comment_content = String::new();
 
-        if self.chars.peek().map_or(false, |c| *c == '-') {
+        if self.chars.peek().is_some_and(|c| *c == '-') {
             self.chars.next();
-            if self.chars.peek().map_or(false, |c| *c == '-') {
+            if self.chars.peek().is_some_and(|c| *c == '-') {
                 self.chars.next();
             } else {
                 self.consume_while(|c| c != '>');
+                self.chars.next();
                 return Node::new(NodeType::Comment(comment_content), Vec::new());
             }
-        } else {
-            self.consume_while(|c| c != '>');
-            return Node::new(NodeType::Comment(comment_content), Vec::new());
         }
 
-        if self.chars.peek().map_or(false, |c| *c == '>') {
+        if self.chars.peek().is_some_and(|c| *c == '>') {
             self.chars.next();
             return Node::new(NodeType::Comment(comment_content), Vec::new());
         }
 
-        if self.chars.peek().map_or(false, |c| *c == '-') {
+        if self.chars.peek().is_some_and(|c| *c == '-') {
             self.chars.next();
-            if self.chars.peek().map_or(false, |c| *c == '>') {
+            if self.chars.peek().is_some_and(|c| *c == '>') {
                 self.chars.next();
                 return Node::new(NodeType::Comment(comment_content), Vec::new());
+            } else if self.chars.next_if_eq(&'-').is_some() {
+                // `<!---->`, an empty comment, or content starting with `--`.
+                if self.chars.next_if_eq(&'>').is_some() {
+                    return Node::new(NodeType::Comment(comment_content), Vec::new());
+                }
+                comment_content.push_str("--");
             } else {
                 comment_content.push('-');
             }
@@ -123,27 +489,27 @@ impl<'a> HtmlParser<'a> {
 
         while self.chars.peek().is_some() {
             comment_content.push_str(&self.consume_while(|c| c != '<' && c != '-'));
-            if self.chars.peek().map_or(false, |c| *c == '<') {
+            if self.chars.peek().is_some_and(|c| *c == '<') {
                 self.chars.next();
-                if self.chars.peek().map_or(false, |c| *c == '!') {
+                if self.chars.peek().is_some_and(|c| *c == '!') {
                     self.chars.next();
-                    if self.chars.peek().map_or(false, |c| *c == '-') {
+                    if self.chars.peek().is_some_and(|c| *c == '-') {
                         self.chars.next();
-                        if self.chars.peek().map_or(false, |c| *c == '-') {
+                        if self.chars.peek().is_some_and(|c| *c == '-') {
                             self.consume_while(|c| c != '>');
                             return Node::new(NodeType::Comment(String::from("")), Vec::new());
                         } else {
                             comment_content.push_str("<!-");
                         }
-                    } else if self.chars.peek().map_or(false, |c| *c == ' ') {
+                    } else if self.chars.peek().is_some_and(|c| *c == ' ') {
                         self.chars.next();
-                        if self.chars.peek().map_or(false, |c| *c == '-') {
+                        if self.chars.peek().is_some_and(|c| *c == '-') {
                             self.chars.next();
-                            if self.chars.peek().map_or(false, |c| *c == '-') {
+                            if self.chars.peek().is_some_and(|c| *c == '-') {
                                 self.chars.next();
-                                if self.chars.peek().map_or(false, |c| *c == '-') {
+                                if self.chars.peek().is_some_and(|c| *c == '-') {
                                     self.chars.next();
-                                    if self.chars.peek().map_or(false, |c| *c == '>') {
+                                    if self.chars.peek().is_some_and(|c| *c == '>') {
                                         self.chars.next();
                                         return Node::new(
                                             NodeType::Comment(String::from("")),
@@ -163,13 +529,13 @@ impl<'a> HtmlParser<'a> {
                         comment_content.push_str("<!");
                     }
                 } else {
-                    comment_content.push_str("<");
+                    comment_content.push('<');
                 }
-            } else if self.chars.peek().map_or(false, |c| *c == '-') {
+            } else if self.chars.peek().is_some_and(|c| *c == '-') {
                 self.chars.next();
-                if self.chars.peek().map_or(false, |c| *c == '-') {
+                if self.chars.peek().is_some_and(|c| *c == '-') {
                     self.chars.next();
-                    if self.chars.peek().map_or(false, |c| *c == '>') {
+                    if self.chars.peek().is_some_and(|c| *c == '>') {
                         self.chars.next();
                         break;
                     } else {
@@ -183,15 +549,37 @@ impl<'a> HtmlParser<'a> {
         Node::new(NodeType::Comment(comment_content), Vec::new())
     }
 
-    fn parse_attributes(&mut self) -> AttrMap {
+    fn in_foreign_content(&self) -> bool {
+        self.open.iter().any(|tag| is_foreign_root(tag))
+    }
+
+    // Also says whether the tag ended in `/>`. An unquoted value swallows
+    // the slash (`<a href=x/>`), so that one doesn't count.
+    fn parse_attributes(&mut self) -> (AttrMap, bool) {
         let mut attrs = AttrMap::new();
+        let mut self_closing = false;
 
-        while self.chars.peek().map_or(false, |c| *c != '>') {
+        while self.chars.peek().is_some_and(|c| *c != '>') {
+            self_closing = false;
             self.consume_while(char::is_whitespace);
-            let name = self.consume_while(|c| is_valid_attr_name(c)).to_lowercase();
+            let name_start = self.pos();
+            let mut name = self.consume_while(is_valid_attr_name);
+            if self.options.lowercase_attributes {
+                name = name.to_lowercase();
+            }
             self.consume_while(char::is_whitespace);
 
-            let val = if self.chars.peek().map_or(false, |c| *c == '=') {
+            // A stray `/`, quote or control char can't start a name; skip it
+            // so malformed tags can't stall the loop.
+            if name.is_empty() && self.chars.peek().is_some_and(|c| *c != '=') {
+                if self.chars.peek().is_some_and(|c| *c != '>') {
+                    self_closing =
+                        self.chars.next() == Some('/') && self.chars.peek() == Some(&'>');
+                }
+                continue;
+            }
+
+            let val = if self.chars.peek().is_some_and(|c| *c == '=') {
                 self.chars.next();
                 self.consume_while(char::is_whitespace);
                 let s = self.parse_attr_value();
@@ -202,11 +590,16 @@ impl<'a> HtmlParser<'a> {
             } else {
                 "".to_string()
             };
-            attrs.insert(name, val);
+            if attrs.contains_key(name.as_str()) {
+                self.diagnostics
+                    .push((name_start, Diagnostic::DuplicateAttribute(name)));
+            } else if !name.is_empty() {
+                attrs.insert(self.intern(name), val);
+            }
         }
         self.chars.next();
 
-        attrs
+        (attrs, self_closing)
     }
 
     fn parse_attr_value(&mut self) -> String {
@@ -227,12 +620,22 @@ impl<'a> HtmlParser<'a> {
 
     //
 
+    fn intern(&mut self, name: String) -> Rc<str> {
+        if let Some(shared) = self.names.get(name.as_str()) {
+            return shared.clone();
+        }
+
+        let shared: Rc<str> = name.into();
+        self.names.insert(shared.clone());
+        shared
+    }
+
     fn consume_while<F>(&mut self, condition: F) -> String
     where
         F: Fn(char) -> bool,
     {
         let mut result = String::new();
-        while self.chars.peek().map_or(false, |c| condition(*c)) {
+        while self.chars.peek().is_some_and(|c| condition(*c)) {
             result.push(self.chars.next().unwrap());
         }
 
@@ -240,25 +643,215 @@ impl<'a> HtmlParser<'a> {
     }
 }
 
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            ParseError::UnsupportedEncoding(ref label) => {
+                write!(f, "unsupported encoding: {}", label)
+            }
+            ParseError::InputTooLarge { len, limit } => {
+                write!(f, "input of {} bytes exceeds the {} byte limit", len, limit)
+            }
+            ParseError::TooDeep { limit } => {
+                write!(f, "elements nest deeper than the limit of {}", limit)
+            }
+            ParseError::Malformed(ref diagnostics) => {
+                write!(f, "malformed markup: {:?}", diagnostics)
+            }
+            ParseError::NotAnElement => write!(f, "only elements have children to replace"),
+            ParseError::InvalidOffset(pos) => {
+                write!(f, "offset {} is outside the input or inside a char", pos)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+fn decode(bytes: &[u8]) -> Result<String, ParseError> {
+    let (encoding, body) = match bytes {
+        [0xEF, 0xBB, 0xBF, rest @ ..] => (Encoding::Utf8, rest),
+        [0xFF, 0xFE, rest @ ..] => (Encoding::Utf16Le, rest),
+        [0xFE, 0xFF, rest @ ..] => (Encoding::Utf16Be, rest),
+        _ => match sniff_meta_charset(bytes) {
+            Some(label) => (encoding_for_label(&label)?, bytes),
+            None => (Encoding::Utf8, bytes),
+        },
+    };
+
+    Ok(match encoding {
+        Encoding::Utf8 => String::from_utf8_lossy(body).into_owned(),
+        Encoding::Windows1252 => body.iter().map(|&b| windows_1252_char(b)).collect(),
+        Encoding::Utf16Le | Encoding::Utf16Be => {
+            let units: Vec<u16> = body
+                .chunks_exact(2)
+                .map(|pair| match encoding {
+                    Encoding::Utf16Le => u16::from_le_bytes([pair[0], pair[1]]),
+                    _ => u16::from_be_bytes([pair[0], pair[1]]),
+                })
+                .collect();
+            String::from_utf16_lossy(&units)
+        }
+    })
+}
+
+// Covers both `<meta charset="...">` and the older
+// `<meta http-equiv="Content-Type" content="text/html; charset=...">`.
+pub(crate) fn sniff_meta_charset(bytes: &[u8]) -> Option<String> {
+    let head = &bytes[..bytes.len().min(1024)];
+    let head = String::from_utf8_lossy(head).to_lowercase();
+
+    let mut rest = head.as_str();
+    while let Some(meta_at) = rest.find("<meta") {
+        rest = &rest[meta_at + 5..];
+        let tag = &rest[..rest.find('>').unwrap_or(rest.len())];
+
+        if let Some(charset_at) = tag.find("charset") {
+            let label: String = tag[charset_at + 7..]
+                .trim_start()
+                .trim_start_matches('=')
+                .trim_start()
+                .trim_start_matches(['"', '\''])
+                .chars()
+                .take_while(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | ':' | '.'))
+                .collect();
+
+            if !label.is_empty() {
+                return Some(label);
+            }
+        }
+    }
+
+    None
+}
+
+fn encoding_for_label(label: &str) -> Result<Encoding, ParseError> {
+    match label {
+        // A document able to declare its charset in ASCII isn't UTF-16, so
+        // the HTML spec reads a UTF-16 label in `<meta>` as UTF-8. Only a
+        // BOM selects UTF-16.
+        "utf-8" | "utf8" | "unicode-1-1-utf-8" | "utf-16le" | "utf-16" | "utf-16be" => {
+            Ok(Encoding::Utf8)
+        }
+        "iso-8859-1" | "latin1" | "l1" | "iso_8859-1" | "windows-1252" | "cp1252" | "us-ascii"
+        | "ascii" => Ok(Encoding::Windows1252),
+        _ => Err(ParseError::UnsupportedEncoding(label.to_string())),
+    }
+}
+
+// https://encoding.spec.whatwg.org/index-windows-1252.txt
+// Matches Latin-1 except in 0x80..=0x9F, where Latin-1 has C1 control
+// characters and windows-1252 mostly has punctuation. The five bytes it
+// leaves unassigned map to the control characters, as in Latin-1.
+fn windows_1252_char(byte: u8) -> char {
+    const HIGH: [char; 32] = [
+        '\u{20AC}', '\u{0081}', '\u{201A}', '\u{0192}', '\u{201E}', '\u{2026}', '\u{2020}',
+        '\u{2021}', '\u{02C6}', '\u{2030}', '\u{0160}', '\u{2039}', '\u{0152}', '\u{008D}',
+        '\u{017D}', '\u{008F}', '\u{0090}', '\u{2018}', '\u{2019}', '\u{201C}', '\u{201D}',
+        '\u{2022}', '\u{2013}', '\u{2014}', '\u{02DC}', '\u{2122}', '\u{0161}', '\u{203A}',
+        '\u{0153}', '\u{009D}', '\u{017E}', '\u{0178}',
+    ];
+
+    match byte {
+        0x80..=0x9F => HIGH[usize::from(byte - 0x80)],
+        _ => char::from(byte),
+    }
+}
+
+// Builds `<html><head>...</head><body>...</body></html>` around `nodes`.
+// Existing `html`, `head` and `body` elements are merged into the skeleton,
+// keeping their attributes. Metadata elements go in the head and all other
+// content in the body, in document order; whitespace-only text between
+// them is dropped. Comments before anything else, such as a license
+// header, stay outside as siblings preceding `html`, like browsers keep them.
+fn insert_skeleton(mut nodes: Vec<Node>) -> Vec<Node> {
+    let mut html = AttrMap::new();
+    let mut head = (AttrMap::new(), Vec::new());
+    let mut body = (AttrMap::new(), Vec::new());
+
+    let prologue_len = nodes
+        .iter()
+        .take_while(|node| match node.node_type {
+            NodeType::Comment(_) => true,
+            NodeType::Text(ref t) => t.trim().is_empty(),
+            NodeType::Element(_) => false,
+        })
+        .count();
+    let mut pending = nodes.split_off(prologue_len);
+    let mut prologue = nodes;
+    prologue.retain(|node| matches!(node.node_type, NodeType::Comment(_)));
+
+    pending.reverse();
+    while let Some(node) = pending.pop() {
+        let section = match node.node_type {
+            NodeType::Element(ref e) if matches!(&*e.tag_name, "html" | "head" | "body") => {
+                let target = match &*e.tag_name {
+                    "html" => &mut html,
+                    "head" => &mut head.0,
+                    _ => &mut body.0,
+                };
+                if let NodeType::Element(e) = node.node_type {
+                    target.extend(e.into_attributes());
+                }
+                // Their children take their place in the queue.
+                pending.extend(node.children.into_iter().rev());
+                continue;
+            }
+            NodeType::Element(ref e) if is_metadata_element(&e.tag_name) => &mut head.1,
+            NodeType::Text(ref t) if t.trim().is_empty() => continue,
+            _ => &mut body.1,
+        };
+        section.push(node);
+    }
+
+    let section = |tag_name: &str, (attrs, children): (AttrMap, Vec<Node>)| {
+        Node::new(
+            NodeType::Element(ElementData::new(tag_name, attrs)),
+            children,
+        )
+    };
+    let children = vec![section("head", head), section("body", body)];
+    prologue.push(Node::new(
+        NodeType::Element(ElementData::new("html", html)),
+        children,
+    ));
+    prologue
+}
+
 // Checkers
+fn is_metadata_element(tag_name: &str) -> bool {
+    matches!(tag_name, "title" | "meta" | "link" | "style" | "base")
+}
+
+// Roots of the foreign (non-HTML) content allowed inline in HTML.
+fn is_foreign_root(tag_name: &str) -> bool {
+    matches!(tag_name, "svg" | "math")
+}
+
+// Textarea is only close to raw text: its content can't hold elements, but
+// unlike script and style it may contain character references. Those are
+// kept as written here.
+fn is_raw_text_element(tag_name: &str) -> bool {
+    matches!(tag_name, "script" | "style" | "textarea")
+}
+
+// Any letter or digit, not just ASCII ones, so `<café>` keeps its `é`
+// rather than having it split off into an attribute.
 fn is_valid_tag_name(ch: char) -> bool {
-    ch.is_digit(36)
+    ch.is_alphanumeric()
 }
 
 fn is_control(ch: char) -> bool {
     match ch {
         '\u{007F}' => true,
-        c if c >= '\u{0000}' && c <= '\u{001F}' => true,
-        c if c >= '\u{0080}' && c <= '\u{009F}' => true,
+        c if ('\u{0000}'..='\u{001F}').contains(&c) => true,
+        c if ('\u{0080}'..='\u{009F}').contains(&c) => true,
         _ => false,
     }
 }
 
 fn is_excluded_name(ch: char) -> bool {
-    match ch {
-        ' ' | '"' | '\'' | '>' | '/' | '=' => true,
-        _ => false,
-    }
+    matches!(ch, ' ' | '"' | '\'' | '>' | '/' | '=')
 }
 
 fn is_valid_attr_name(ch: char) -> bool {
@@ -266,8 +859,449 @@ fn is_valid_attr_name(ch: char) -> bool {
 }
 
 fn is_valid_attr_value(ch: char) -> bool {
-    match ch {
-        ' ' | '"' | '\'' | '=' | '<' | '>' | '`' => false,
-        _ => true,
+    !matches!(ch, ' ' | '"' | '\'' | '=' | '<' | '>' | '`')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::css::{Unit, Value};
+    use crate::css_parser::CssParser;
+    use crate::styles::StyledNode;
+
+    fn texts(nodes: &[Node]) -> String {
+        nodes.iter().map(Node::text_content).collect()
+    }
+
+    #[test]
+    fn truncated_input_does_not_panic() {
+        let inputs = [
+            "",
+            "<",
+            "<a",
+            "<a x=",
+            "<a x=\"y",
+            "<a x='y",
+            "\"",
+            "</",
+            "<!",
+            "<!--",
+            "<!-",
+            "&",
+            "&#",
+            "<p>\u{e9}<",
+            "<a \u{e9}=\u{e9}",
+        ];
+        for html in inputs {
+            HtmlParser::new(html).parse_with_diagnostics();
+            HtmlParser::parse_all_errors(html);
+            HtmlParser::parse_bytes(html.as_bytes()).unwrap();
+        }
+
+        let (nodes, diagnostics) = HtmlParser::new("<a x=").parse_with_diagnostics();
+        let a = nodes[0].as_element().unwrap();
+        assert_eq!(&*a.tag_name, "a");
+        assert_eq!(a.get_attribute_raw("x"), Some(""));
+        assert_eq!(
+            diagnostics,
+            [Diagnostic::UnclosedElement(String::from("a"))]
+        );
+
+        let (nodes, _) = HtmlParser::new("<a x=\"y").parse_with_diagnostics();
+        assert_eq!(
+            nodes[0].as_element().unwrap().get_attribute_raw("x"),
+            Some("y")
+        );
+
+        assert!(HtmlParser::new("").parse_with_diagnostics().0.is_empty());
+        assert_eq!(
+            texts(&HtmlParser::new("\"").parse_with_diagnostics().0),
+            "\""
+        );
+    }
+
+    #[test]
+    fn unclosed_elements_are_closed_and_reported() {
+        let (nodes, diagnostics) = HtmlParser::new("<div><p>text<b>bold").parse_with_diagnostics();
+        assert_eq!(nodes.len(), 1);
+        assert_eq!(nodes[0].to_html(), "<div><p>text<b>bold</b></p></div>");
+        assert_eq!(
+            diagnostics,
+            [
+                Diagnostic::UnclosedElement(String::from("b")),
+                Diagnostic::UnclosedElement(String::from("p")),
+                Diagnostic::UnclosedElement(String::from("div")),
+            ]
+        );
+
+        let (nodes, diagnostics) =
+            HtmlParser::new("<div><span>a</div><p>b</p></i>").parse_with_diagnostics();
+        assert_eq!(nodes.len(), 2);
+        assert_eq!(nodes[0].to_html(), "<div><span>a</span></div>");
+        assert_eq!(
+            diagnostics,
+            [
+                Diagnostic::UnclosedElement(String::from("span")),
+                Diagnostic::UnexpectedEndTag(String::from("i")),
+            ]
+        );
+
+        let (_, diagnostics) = HtmlParser::new("<p>a<br>b</p>").parse_with_diagnostics();
+        assert!(diagnostics.is_empty());
+    }
+
+    #[test]
+    fn max_len_rejects_only_longer_input() {
+        let html = "<p>caf\u{e9}</p>";
+        assert!(matches!(
+            HtmlParser::with_max_len(html, html.len() - 1),
+            Err(ParseError::InputTooLarge { len: 12, limit: 11 })
+        ));
+
+        let mut parser = HtmlParser::with_max_len(html, html.len()).unwrap();
+        assert_eq!(texts(&parser.parse_with_diagnostics().0), "caf\u{e9}");
+    }
+
+    #[test]
+    fn repeated_tag_names_and_attribute_keys_are_shared() {
+        let html = "<div class=x>a</div>".repeat(10_000);
+        let nodes = HtmlParser::new(&html).parse_with_diagnostics().0;
+        assert_eq!(nodes.len(), 10_000);
+
+        let first = nodes[0].as_element().unwrap();
+        let first_key = first.sorted_attributes()[0].0;
+        for node in &nodes {
+            let el = node.as_element().unwrap();
+            assert!(Rc::ptr_eq(&el.tag_name, &first.tag_name));
+            assert!(std::ptr::eq(el.sorted_attributes()[0].0, first_key));
+        }
+        // One reference per element, plus the parser's, which is gone.
+        assert_eq!(Rc::strong_count(&first.tag_name), 10_000);
+    }
+
+    #[test]
+    fn xml_declaration_is_skipped() {
+        let (nodes, diagnostics) = HtmlParser::new(
+            "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<html><body><p>a ? b > c</p><?php echo 1 ?></body></html>",
+        )
+        .parse_with_diagnostics();
+
+        assert!(diagnostics.is_empty());
+        assert_eq!(nodes.len(), 1);
+        assert_eq!(
+            nodes[0].to_html(),
+            "<html><body><p>a ? b &gt; c</p></body></html>"
+        );
+
+        assert!(HtmlParser::new("<?xml unterminated")
+            .parse_with_diagnostics()
+            .0
+            .is_empty());
+    }
+
+    #[test]
+    fn recorded_spans_bracket_the_source() {
+        let html = "<div id=a>caf\u{e9} <p class=x>text</p><!-- c --><br></div>";
+        let options = ParseOptions::default().record_spans(true);
+        let nodes = HtmlParser::parse_with_options(html, &options).unwrap();
+
+        let source = |node: &Node| {
+            let (start, end) = node.span.unwrap();
+            &html[start..end]
+        };
+        let div = &nodes[0];
+        assert_eq!(source(div), html);
+        assert_eq!(source(&div.children[0]), "caf\u{e9} ");
+        assert_eq!(source(&div.children[1]), "<p class=x>text</p>");
+        assert_eq!(source(&div.children[1].children[0]), "text");
+        assert_eq!(source(&div.children[2]), "<!-- c -->");
+        assert_eq!(source(&div.children[3]), "<br>");
+
+        let nodes = HtmlParser::new(html).parse_with_diagnostics().0;
+        assert_eq!(nodes[0].span, None);
+    }
+
+    #[test]
+    fn multibyte_names_and_values_stay_on_char_boundaries() {
+        for (html, expected) in [
+            ("<café>x</café>", "<café>x</café>"),
+            ("<日本>x</日本>", "<日本>x</日本>"),
+            (
+                "<p title=\"日本語\" data-é=ü>ё</p>",
+                "<p data-é=\"ü\" title=\"日本語\">ё</p>",
+            ),
+        ] {
+            let (nodes, diagnostics) = HtmlParser::new(html).parse_with_diagnostics();
+            assert_eq!(nodes[0].to_html(), expected);
+            assert!(diagnostics.is_empty(), "{:?}", diagnostics);
+        }
+
+        let (_, diagnostics) = HtmlParser::new("<p>ü</pé>").parse_with_diagnostics();
+        assert_eq!(
+            diagnostics,
+            [
+                Diagnostic::UnexpectedEndTag(String::from("pé")),
+                Diagnostic::UnclosedElement(String::from("p")),
+            ]
+        );
+    }
+
+    #[test]
+    fn parse_all_errors_reports_every_problem_with_its_offset() {
+        let html = "<div><p>a</span><b>b</div><i>c";
+        let (nodes, errors) = HtmlParser::parse_all_errors(html);
+
+        assert_eq!(
+            errors,
+            [
+                (5, Diagnostic::UnclosedElement(String::from("p"))),
+                (9, Diagnostic::UnexpectedEndTag(String::from("span"))),
+                (16, Diagnostic::UnclosedElement(String::from("b"))),
+                (26, Diagnostic::UnclosedElement(String::from("i"))),
+            ]
+        );
+        for (offset, _) in &errors {
+            assert_eq!(&html[*offset..*offset + 1], "<");
+        }
+        assert_eq!(
+            nodes.iter().map(Node::to_html).collect::<String>(),
+            "<div><p>a<b>b</b></p></div><i>c</i>"
+        );
+    }
+
+    #[test]
+    fn pre_and_textarea_keep_their_whitespace() {
+        let (nodes, _) = HtmlParser::new(
+            "<div>  a   b  <pre>\n\n  x   y\n z </pre>  c   d <textarea>\n  <b>t</b>   </textarea></div>",
+        )
+        .parse_with_diagnostics();
+        let div = &nodes[0];
+
+        assert_eq!(div.children[0].as_text(), Some("a b "));
+        assert_eq!(
+            div.children[1].children[0].as_text(),
+            Some("\n  x   y\n z ")
+        );
+        assert_eq!(div.children[2].as_text(), Some(" c d "));
+        assert_eq!(div.children[3].children.len(), 1);
+        assert_eq!(div.children[3].children[0].as_text(), Some("  <b>t</b>   "));
+
+        let (nodes, _) = HtmlParser::new("<pre><b>  x  </b>\n</pre>").parse_with_diagnostics();
+        assert_eq!(nodes[0].children[0].children[0].as_text(), Some("  x  "));
+        assert_eq!(nodes[0].children[1].as_text(), Some("\n"));
+    }
+
+    #[test]
+    fn empty_and_comment_only_documents() {
+        for html in ["", "   \n\t"] {
+            let (nodes, diagnostics) = HtmlParser::new(html).parse_with_diagnostics();
+            assert!(nodes.is_empty(), "{:?} gave {:?}", html, nodes);
+            assert!(diagnostics.is_empty());
+        }
+
+        let (nodes, diagnostics) =
+            HtmlParser::new(" <!-- only comment --> ").parse_with_diagnostics();
+        assert!(diagnostics.is_empty());
+        assert_eq!(nodes.len(), 1);
+        assert_eq!(
+            nodes[0].node_type,
+            NodeType::Comment(String::from(" only comment "))
+        );
+
+        let (nodes, _) = HtmlParser::new("<p>x</p>  \n").parse_with_diagnostics();
+        assert_eq!(nodes.len(), 1);
+
+        let skeleton = parse_with_skeleton("");
+        assert_eq!(skeleton.len(), 1);
+        assert_eq!(skeleton[0].tag_name(), Some("html"));
+    }
+
+    #[test]
+    fn with_offset_parses_a_region_with_whole_document_offsets() {
+        let full = "<h1>\u{e9}t\u{e9}</h1><ul><li>a</li><li>b</ul>";
+        let pos = full.find("<ul>").unwrap();
+
+        let mut region = HtmlParser::with_offset(full, pos).unwrap();
+        region.options = ParseOptions::default().record_spans(true);
+        let nodes = region.parse_nodes();
+        let direct = HtmlParser::new(&full[pos..]).parse_nodes();
+        assert_eq!(nodes.len(), 1);
+        assert_eq!(nodes[0].to_html(), direct[0].to_html());
+
+        assert_eq!(nodes[0].span, Some((pos, full.len())));
+        assert_eq!(
+            region.take_located_diagnostics(),
+            [(
+                full.rfind("<li>").unwrap(),
+                Diagnostic::UnclosedElement(String::from("li"))
+            ),]
+        );
+
+        assert!(matches!(
+            HtmlParser::with_offset(full, 5),
+            Err(ParseError::InvalidOffset(5))
+        ));
+    }
+
+    #[test]
+    fn self_closing_slash_only_closes_void_and_foreign_elements() {
+        let (nodes, diagnostics) =
+            HtmlParser::new("<div/>text</div><br/>after").parse_with_diagnostics();
+        assert!(diagnostics.is_empty());
+        assert_eq!(nodes.len(), 3);
+        assert_eq!(nodes[0].to_html(), "<div>text</div>");
+        assert_eq!(nodes[1].tag_name(), Some("br"));
+        assert!(nodes[1].children.is_empty());
+        assert_eq!(texts(&nodes[2..]), "after");
+
+        let nodes = HtmlParser::new("<svg><circle/><rect/></svg>").parse_nodes();
+        let children: Vec<Option<&str>> = nodes[0].children.iter().map(Node::tag_name).collect();
+        assert_eq!(children, [Some("circle"), Some("rect")]);
+    }
+
+    #[test]
+    fn duplicate_attributes_keep_the_first_value() {
+        let html = "<a id=\"x\" href=/a ID=\"y\" id=z>t</a>";
+        let (nodes, diagnostics) = HtmlParser::parse_all_errors(html);
+        let a = nodes[0].as_element().unwrap();
+        assert_eq!(a.sorted_attributes(), [("href", "/a"), ("id", "x")]);
+        assert_eq!(
+            diagnostics,
+            [
+                (
+                    html.find("ID").unwrap(),
+                    Diagnostic::DuplicateAttribute(String::from("id"))
+                ),
+                (
+                    html.rfind("id").unwrap(),
+                    Diagnostic::DuplicateAttribute(String::from("id"))
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn parse_bytes_detects_boms_and_meta_charsets() {
+        let mut utf8 = b"\xEF\xBB\xBF".to_vec();
+        utf8.extend_from_slice("<p>caf\u{e9}</p>".as_bytes());
+        assert_eq!(texts(&HtmlParser::parse_bytes(&utf8).unwrap()), "caf\u{e9}");
+
+        let utf16le: Vec<u8> = [0xFEFF_u16]
+            .into_iter()
+            .chain("<p>caf\u{e9}</p>".encode_utf16())
+            .flat_map(u16::to_le_bytes)
+            .collect();
+        assert_eq!(
+            texts(&HtmlParser::parse_bytes(&utf16le).unwrap()),
+            "caf\u{e9}"
+        );
+
+        let latin1 = b"<meta http-equiv=Content-Type content=\"text/html; charset=ISO-8859-1\"><p>caf\xe9</p>";
+        assert_eq!(
+            texts(&HtmlParser::parse_bytes(latin1).unwrap()),
+            "caf\u{e9}"
+        );
+
+        assert!(matches!(
+            HtmlParser::parse_bytes(b"<meta charset=koi8-r><p>x</p>"),
+            Err(ParseError::UnsupportedEncoding(label)) if label == "koi8-r"
+        ));
+    }
+
+    #[test]
+    fn latin1_labels_decode_as_windows_1252() {
+        for label in ["iso-8859-1", "windows-1252"] {
+            let mut html = format!("<meta charset={}><p>", label).into_bytes();
+            html.extend_from_slice(b"\x93caf\xe9\x94 \x80</p>");
+            let nodes = HtmlParser::parse_bytes(&html).unwrap();
+            assert_eq!(texts(&nodes), "\u{201C}caf\u{e9}\u{201D} \u{20AC}");
+        }
+    }
+
+    #[test]
+    fn meta_utf16_label_decodes_as_utf8() {
+        for label in ["utf-16", "utf-16le", "utf-16be"] {
+            let html = format!("<meta charset=\"{}\"><p>caf\u{e9}</p>", label);
+            let nodes = HtmlParser::parse_bytes(html.as_bytes()).unwrap();
+            assert_eq!(texts(&nodes), "caf\u{e9}");
+        }
+    }
+
+    fn parse_with_skeleton(html: &str) -> Vec<Node> {
+        let options = ParseOptions::default().insert_skeleton(true);
+        HtmlParser::parse_with_options(html, &options).unwrap()
+    }
+
+    #[test]
+    fn skeleton_reuses_a_real_html_root() {
+        let real = parse_with_skeleton(
+            "<!DOCTYPE html>\n<html lang=en>\n<head><title>t</title></head>\n<body class=page><p>x</p></body>\n</html>\n",
+        );
+        let fragment = parse_with_skeleton("<title>t</title><p>x</p>");
+
+        assert_eq!(real.len(), 1);
+        assert_eq!(
+            real[0].to_html(),
+            "<html lang=\"en\"><head><title>t</title></head><body class=\"page\"><p>x</p></body></html>"
+        );
+        assert_eq!(fragment.len(), 1);
+        assert_eq!(
+            fragment[0].to_html(),
+            "<html><head><title>t</title></head><body><p>x</p></body></html>"
+        );
+    }
+
+    #[test]
+    fn skeleton_moves_metadata_to_head_and_is_off_by_default() {
+        let html = "<p>a</p><meta charset=utf-8><link rel=x><style>p{}</style><p>b</p>";
+
+        let plain = HtmlParser::parse_with_options(html, &ParseOptions::default()).unwrap();
+        assert_eq!(plain.len(), 5);
+        assert_eq!(plain[0].tag_name(), Some("p"));
+
+        let wrapped = parse_with_skeleton(html);
+        assert_eq!(wrapped.len(), 1);
+        assert_eq!(
+            wrapped[0].to_html(),
+            "<html><head><meta charset=\"utf-8\"><link rel=\"x\"><style>p{}</style></head>\
+             <body><p>a</p><p>b</p></body></html>"
+        );
+    }
+
+    #[test]
+    fn html_and_body_rules_match_once_with_or_without_a_real_root() {
+        let stylesheet = CssParser::new(
+            "html { width: 1px } body { height: 2px } html > body > p { color: red } html html, body body { width: 9px }",
+        )
+        .parse_stylesheet();
+
+        for html in [
+            "<html><body><p>x</p></body></html>",
+            "<!-- license --><html><body><p>x</p></body></html>",
+            "<p>x</p>",
+            "<body><p>x</p></body>",
+        ] {
+            let nodes = parse_with_skeleton(html);
+            let root = nodes.iter().find(|node| node.is_element()).unwrap();
+            assert_eq!(root.tag_name(), Some("html"), "{}", html);
+
+            let styled = StyledNode::new(root, &stylesheet);
+            assert_eq!(
+                styled.value("width").copied(),
+                Some(&Value::Length(1.0, Unit::Px))
+            );
+
+            let body = &styled.children[1];
+            assert_eq!(body.node().tag_name(), Some("body"));
+            assert_eq!(
+                body.value("height").copied(),
+                Some(&Value::Length(2.0, Unit::Px))
+            );
+            assert_eq!(body.value("width"), None, "{}", html);
+            assert!(matches!(
+                body.children[0].value("color"),
+                Some(Value::Color(_))
+            ));
+        }
     }
 }
@@ -0,0 +1,139 @@
+// Settings shared by `HtmlParser::parse_with_options` and
+// `CssParser::parse_with_options`. Each parser ignores the options that
+// don't concern it. The default matches `HtmlParser::new`/`CssParser::new`.
+#[derive(Debug, Clone)]
+pub struct ParseOptions {
+    // Collapse runs of whitespace in text to one space and drop
+    // whitespace-only text between tags. Off keeps text verbatim.
+    pub normalize_whitespace: bool,
+    // Keep `<!-- -->` comments as `NodeType::Comment` nodes.
+    pub keep_comments: bool,
+    // Lowercase attribute names, so `onClick` is stored as `onclick`.
+    pub lowercase_attributes: bool,
+    // Deepest element nesting allowed before parsing fails; `None` is
+    // unlimited.
+    pub max_depth: Option<usize>,
+    // Longest input, in bytes, accepted; `None` is unlimited.
+    pub max_len: Option<usize>,
+    // See `CssParser::strict_units`.
+    pub strict_units: bool,
+    // Fill in `Node::span` with each node's byte range in the input.
+    pub record_spans: bool,
+    // Wrap the result in one `html` element holding a `head` and a `body`,
+    // as browsers do, creating whichever of the three the input lacks. A
+    // real `<html>` (or `<body>`) becomes that element, attributes and all,
+    // rather than ending up nested inside a synthetic one, so `html` and
+    // `body` rules each match exactly one element either way. Comments
+    // leading the input are kept in front of it.
+    pub insert_skeleton: bool,
+}
+
+impl ParseOptions {
+    pub fn normalize_whitespace(mut self, normalize: bool) -> Self {
+        self.normalize_whitespace = normalize;
+        self
+    }
+
+    pub fn keep_comments(mut self, keep: bool) -> Self {
+        self.keep_comments = keep;
+        self
+    }
+
+    pub fn lowercase_attributes(mut self, lowercase: bool) -> Self {
+        self.lowercase_attributes = lowercase;
+        self
+    }
+
+    pub fn max_depth(mut self, depth: Option<usize>) -> Self {
+        self.max_depth = depth;
+        self
+    }
+
+    pub fn max_len(mut self, len: Option<usize>) -> Self {
+        self.max_len = len;
+        self
+    }
+
+    pub fn strict_units(mut self, strict: bool) -> Self {
+        self.strict_units = strict;
+        self
+    }
+
+    pub fn record_spans(mut self, record: bool) -> Self {
+        self.record_spans = record;
+        self
+    }
+
+    pub fn insert_skeleton(mut self, insert: bool) -> Self {
+        self.insert_skeleton = insert;
+        self
+    }
+}
+
+impl Default for ParseOptions {
+    fn default() -> Self {
+        ParseOptions {
+            normalize_whitespace: true,
+            keep_comments: true,
+            lowercase_attributes: true,
+            max_depth: None,
+            max_len: None,
+            strict_units: false,
+            record_spans: false,
+            insert_skeleton: false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::css::{Unit, Value};
+    use crate::css_parser::CssParser;
+    use crate::html_parser::{HtmlParser, ParseError};
+
+    #[test]
+    fn options_all_take_effect_together() {
+        let html = "<div onClick=go>  a   b  <!-- note --> <p>c</p></div>";
+        let options = ParseOptions::default()
+            .normalize_whitespace(false)
+            .keep_comments(false)
+            .lowercase_attributes(false)
+            .max_depth(Some(2))
+            .max_len(Some(html.len()))
+            .strict_units(true);
+
+        let nodes = HtmlParser::parse_with_options(html, &options).unwrap();
+        assert_eq!(
+            nodes[0].to_html(),
+            "<div onClick=\"go\">  a   b   <p>c</p></div>"
+        );
+
+        let defaults = HtmlParser::parse_with_options(html, &ParseOptions::default()).unwrap();
+        assert_eq!(
+            defaults[0].to_html(),
+            "<div onclick=\"go\">a b <!-- note --><p>c</p></div>"
+        );
+
+        assert!(matches!(
+            HtmlParser::parse_with_options("<div><p><b>x</b></p></div>", &options),
+            Err(ParseError::TooDeep { limit: 2 })
+        ));
+        assert!(matches!(
+            HtmlParser::parse_with_options(&format!("{} ", html), &options),
+            Err(ParseError::InputTooLarge { .. })
+        ));
+
+        let stylesheet = CssParser::parse_with_options("a { width: 1pz }", &options).unwrap();
+        assert_eq!(
+            stylesheet.rules[0].declarations[0].value,
+            Value::Other(String::from("1pz"))
+        );
+        let stylesheet =
+            CssParser::parse_with_options("a { width: 1pz }", &ParseOptions::default()).unwrap();
+        assert_eq!(
+            stylesheet.rules[0].declarations[0].value,
+            Value::Length(1.0, Unit::Px)
+        );
+    }
+}
@@ -1,16 +1,62 @@
-use crate::css::{Selector, Stylesheet, Value};
+use crate::css::{
+    Color, CssWide, Declaration, LengthContext, Rule, Selector, SimpleSelector, Specificity,
+    Stylesheet, Value,
+};
 use crate::dom::{ElementData, Node, NodeType};
+use crate::layout::{BoxEdges, EdgeSizes, FONT_SIZE};
+use std::cell::{Cell, OnceCell};
+use std::cmp::Reverse;
 use std::collections::HashMap;
 use std::{fmt, str};
 
-type PropertyMap<'a> = HashMap<&'a str, &'a Value>;
+pub type PropertyMap<'a> = HashMap<&'a str, &'a Value>;
+type OriginMap<'a> = HashMap<&'a str, StyleOrigin<'a>>;
 
 pub struct StyledNode<'a> {
     node: &'a Node,
     styles: PropertyMap<'a>,
+    origins: OriginMap<'a>,
+    cache: StyleCache,
     pub children: Vec<StyledNode<'a>>,
 }
 
+// Computed values layout asks for over and over, filled in on first use.
+// `styles` never changes after the cascade, so they can't go stale. Box
+// edges depend on the length context, so only the last one is kept.
+#[derive(Default)]
+struct StyleCache {
+    display: OnceCell<Display>,
+    color: OnceCell<Option<Color>>,
+    box_edges: Cell<Option<(LengthContext, BoxEdges)>>,
+}
+
+// Where a computed property came from: the rule that won the cascade and
+// the selector through which it matched.
+#[derive(Debug, Clone, Copy)]
+pub struct StyleOrigin<'a> {
+    pub rule: &'a Rule,
+    pub selector: &'a Selector,
+    pub specificity: Specificity,
+}
+
+// An element as the matcher sees it: its data plus its 1-based position
+// among its element siblings and their count, which structural
+// pseudo-classes need. Without a position those never match.
+#[derive(Clone, Copy)]
+pub struct ElementContext<'a> {
+    pub data: &'a ElementData,
+    pub position: Option<(usize, usize)>,
+}
+
+// A styled tree that owns its data, so it can outlive the `Node` and
+// `Stylesheet` it was computed from and be moved around freely.
+pub struct OwnedStyledNode {
+    pub node_type: NodeType,
+    pub styles: HashMap<String, Value>,
+    pub children: Vec<OwnedStyledNode>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Display {
     Block,
     Inline,
@@ -20,121 +66,655 @@ pub enum Display {
 
 impl<'a> StyledNode<'a> {
     pub fn new(node: &'a Node, stylesheet: &'a Stylesheet) -> StyledNode<'a> {
-        let mut style_children = Vec::new();
+        StyledNode::build(
+            node,
+            None,
+            None,
+            RuleSource::All(stylesheet),
+            &mut Vec::new(),
+        )
+    }
 
-        for child in &node.children {
-            match child.node_type {
-                NodeType::Element(_) => style_children.push(StyledNode::new(&child, stylesheet)),
-                _ => {}
-            }
+    // Same styles as `new`, but each element is only matched against the
+    // rules `index` picks out for it rather than the whole stylesheet.
+    pub fn with_index(node: &'a Node, index: &'a RuleIndex<'a>) -> StyledNode<'a> {
+        StyledNode::build(
+            node,
+            None,
+            None,
+            RuleSource::Indexed(index),
+            &mut Vec::new(),
+        )
+    }
+
+    // `ancestors` holds the element path from the root down to the parent of
+    // `node`, which combinator matching walks upwards. `parent` holds the
+    // parent's styles, which `inherit` reads.
+    fn build(
+        node: &'a Node,
+        position: Option<(usize, usize)>,
+        parent: Option<&PropertyMap<'a>>,
+        rules: RuleSource<'a>,
+        ancestors: &mut Vec<ElementContext<'a>>,
+    ) -> StyledNode<'a> {
+        let element = match node.node_type {
+            NodeType::Element(ref e) => Some(ElementContext { data: e, position }),
+            _ => None,
+        };
+
+        let (styles, origins) = match element {
+            Some(el) => StyledNode::get_styles(el, ancestors, parent, rules),
+            None => (PropertyMap::new(), OriginMap::new()),
+        };
+
+        if let Some(el) = element {
+            ancestors.push(el);
+        }
+
+        let count = element_count(node);
+        let style_children = element_children(node)
+            .enumerate()
+            .map(|(i, child)| {
+                StyledNode::build(child, Some((i + 1, count)), Some(&styles), rules, ancestors)
+            })
+            .collect();
+
+        if element.is_some() {
+            ancestors.pop();
         }
 
         StyledNode {
             node,
-            styles: match node.node_type {
-                NodeType::Element(ref e) => StyledNode::get_styles(e, stylesheet),
-                _ => PropertyMap::new(),
-            },
+            styles,
+            origins,
+            cache: StyleCache::default(),
             children: style_children,
         }
     }
 
-    fn get_styles(el: &'a ElementData, stylesheet: &'a Stylesheet) -> PropertyMap<'a> {
+    fn get_styles(
+        el: ElementContext,
+        ancestors: &[ElementContext],
+        parent: Option<&PropertyMap<'a>>,
+        rules: RuleSource<'a>,
+    ) -> (PropertyMap<'a>, OriginMap<'a>) {
         let mut styles = PropertyMap::new();
+        let mut origins = OriginMap::new();
 
-        for rule in &stylesheet.rules {
-            for selector in &rule.selectors {
-                if is_selector_matches(el, &selector) {
-                    for dclr in &rule.declarations {
-                        styles.insert(&dclr.property, &dclr.value);
-                    }
-                    break;
+        let candidates = rules.candidates(el.data);
+        for (origin, dclr) in cascade(el, ancestors, &candidates) {
+            styles.insert(&dclr.property, &dclr.value);
+            origins.insert(&dclr.property, origin);
+        }
+
+        // A property missing from the map has its initial value, so
+        // `initial` removes it and `inherit` copies the parent's entry, if
+        // there is one.
+        styles.retain(|&property, value| {
+            let inherit = match value {
+                Value::Global(CssWide::Inherit) => true,
+                Value::Global(CssWide::Initial) => false,
+                Value::Global(CssWide::Unset) => is_inherited(property),
+                _ => return true,
+            };
+
+            match parent.and_then(|p| p.get(property)) {
+                Some(parent_value) if inherit => {
+                    *value = parent_value;
+                    true
                 }
+                _ => false,
             }
-        }
+        });
 
-        styles
+        (styles, origins)
+    }
+
+    pub fn node(&self) -> &'a Node {
+        self.node
     }
 
     pub fn value(&self, name: &str) -> Option<&&Value> {
         self.styles.get(name)
     }
 
+    pub fn explain(&self, property: &str) -> Option<StyleOrigin<'a>> {
+        self.origins.get(property).copied()
+    }
+
     pub fn get_display(&self) -> Display {
-        match self.value("display") {
-            Some(s) => match **s {
-                Value::Other(ref v) => match v.as_ref() {
-                    "block" => Display::Block,
-                    "none" => Display::None,
-                    "inline" => Display::Inline,
-                    "inline-block" => Display::InlineBlock,
-                    _ => Display::Inline,
-                },
-                _ => Display::Inline,
-            },
-            None => Display::None,
+        *self
+            .cache
+            .display
+            .get_or_init(|| display_of(self.value("display").copied()))
+    }
+
+    // The `color` property, if set.
+    pub fn color(&self) -> Option<Color> {
+        *self.cache.color.get_or_init(|| match self.value("color") {
+            Some(Value::Color(c)) => Some(*c),
+            _ => None,
+        })
+    }
+
+    // Whether the node gets painted. `display: none` also takes it out of
+    // layout, while `visibility: hidden` (or `collapse`) keeps the space it
+    // occupies.
+    pub fn is_visible(&self) -> bool {
+        let hidden = matches!(
+            self.value("visibility"),
+            Some(Value::Keyword(v)) if v == "hidden" || v == "collapse"
+        );
+
+        !hidden && !matches!(self.get_display(), Display::None)
+    }
+
+    // Composites this node's `background-color` over `under`, the color
+    // showing through from behind, using the source-over operator. Without a
+    // background color the node is transparent and `under` comes back as is.
+    pub fn effective_background(&self, under: Color) -> Color {
+        let bg = match self.value("background-color") {
+            Some(Value::Color(c)) => c,
+            _ => return under,
+        };
+
+        let alpha = bg.a + under.a * (1.0 - bg.a);
+        if alpha <= 0.0 {
+            return Color::new(0.0, 0.0, 0.0, 0.0);
+        }
+
+        let blend = |top: f32, bottom: f32| (top * bg.a + bottom * under.a * (1.0 - bg.a)) / alpha;
+
+        Color::new(
+            blend(bg.r, under.r),
+            blend(bg.g, under.g),
+            blend(bg.b, under.b),
+            alpha,
+        )
+    }
+
+    // The `z-index` a painter sorts positioned boxes by; `None` is `auto`
+    // (the default), which paints at level 0 without starting a new
+    // stacking context.
+    pub fn z_index(&self) -> Option<i32> {
+        match self.value("z-index") {
+            Some(Value::Integer(n)) => Some(*n),
+            _ => None,
         }
     }
 
     pub fn num_or(&self, name: &str, def: f32) -> f32 {
         match self.value(name) {
-            Some(v) => match **v {
-                Value::Length(n, _) => n,
-                _ => def,
-            },
-            None => def,
+            Some(Value::Length(n, _)) => *n,
+            _ => def,
+        }
+    }
+
+    // Like `num_or`, but converts the length (or `calc()`) to pixels with
+    // `ctx` instead of taking the number as is.
+    pub fn px_or(&self, name: &str, def: f32, ctx: &LengthContext) -> f32 {
+        match self.value(name) {
+            Some(Value::Length(n, unit)) => unit.to_px(*n, ctx),
+            Some(Value::Calc(calc)) => calc.eval(ctx),
+            _ => def,
+        }
+    }
+
+    // The font size `rem` refers to, taking `self` as the root element:
+    // its `font-size` in pixels, or 16px when unset. Relative sizes on the
+    // root (`em`, `rem`, `%`) resolve against that same 16px default. Put the
+    // result in `LengthContext::root_font_size` so every element's `rem`
+    // lengths agree, whatever font sizes sit in between.
+    pub fn compute_root_font_size(&self) -> f32 {
+        let mut ctx = LengthContext::new(FONT_SIZE, 0.0, 0.0);
+        // A percentage font size is a share of the parent's font size.
+        ctx.containing_width = FONT_SIZE;
+
+        self.px_or("font-size", FONT_SIZE, &ctx)
+    }
+
+    // Margins, border widths and paddings on all four sides, in pixels. Sides
+    // without a length are 0.
+    pub fn box_edges(&self, ctx: &LengthContext) -> BoxEdges {
+        match self.cache.box_edges.get() {
+            Some((cached_ctx, edges)) if cached_ctx == *ctx => edges,
+            _ => {
+                let edges = self.compute_box_edges(ctx);
+                self.cache.box_edges.set(Some((*ctx, edges)));
+                edges
+            }
+        }
+    }
+
+    fn compute_box_edges(&self, ctx: &LengthContext) -> BoxEdges {
+        let edges = |side_name: fn(&str) -> String| EdgeSizes {
+            left: self.px_or(&side_name("left"), 0.0, ctx),
+            right: self.px_or(&side_name("right"), 0.0, ctx),
+            top: self.px_or(&side_name("top"), 0.0, ctx),
+            bottom: self.px_or(&side_name("bottom"), 0.0, ctx),
+        };
+
+        BoxEdges {
+            margin: edges(|side| format!("margin-{}", side)),
+            border: edges(|side| format!("border-{}-width", side)),
+            padding: edges(|side| format!("padding-{}", side)),
         }
     }
 }
 
+impl OwnedStyledNode {
+    pub fn new(node: &Node, stylesheet: &Stylesheet) -> OwnedStyledNode {
+        StyledNode::new(node, stylesheet).to_owned_tree()
+    }
+
+    pub fn value(&self, name: &str) -> Option<&Value> {
+        self.styles.get(name)
+    }
+
+    pub fn get_display(&self) -> Display {
+        display_of(self.value("display"))
+    }
+}
+
+impl<'a> StyledNode<'a> {
+    pub fn to_owned_tree(&self) -> OwnedStyledNode {
+        OwnedStyledNode {
+            node_type: self.node.node_type.clone(),
+            styles: self
+                .styles
+                .iter()
+                .map(|(&name, &value)| (name.to_string(), value.clone()))
+                .collect(),
+            children: self.children.iter().map(|c| c.to_owned_tree()).collect(),
+        }
+    }
+}
+
+impl fmt::Debug for OwnedStyledNode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}: {:?}", self.node_type, self.styles)
+    }
+}
+
+fn display_of(value: Option<&Value>) -> Display {
+    match value {
+        Some(Value::Keyword(v)) => match v.as_ref() {
+            "block" => Display::Block,
+            "none" => Display::None,
+            "inline" => Display::Inline,
+            "inline-block" => Display::InlineBlock,
+            _ => Display::Inline,
+        },
+        Some(_) => Display::Inline,
+        None => Display::None,
+    }
+}
+
 impl<'a> fmt::Debug for StyledNode<'a> {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(f, "{:?}: {:?}", self.node, self.styles)
     }
 }
 
-fn is_selector_matches(el: &ElementData, sel: &Selector) -> bool {
-    for simple in &sel.simple {
-        let mut matches = true;
+// The cascaded properties of a single element, without styling the rest of
+// the tree. `ancestors` runs from the root down to the element's parent.
+// Sibling positions are unknown here, so structural pseudo-classes such as
+// `:first-child` never match, and with no parent styles `inherit` acts like
+// `initial`.
+pub fn compute_styles<'a>(
+    el: &ElementData,
+    ancestors: &[&ElementData],
+    stylesheet: &'a Stylesheet,
+) -> PropertyMap<'a> {
+    let ancestors: Vec<ElementContext> = ancestors
+        .iter()
+        .map(|data| ElementContext {
+            data,
+            position: None,
+        })
+        .collect();
+    let el = ElementContext {
+        data: el,
+        position: None,
+    };
 
-        match simple.tag_name {
-            Some(ref t) => {
-                if *t != el.tag_name {
-                    continue;
+    StyledNode::get_styles(el, &ancestors, None, RuleSource::All(stylesheet)).0
+}
+
+impl Stylesheet {
+    // Every rule with the specificity of its most specific selector, in the
+    // order the cascade applies them to an element they all match:
+    // user-agent rules first, then ascending specificity, then source order.
+    // Only covers the top-level rules, not `@supports` groups.
+    pub fn sorted_rules(&self) -> Vec<(&Rule, Specificity)> {
+        let mut rules: Vec<(&Rule, Specificity)> = self
+            .rules
+            .iter()
+            .map(|rule| {
+                let specificity = rule
+                    .selectors
+                    .iter()
+                    .map(Selector::specificity)
+                    .max()
+                    .unwrap_or_default();
+                (rule, specificity)
+            })
+            .collect();
+
+        rules.sort_by_key(|&(rule, specificity)| (rule.origin, specificity));
+        rules
+    }
+
+    // Every declaration applying to `el`, each with the selector it matched
+    // through, in cascade order: later entries win over earlier ones for the
+    // same property. Positions are unknown, as in `compute_styles`.
+    pub fn cascade_for<'a>(
+        &'a self,
+        el: &ElementData,
+        ancestors: &[&ElementData],
+    ) -> Vec<(&'a Selector, &'a Declaration)> {
+        let ancestors: Vec<ElementContext> = ancestors
+            .iter()
+            .map(|data| ElementContext {
+                data,
+                position: None,
+            })
+            .collect();
+        let el = ElementContext {
+            data: el,
+            position: None,
+        };
+
+        let rules: Vec<&Rule> = self.rules.iter().collect();
+        cascade(el, &ancestors, &rules)
+            .into_iter()
+            .map(|(origin, dclr)| (origin.selector, dclr))
+            .collect()
+    }
+}
+
+impl Selector {
+    // Whether `el` matches the rightmost compound of this selector, without
+    // any context. That is exact for selectors without combinators; for
+    // `div > p` it only checks the `p` part, so use `Node::query` when
+    // ancestors matter. Structural pseudo-classes like `:first-child` need
+    // the element's position and never match here.
+    pub fn matches(&self, el: &ElementData) -> bool {
+        let el = ElementContext {
+            data: el,
+            position: None,
+        };
+
+        self.simple
+            .last()
+            .is_some_and(|simple| is_simple_matches(el, simple))
+    }
+}
+
+// Matched declarations in application order. Important declarations come
+// after all normal ones so they win whatever their specificity. Within each
+// group rules are ordered by origin first: user-agent before author for
+// normal declarations, the reverse for important ones.
+fn cascade<'a>(
+    el: ElementContext,
+    ancestors: &[ElementContext],
+    rules: &[&'a Rule],
+) -> Vec<(StyleOrigin<'a>, &'a Declaration)> {
+    let mut matched = matched_rules(el, ancestors, rules);
+    let mut declarations = Vec::new();
+
+    for important in [false, true] {
+        // Stable, so specificity and source order still decide within an
+        // origin.
+        if important {
+            matched.sort_by_key(|origin| Reverse(origin.rule.origin));
+        } else {
+            matched.sort_by_key(|origin| origin.rule.origin);
+        }
+
+        for origin in &matched {
+            for dclr in &origin.rule.declarations {
+                if dclr.important == important {
+                    declarations.push((*origin, dclr));
                 }
             }
+        }
+    }
+
+    declarations
+}
 
-            None => {}
+// Matching rules in the order they should be applied: ascending specificity,
+// then source order. `rules` must be in source order. A rule matching
+// through several of its selectors counts with the most specific one.
+fn matched_rules<'a>(
+    el: ElementContext,
+    ancestors: &[ElementContext],
+    rules: &[&'a Rule],
+) -> Vec<StyleOrigin<'a>> {
+    let mut matched: Vec<StyleOrigin> = rules
+        .iter()
+        .filter_map(|&rule| {
+            rule.selectors
+                .iter()
+                .filter(|selector| is_selector_matches(el, ancestors, selector))
+                .map(|selector| StyleOrigin {
+                    rule,
+                    selector,
+                    specificity: selector.specificity(),
+                })
+                .max_by_key(|origin| origin.specificity)
+        })
+        .collect();
+
+    matched.sort_by_key(|origin| origin.specificity);
+    matched
+}
+
+// The rules to try against an element while building a styled tree.
+#[derive(Clone, Copy)]
+enum RuleSource<'a> {
+    All(&'a Stylesheet),
+    Indexed(&'a RuleIndex<'a>),
+}
+
+impl<'a> RuleSource<'a> {
+    fn candidates(self, el: &ElementData) -> Vec<&'a Rule> {
+        match self {
+            RuleSource::All(stylesheet) => stylesheet.rules.iter().collect(),
+            RuleSource::Indexed(index) => index.candidates(el),
+        }
+    }
+}
+
+// Rules bucketed by the id, else first class, else tag of the rightmost
+// compound of each selector. An element can only match a selector whose key
+// it has, so it need only be tried against the buckets for its own id,
+// classes and tag, plus the rules keyed on nothing (`*`, `[attr]`, ...).
+pub struct RuleIndex<'a> {
+    rules: &'a [Rule],
+    by_id: HashMap<&'a str, Vec<usize>>,
+    by_class: HashMap<&'a str, Vec<usize>>,
+    by_tag: HashMap<&'a str, Vec<usize>>,
+    universal: Vec<usize>,
+}
+
+impl<'a> RuleIndex<'a> {
+    pub fn new(stylesheet: &'a Stylesheet) -> RuleIndex<'a> {
+        let mut index = RuleIndex {
+            rules: &stylesheet.rules,
+            by_id: HashMap::new(),
+            by_class: HashMap::new(),
+            by_tag: HashMap::new(),
+            universal: Vec::new(),
         };
 
-        match el.get_id() {
-            Some(i) => match simple.id {
-                Some(ref id) => {
-                    if *i != *id {
-                        continue;
-                    }
-                }
-                None => {}
-            },
-            None => match simple.id {
-                Some(_) => {
+        for (i, rule) in stylesheet.rules.iter().enumerate() {
+            for selector in &rule.selectors {
+                let Some(key) = selector.simple.last() else {
                     continue;
-                }
-                _ => {}
-            },
+                };
+
+                let bucket = if let Some(ref id) = key.id {
+                    index.by_id.entry(id).or_default()
+                } else if let Some(class) = key.classes.first() {
+                    index.by_class.entry(class).or_default()
+                } else {
+                    match key.tag_name.as_deref() {
+                        Some(tag) if tag != "*" => index.by_tag.entry(tag).or_default(),
+                        _ => &mut index.universal,
+                    }
+                };
+                bucket.push(i);
+            }
+        }
+
+        index
+    }
+
+    // The rules that may match `el`, in source order. The rest can't match
+    // it whatever its ancestors.
+    pub fn candidates(&self, el: &ElementData) -> Vec<&'a Rule> {
+        let mut picked: Vec<usize> = self.universal.clone();
+
+        if let Some(rules) = el.get_id().and_then(|id| self.by_id.get(id.as_str())) {
+            picked.extend(rules);
         }
+        for class in el.get_classes() {
+            if let Some(rules) = self.by_class.get(class) {
+                picked.extend(rules);
+            }
+        }
+        if let Some(rules) = self.by_tag.get(&*el.tag_name) {
+            picked.extend(rules);
+        }
+
+        picked.sort_unstable();
+        picked.dedup();
+        picked.into_iter().map(|i| &self.rules[i]).collect()
+    }
+}
+
+pub(crate) fn is_selector_matches(
+    el: ElementContext,
+    ancestors: &[ElementContext],
+    sel: &Selector,
+) -> bool {
+    match sel.simple.len() {
+        0 => false,
+        n => matches_from(sel, n - 1, el, ancestors),
+    }
+}
+
+// Matches `sel.simple[..=idx]` right to left, with `el` standing in for
+// `sel.simple[idx]`. Descendant combinators backtrack over every candidate
+// ancestor so chains like `a > b c` resolve correctly.
+fn matches_from(
+    sel: &Selector,
+    idx: usize,
+    el: ElementContext,
+    ancestors: &[ElementContext],
+) -> bool {
+    if !is_simple_matches(el, &sel.simple[idx]) {
+        return false;
+    }
+
+    if idx == 0 {
+        return true;
+    }
+
+    match sel.combinators.get(idx - 1) {
+        Some('>') => match ancestors.split_last() {
+            Some((parent, rest)) => matches_from(sel, idx - 1, *parent, rest),
+            None => false,
+        },
+        Some(' ') => (0..ancestors.len())
+            .rev()
+            .any(|i| matches_from(sel, idx - 1, ancestors[i], &ancestors[..i])),
+        _ => false,
+    }
+}
 
-        let el_classes = el.get_classes();
-        for class in &simple.classes {
-            matches = matches & el_classes.contains::<str>(class);
+fn is_simple_matches(ctx: ElementContext, simple: &SimpleSelector) -> bool {
+    let el = ctx.data;
+
+    if let Some(ref t) = simple.tag_name {
+        if t != "*" && **t != *el.tag_name {
+            return false;
         }
+    };
 
-        if matches {
-            return true;
+    match el.get_id() {
+        Some(i) => {
+            if let Some(ref id) = simple.id {
+                if *i != *id {
+                    return false;
+                }
+            }
+        }
+        None => {
+            if simple.id.is_some() {
+                return false;
+            }
         }
     }
 
-    false
+    let el_classes = el.get_classes();
+    if !simple
+        .classes
+        .iter()
+        .all(|class| el_classes.contains::<str>(class))
+    {
+        return false;
+    }
+
+    if !simple
+        .attributes
+        .iter()
+        .all(|attr| attr.matches(el.get_attribute(&attr.name).as_deref()))
+    {
+        return false;
+    }
+
+    simple
+        .pseudo_classes
+        .iter()
+        .all(|pseudo| match ctx.position {
+            Some((position, count)) => pseudo.matches(position, count),
+            None => false,
+        })
+}
+
+// Properties that `unset` treats as `inherit`.
+// https://developer.mozilla.org/en-US/docs/Web/CSS/Inheritance
+fn is_inherited(property: &str) -> bool {
+    matches!(
+        property,
+        "color"
+            | "cursor"
+            | "font-family"
+            | "font-size"
+            | "font-style"
+            | "font-weight"
+            | "letter-spacing"
+            | "line-height"
+            | "list-style-type"
+            | "text-align"
+            | "text-transform"
+            | "visibility"
+            | "white-space"
+            | "word-spacing"
+    )
+}
+
+pub(crate) fn element_children(node: &Node) -> impl Iterator<Item = &Node> {
+    node.children
+        .iter()
+        .filter(|child| matches!(child.node_type, NodeType::Element(_)))
+}
+
+pub(crate) fn element_count(node: &Node) -> usize {
+    element_children(node).count()
 }
 
 pub fn pretty_print(node: &StyledNode, indent_size: usize) {
@@ -142,6 +722,494 @@ pub fn pretty_print(node: &StyledNode, indent_size: usize) {
     println!("{}{:?}", indent, node);
 
     for child in node.children.iter() {
-        pretty_print(&child, indent_size + 2);
+        pretty_print(child, indent_size + 2);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::css::{Origin, Unit};
+    use crate::css_parser::CssParser;
+    use crate::html_parser::HtmlParser;
+
+    fn parse_html(html: &str) -> Vec<Node> {
+        HtmlParser::new(html).parse_with_diagnostics().0
+    }
+
+    fn parse_css(css: &str) -> Stylesheet {
+        CssParser::new(css).parse_stylesheet()
+    }
+
+    #[test]
+    fn explain_names_the_more_specific_rule() {
+        let stylesheet = parse_css("p.note { color: blue } p { color: red; width: 5px }");
+        let nodes = parse_html("<p class=note></p>");
+        let styled = StyledNode::new(&nodes[0], &stylesheet);
+
+        let color = styled.explain("color").unwrap();
+        assert!(std::ptr::eq(color.rule, &stylesheet.rules[0]));
+        assert_eq!(color.selector.to_string(), "p.note");
+        assert_eq!(color.specificity, (0, 1, 1));
+
+        let width = styled.explain("width").unwrap();
+        assert!(std::ptr::eq(width.rule, &stylesheet.rules[1]));
+        assert_eq!(width.specificity, (0, 0, 1));
+
+        assert!(styled.explain("height").is_none());
+    }
+
+    // The `id` of every element in the tree that has `property` set.
+    fn ids_with(styled: &StyledNode, property: &str) -> Vec<String> {
+        let mut found = Vec::new();
+        if let (Some(_), Some(el)) = (styled.value(property), styled.node.as_element()) {
+            found.extend(el.get_id().cloned());
+        }
+        for child in &styled.children {
+            found.extend(ids_with(child, property));
+        }
+        found
+    }
+
+    #[test]
+    fn descendant_combinators_match_against_the_ancestor_path() {
+        let stylesheet = parse_css("body article p { color: red }");
+        let nodes = parse_html(
+            "<html><body><article><p id=a></p><div><p id=b></p></div></article>\
+             <p id=c></p><section><p id=d></p></section></body>\
+             <article><p id=e></p></article></html>",
+        );
+        let styled = StyledNode::new(&nodes[0], &stylesheet);
+        assert_eq!(ids_with(&styled, "color"), ["a", "b"]);
+
+        let stylesheet = parse_css("article > p { color: red }");
+        let styled = StyledNode::new(&nodes[0], &stylesheet);
+        assert_eq!(ids_with(&styled, "color"), ["a", "e"]);
+    }
+
+    #[test]
+    fn universal_selector_inside_combinator_chains() {
+        let stylesheet =
+            parse_css(".parent * { color: red } ul > * { width: 1px } * > .child { height: 1px }");
+        let nodes = parse_html(
+            "<div id=root><section id=p class=parent><p id=a><b id=b class=child></b></p></section>\
+             <ul id=u><li id=c><span id=d></span></li><li id=e></li></ul></div>",
+        );
+        let styled = StyledNode::new(&nodes[0], &stylesheet);
+
+        assert_eq!(ids_with(&styled, "color"), ["a", "b"]);
+        assert_eq!(ids_with(&styled, "width"), ["c", "e"]);
+        assert_eq!(ids_with(&styled, "height"), ["b"]);
+    }
+
+    #[test]
+    fn structural_pseudo_classes_use_sibling_positions() {
+        let stylesheet = parse_css(
+            "li:first-child { color: red } li:last-child { width: 1px } \
+             tr:nth-child(odd) { height: 1px } tr:nth-child(even) { margin-top: 1px } \
+             tr:nth-child(2) { margin-left: 1px }",
+        );
+        let nodes = parse_html(
+            "<div><ul><li id=a></li>text<li id=b></li><li id=c></li></ul>\
+             <table><tr id=r1></tr><tr id=r2></tr><tr id=r3></tr><tr id=r4></tr><tr id=r5></tr></table></div>",
+        );
+        let styled = StyledNode::new(&nodes[0], &stylesheet);
+
+        assert_eq!(ids_with(&styled, "color"), ["a"]);
+        assert_eq!(ids_with(&styled, "width"), ["c"]);
+        assert_eq!(ids_with(&styled, "height"), ["r1", "r3", "r5"]);
+        assert_eq!(ids_with(&styled, "margin-top"), ["r2", "r4"]);
+        assert_eq!(ids_with(&styled, "margin-left"), ["r2"]);
+    }
+
+    #[test]
+    fn effective_background_composites_over_what_is_behind() {
+        let stylesheet = parse_css(
+            "#half { background-color: rgba(255, 0, 0, 0.5) } #solid { background-color: #00ff00 }",
+        );
+        let nodes = parse_html("<div><p id=half></p><p id=solid></p><p id=none></p></div>");
+        let styled = StyledNode::new(&nodes[0], &stylesheet);
+        let white = Color::new(1.0, 1.0, 1.0, 1.0);
+
+        assert_eq!(
+            styled.children[0].effective_background(white),
+            Color::new(1.0, 0.5, 0.5, 1.0)
+        );
+        assert_eq!(
+            styled.children[1].effective_background(white),
+            Color::new(0.0, 1.0, 0.0, 1.0)
+        );
+        assert_eq!(styled.children[2].effective_background(white), white);
+        assert_eq!(
+            styled.children[0].effective_background(Color::new(0.0, 0.0, 0.0, 0.0)),
+            Color::new(1.0, 0.0, 0.0, 0.5)
+        );
+    }
+
+    #[test]
+    fn compute_styles_for_a_single_element() {
+        let stylesheet = parse_css(
+            "p { color: red; width: 1px } div .note { width: 2px } section p { height: 3px }",
+        );
+        let nodes = parse_html("<div><p class=note></p></div>");
+        let div = nodes[0].as_element().unwrap();
+        let p = nodes[0].children[0].as_element().unwrap();
+
+        let styles = compute_styles(p, &[div], &stylesheet);
+        let mut properties: Vec<&str> = styles.keys().copied().collect();
+        properties.sort();
+        assert_eq!(properties, ["color", "width"]);
+        assert_eq!(styles["width"], &Value::Length(2.0, Unit::Px));
+        assert_eq!(
+            styles["color"],
+            &Value::Color(Color::new(1.0, 0.0, 0.0, 1.0))
+        );
+
+        let alone = compute_styles(p, &[], &stylesheet);
+        assert_eq!(alone["width"], &Value::Length(1.0, Unit::Px));
+    }
+
+    // Owns its inputs and drops them on return, which a borrowing
+    // `StyledNode` couldn't outlive.
+    fn owned_tree(html: String, css: String) -> OwnedStyledNode {
+        let nodes = parse_html(&html);
+        let stylesheet = parse_css(&css);
+        OwnedStyledNode::new(&nodes[0], &stylesheet)
+    }
+
+    #[test]
+    fn owned_styled_tree_outlives_its_inputs() {
+        let tree = owned_tree(
+            String::from("<div><span>a</span></div>"),
+            String::from("div { display: block; width: 5px } span { color: red }"),
+        );
+
+        assert_eq!(tree.get_display(), Display::Block);
+        assert_eq!(tree.value("width"), Some(&Value::Length(5.0, Unit::Px)));
+        assert!(matches!(tree.node_type, NodeType::Element(ref e) if &*e.tag_name == "div"));
+
+        let span = &tree.children[0];
+        assert_eq!(
+            span.value("color"),
+            Some(&Value::Color(Color::new(1.0, 0.0, 0.0, 1.0)))
+        );
+        assert!(matches!(span.node_type, NodeType::Element(ref e) if &*e.tag_name == "span"));
+    }
+
+    #[test]
+    fn sorted_rules_orders_by_specificity_then_source() {
+        let mut stylesheet = parse_css(
+            "#id { color: red } p { color: red } .a, div p#x { color: red } \
+             .b { color: red } div p { color: red }",
+        );
+        stylesheet.rules.insert(
+            0,
+            parse_css("#ua { color: red }")
+                .with_origin(Origin::UserAgent)
+                .rules
+                .remove(0),
+        );
+
+        let sorted: Vec<(String, Specificity)> = stylesheet
+            .sorted_rules()
+            .into_iter()
+            .map(|(rule, specificity)| (rule.selectors[0].to_string(), specificity))
+            .collect();
+        assert_eq!(
+            sorted,
+            [
+                ("#ua".to_string(), (1, 0, 0)),
+                ("p".to_string(), (0, 0, 1)),
+                ("div p".to_string(), (0, 0, 2)),
+                (".b".to_string(), (0, 1, 0)),
+                ("#id".to_string(), (1, 0, 0)),
+                (".a".to_string(), (1, 0, 2)),
+            ]
+        );
+    }
+
+    #[test]
+    fn cascade_for_orders_by_specificity_then_source() {
+        let stylesheet = parse_css(
+            "#x { color: blue } p { color: red; width: 1px } div p { width: 2px !important } \
+             .note { color: green } p { height: 3px } span { color: black }",
+        );
+        let nodes = parse_html("<div><p id=x class=note></p></div>");
+        let div = nodes[0].as_element().unwrap();
+        let p = nodes[0].children[0].as_element().unwrap();
+
+        let cascade: Vec<String> = stylesheet
+            .cascade_for(p, &[div])
+            .into_iter()
+            .map(|(selector, declaration)| format!("{} {}", selector, declaration.property))
+            .collect();
+        assert_eq!(
+            cascade,
+            [
+                "p color",
+                "p width",
+                "p height",
+                ".note color",
+                "#x color",
+                "div p width",
+            ]
+        );
+    }
+
+    #[test]
+    fn author_rules_beat_user_agent_rules_unless_important() {
+        let mut stylesheet = parse_css(
+            "html body div#main.box p { color: red; width: 1px; height: 1px !important }",
+        )
+        .with_origin(Origin::UserAgent);
+        stylesheet.rules.extend(
+            parse_css("p { color: blue; width: 2px; height: 2px !important; margin-top: 2px !important } \
+                       p { margin-top: 3px }")
+                .rules,
+        );
+        let nodes = parse_html("<html><body><div id=main class=box><p></p></div></body></html>");
+        let styled = StyledNode::new(&nodes[0], &stylesheet);
+        let p = &styled.children[0].children[0].children[0];
+
+        assert_eq!(
+            p.value("color").copied(),
+            Some(&Value::Color(Color::new(0.0, 0.0, 1.0, 1.0)))
+        );
+        assert_eq!(
+            p.value("width").copied(),
+            Some(&Value::Length(2.0, Unit::Px))
+        );
+        assert_eq!(
+            p.value("height").copied(),
+            Some(&Value::Length(1.0, Unit::Px))
+        );
+        assert_eq!(
+            p.value("margin-top").copied(),
+            Some(&Value::Length(2.0, Unit::Px))
+        );
+    }
+
+    #[test]
+    fn css_wide_keywords_resolve_against_the_parent() {
+        let stylesheet = parse_css(
+            "div { color: red; width: 5px } #a { color: inherit; width: inherit } \
+             #b { color: initial } #c { color: unset; width: unset } #d { color: blue }",
+        );
+        let nodes = parse_html("<div><p id=a></p><p id=b></p><p id=c></p><p id=d></p></div>");
+        let styled = StyledNode::new(&nodes[0], &stylesheet);
+        let red = Value::Color(Color::new(1.0, 0.0, 0.0, 1.0));
+        let [a, b, c, d] = &styled.children[..] else {
+            panic!("expected four children");
+        };
+
+        assert_eq!(a.value("color").copied(), Some(&red));
+        assert_eq!(
+            a.value("width").copied(),
+            Some(&Value::Length(5.0, Unit::Px))
+        );
+        assert_eq!(b.value("color").copied(), None);
+        assert_eq!(c.value("color").copied(), Some(&red));
+        assert_eq!(c.value("width").copied(), None);
+        assert_eq!(
+            d.value("color").copied(),
+            Some(&Value::Color(Color::new(0.0, 0.0, 1.0, 1.0)))
+        );
+    }
+
+    #[test]
+    fn selector_matches_a_single_element() {
+        let nodes = parse_html("<p id=intro class=\"note big\"></p>");
+        let p = nodes[0].as_element().unwrap();
+        let matches =
+            |text: &str| CssParser::new(text).parse_selector_list().unwrap()[0].matches(p);
+
+        for text in [
+            "p",
+            "*",
+            ".note",
+            "#intro",
+            "p.note.big#intro",
+            "div > p.big",
+        ] {
+            assert!(matches(text), "{} should match", text);
+        }
+        for text in [
+            "div",
+            ".other",
+            "#outro",
+            "p.note.other",
+            "p:first-child",
+            "p > div",
+        ] {
+            assert!(!matches(text), "{} should not match", text);
+        }
+    }
+
+    #[test]
+    fn box_edges_resolve_every_side() {
+        let nodes = parse_html("<div></div>");
+        let stylesheet =
+            parse_css("div { margin: 1px 2px 3px 4px; padding-top: 2em; border-left-width: 5px }");
+        let styled = StyledNode::new(&nodes[0], &stylesheet);
+
+        let edges = styled.box_edges(&LengthContext::new(10.0, 0.0, 0.0));
+        let sides = |e: EdgeSizes| [e.top, e.right, e.bottom, e.left];
+        assert_eq!(sides(edges.margin), [1.0, 2.0, 3.0, 4.0]);
+        assert_eq!(sides(edges.padding), [20.0, 0.0, 0.0, 0.0]);
+        assert_eq!(sides(edges.border), [0.0, 0.0, 0.0, 5.0]);
+
+        // A different context isn't served from the cache.
+        let edges = styled.box_edges(&LengthContext::new(8.0, 0.0, 0.0));
+        assert_eq!(edges.padding.top, 16.0);
+    }
+
+    #[test]
+    fn rem_resolves_against_the_root_font_size() {
+        let nodes = parse_html("<html><body><div><p><span></span></p></div></body></html>");
+        let stylesheet = parse_css(
+            "html { font-size: 20px } div { font-size: 40px } p { font-size: 2em } \
+             span { margin-top: 1.5rem }",
+        );
+        let styled = StyledNode::new(&nodes[0], &stylesheet);
+        assert_eq!(styled.compute_root_font_size(), 20.0);
+
+        let mut ctx = LengthContext::new(80.0, 0.0, 0.0);
+        ctx.root_font_size = styled.compute_root_font_size();
+        let span = &styled.children[0].children[0].children[0].children[0];
+        assert_eq!(span.px_or("margin-top", 0.0, &ctx), 30.0);
+
+        let stylesheet = parse_css("span { margin-top: 1.5rem }");
+        let unset = StyledNode::new(&nodes[0], &stylesheet);
+        assert_eq!(unset.compute_root_font_size(), 16.0);
+    }
+
+    #[test]
+    fn z_index_and_position_for_stacking() {
+        let stylesheet = parse_css(
+            "#a { z-index: 5; position: relative } #b { z-index: auto } #c { z-index: -2 } \
+             #d { z-index: 1.5 }",
+        );
+        let nodes =
+            parse_html("<div><p id=a></p><p id=b></p><p id=c></p><p id=d></p><p></p></div>");
+        let styled = StyledNode::new(&nodes[0], &stylesheet);
+
+        let a = &styled.children[0];
+        assert_eq!(a.value("z-index").copied(), Some(&Value::Integer(5)));
+        assert_eq!(
+            a.value("position").copied(),
+            Some(&Value::Keyword(String::from("relative")))
+        );
+        assert_eq!(
+            styled.children[1].value("z-index").copied(),
+            Some(&Value::Keyword(String::from("auto")))
+        );
+
+        let z: Vec<Option<i32>> = styled.children.iter().map(StyledNode::z_index).collect();
+        assert_eq!(z, [Some(5), None, Some(-2), None, None]);
+    }
+
+    #[test]
+    fn cached_values_match_the_uncached_path() {
+        let nodes = parse_html("<div><p></p><span></span></div>");
+        let stylesheet = parse_css(
+            "div { display: block; color: #123456; margin: 1em 2px } p { display: inline }",
+        );
+        let styled = StyledNode::new(&nodes[0], &stylesheet);
+        let ctx = LengthContext::new(10.0, 0.0, 0.0);
+
+        for node in std::iter::once(&styled).chain(&styled.children) {
+            assert!(node.cache.display.get().is_none());
+            for _ in 0..2 {
+                assert_eq!(
+                    node.get_display(),
+                    display_of(node.value("display").copied())
+                );
+                assert!(node.color() == node.value("color").and_then(|v| v.as_color()).copied());
+
+                let (cached, computed) = (node.box_edges(&ctx), node.compute_box_edges(&ctx));
+                assert_eq!(cached.margin.top, computed.margin.top);
+                assert_eq!(cached.margin.left, computed.margin.left);
+            }
+            assert!(node.cache.display.get().is_some());
+            assert!(node.cache.color.get().is_some());
+        }
+        assert_eq!(styled.box_edges(&ctx).margin.top, 10.0);
+    }
+
+    #[test]
+    fn important_shorthand_marks_every_longhand() {
+        let stylesheet = parse_css("p { padding: 4px !important } p { padding-left: 9px }");
+
+        let padding = &stylesheet.rules[0].declarations;
+        let properties: Vec<&str> = padding.iter().map(|d| d.property.as_str()).collect();
+        assert_eq!(
+            properties,
+            [
+                "padding-top",
+                "padding-right",
+                "padding-bottom",
+                "padding-left"
+            ]
+        );
+        assert!(padding.iter().all(|d| d.important));
+
+        let nodes = parse_html("<p></p>");
+        let styled = StyledNode::new(&nodes[0], &stylesheet);
+        for side in ["top", "right", "bottom", "left"] {
+            let property = format!("padding-{}", side);
+            assert_eq!(
+                styled.value(&property).copied(),
+                Some(&Value::Length(4.0, Unit::Px))
+            );
+        }
+    }
+
+    fn assert_same_styles(a: &StyledNode, b: &StyledNode) {
+        assert_eq!(a.styles, b.styles, "styles differ on {:?}", a.node);
+        assert_eq!(a.children.len(), b.children.len());
+        for (a, b) in a.children.iter().zip(&b.children) {
+            assert_same_styles(a, b);
+        }
+    }
+
+    fn elements(node: &Node) -> Vec<&ElementData> {
+        let mut found: Vec<&ElementData> = node.as_element().into_iter().collect();
+        for child in &node.children {
+            found.extend(elements(child));
+        }
+        found
+    }
+
+    #[test]
+    fn rule_index_gives_the_same_styles_with_fewer_candidates() {
+        let mut css = String::from(
+            "* { margin-top: 1px } div p { color: red } .note { color: blue } #main { width: 50px } \
+             ul > li:first-child { padding-top: 2px } [title] { height: 3px } p.note.big { width: 7px }",
+        );
+        for i in 0..50 {
+            css.push_str(&format!(
+                " .unused{} {{ width: {}px }} #nope{} {{ color: red }}",
+                i, i, i
+            ));
+        }
+        let stylesheet = parse_css(&css);
+        let nodes = parse_html(
+            "<div id=main><p class='note big'>a</p><ul><li title=t>b</li><li>c</li></ul><p>d</p></div>",
+        );
+
+        let index = RuleIndex::new(&stylesheet);
+        let plain = StyledNode::new(&nodes[0], &stylesheet);
+        let indexed = StyledNode::with_index(&nodes[0], &index);
+        assert_same_styles(&plain, &indexed);
+
+        let elements = elements(&nodes[0]);
+        let attempts: usize = elements.iter().map(|el| index.candidates(el).len()).sum();
+        let without_index = elements.len() * stylesheet.rules.len();
+        assert!(
+            attempts * 10 < without_index,
+            "{} attempts with the index, {} without",
+            attempts,
+            without_index
+        );
     }
 }